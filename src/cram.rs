@@ -1,4 +1,8 @@
 pub mod cram_impl;
 pub mod cram_error;
+pub mod cram_encode_opts;
+pub mod cram_inspect;
 
+pub use cram_encode_opts::*;
 pub use cram_impl::*;
+pub use cram_inspect::*;
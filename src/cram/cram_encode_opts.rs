@@ -0,0 +1,423 @@
+use std::ffi::{CStr, CString};
+
+use libc::c_int;
+
+use crate::{
+    error::HtsError,
+    hts::{HtsFileRaw, HtsFmtOption, HtsProfileOption, HtsThreadPool},
+};
+
+use super::CramFdRaw;
+
+/// Source of reference sequences when encoding CRAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CramRefMode {
+    /// Use the reference from the FASTA file (the reference is not stored in the CRAM file)
+    External(CString),
+    /// Use the reference from the FASTA file and embed the required sequence in the CRAM file
+    Embed(CString),
+    /// Encode without a reference
+    NoRef,
+}
+
+/// Builder for the options used when encoding CRAM.  Options that are not set are left at the
+/// htslib defaults.  Explicit settings override those from the profile (see
+/// [CramEncodeOptions::set_profile]).
+///
+/// The options are validated as a whole, and the file is checked to be a CRAM file opened for
+/// writing with no header written yet, before any are applied.  The reference (the only option
+/// requiring file access) is then loaded before any other option is set, so if any of these
+/// steps fail the file is left unchanged.  If htslib rejects an option the error names the
+/// option
+#[derive(Default)]
+pub struct CramEncodeOptions<'a> {
+    version: Option<(u8, u8)>,
+    profile: Option<HtsProfileOption>,
+    ref_mode: Option<CramRefMode>,
+    seqs_per_slice: Option<usize>,
+    bases_per_slice: Option<usize>,
+    slices_per_container: Option<usize>,
+    use_bzip2: Option<bool>,
+    use_lzma: Option<bool>,
+    use_rans: Option<bool>,
+    use_tok: Option<bool>,
+    use_fqz: Option<bool>,
+    use_arith: Option<bool>,
+    lossy_read_names: Option<bool>,
+    store_md: Option<bool>,
+    store_nm: Option<bool>,
+    thread_pool: Option<&'a mut HtsThreadPool>,
+}
+
+const CRAM_VERSIONS: [(u8, u8); 3] = [(2, 1), (3, 0), (3, 1)];
+
+fn bad_opts(s: &str) -> HtsError {
+    HtsError::InvalidCramOptions(s.to_owned())
+}
+
+fn size_opt(x: usize, name: &str) -> Result<c_int, HtsError> {
+    if x == 0 {
+        Err(bad_opts(&format!("{name} must be greater than zero")))
+    } else {
+        c_int::try_from(x).map_err(|_| bad_opts(&format!("{name} is too large")))
+    }
+}
+
+impl<'a> CramEncodeOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// CRAM format version (i.e., 3.0 or 3.1)
+    pub fn set_version(&mut self, major: u8, minor: u8) -> &mut Self {
+        self.version = Some((major, minor));
+        self
+    }
+
+    /// Compression profile setting the codecs, levels and slice sizes
+    pub fn set_profile(&mut self, profile: HtsProfileOption) -> &mut Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    pub fn set_reference(&mut self, fasta: &CStr) -> &mut Self {
+        self.ref_mode = Some(CramRefMode::External(fasta.to_owned()));
+        self
+    }
+
+    pub fn set_embed_ref(&mut self, fasta: &CStr) -> &mut Self {
+        self.ref_mode = Some(CramRefMode::Embed(fasta.to_owned()));
+        self
+    }
+
+    pub fn set_no_ref(&mut self) -> &mut Self {
+        self.ref_mode = Some(CramRefMode::NoRef);
+        self
+    }
+
+    pub fn set_seqs_per_slice(&mut self, n: usize) -> &mut Self {
+        self.seqs_per_slice = Some(n);
+        self
+    }
+
+    pub fn set_bases_per_slice(&mut self, n: usize) -> &mut Self {
+        self.bases_per_slice = Some(n);
+        self
+    }
+
+    pub fn set_slices_per_container(&mut self, n: usize) -> &mut Self {
+        self.slices_per_container = Some(n);
+        self
+    }
+
+    pub fn set_use_bzip2(&mut self, b: bool) -> &mut Self {
+        self.use_bzip2 = Some(b);
+        self
+    }
+
+    pub fn set_use_lzma(&mut self, b: bool) -> &mut Self {
+        self.use_lzma = Some(b);
+        self
+    }
+
+    /// Enable rANS codecs (requires CRAM 3.0 or later)
+    pub fn set_use_rans(&mut self, b: bool) -> &mut Self {
+        self.use_rans = Some(b);
+        self
+    }
+
+    /// Enable the name tokeniser (requires CRAM 3.1)
+    pub fn set_use_tok(&mut self, b: bool) -> &mut Self {
+        self.use_tok = Some(b);
+        self
+    }
+
+    /// Enable the fqzcomp quality codec (requires CRAM 3.1)
+    pub fn set_use_fqz(&mut self, b: bool) -> &mut Self {
+        self.use_fqz = Some(b);
+        self
+    }
+
+    /// Enable the adaptive arithmetic coder (requires CRAM 3.1)
+    pub fn set_use_arith(&mut self, b: bool) -> &mut Self {
+        self.use_arith = Some(b);
+        self
+    }
+
+    /// Discard read names where they can be regenerated (i.e., for mate pairs)
+    pub fn set_lossy_read_names(&mut self, b: bool) -> &mut Self {
+        self.lossy_read_names = Some(b);
+        self
+    }
+
+    /// Store MD tags verbatim rather than regenerating them on decoding
+    pub fn set_store_md(&mut self, b: bool) -> &mut Self {
+        self.store_md = Some(b);
+        self
+    }
+
+    /// Store NM tags verbatim rather than regenerating them on decoding
+    pub fn set_store_nm(&mut self, b: bool) -> &mut Self {
+        self.store_nm = Some(b);
+        self
+    }
+
+    pub fn set_thread_pool(&mut self, tp: &'a mut HtsThreadPool) -> &mut Self {
+        self.thread_pool = Some(tp);
+        self
+    }
+
+    /// Check for invalid option values and incompatible combinations
+    pub fn validate(&self) -> Result<(), HtsError> {
+        if let Some(v) = self.version
+            && !CRAM_VERSIONS.contains(&v)
+        {
+            return Err(bad_opts(&format!(
+                "unsupported CRAM version {}.{}",
+                v.0, v.1
+            )));
+        }
+        let v = self.version.unwrap_or((3, 0));
+        if self.use_rans == Some(true) && v < (3, 0) {
+            return Err(bad_opts("rANS codecs require CRAM version 3.0 or later"));
+        }
+        for (b, name) in [
+            (self.use_tok, "tok"),
+            (self.use_fqz, "fqz"),
+            (self.use_arith, "arith"),
+        ] {
+            if b == Some(true) && v < (3, 1) {
+                return Err(bad_opts(&format!(
+                    "{name} codec requires CRAM version 3.1 (set explicitly)"
+                )));
+            }
+        }
+        for (x, name) in [
+            (self.seqs_per_slice, "seqs_per_slice"),
+            (self.bases_per_slice, "bases_per_slice"),
+            (self.slices_per_container, "slices_per_container"),
+        ] {
+            if let Some(x) = x {
+                size_opt(x, name)?;
+            }
+        }
+        if let Some(CramRefMode::External(s) | CramRefMode::Embed(s)) = &self.ref_mode
+            && s.is_empty()
+        {
+            return Err(bad_opts("empty reference file name"));
+        }
+        Ok(())
+    }
+
+    // Build the list of options (with their names for error messages) in the order they should
+    // be applied.  The reference is loaded first, as this involves file access and so is the
+    // option most likely to fail; this way a failure leaves the file unchanged.  The version and
+    // profile are applied next so that the other options override the profile defaults
+    fn options<'b>(
+        &'b mut self,
+        version: &'b CStr,
+        cram_fd: bool,
+    ) -> Vec<(&'static str, HtsFmtOption<'b>)> {
+        let mut v = Vec::new();
+        if let Some(CramRefMode::External(s) | CramRefMode::Embed(s)) = &self.ref_mode {
+            v.push(("reference", HtsFmtOption::CramOptReference(s)))
+        }
+        if self.version.is_some() {
+            v.push(("version", HtsFmtOption::CramVersion(version)))
+        }
+        if let Some(p) = self.profile {
+            v.push(("profile", HtsFmtOption::HtsProfile(p)))
+        }
+        match &self.ref_mode {
+            Some(CramRefMode::External(_)) => {
+                v.push(("embed_ref", HtsFmtOption::CramEmbedRef(false)))
+            }
+            Some(CramRefMode::Embed(_)) => v.push(("embed_ref", HtsFmtOption::CramEmbedRef(true))),
+            Some(CramRefMode::NoRef) => v.push(("no_ref", HtsFmtOption::CramNoRef(true))),
+            None => {}
+        }
+        // Sizes have already been checked by validate()
+        let sz = |x: usize| x as c_int;
+        if let Some(x) = self.seqs_per_slice {
+            v.push(("seqs_per_slice", HtsFmtOption::CramSeqsPerSlice(sz(x))))
+        }
+        if let Some(x) = self.bases_per_slice {
+            v.push(("bases_per_slice", HtsFmtOption::CramBasesPerSlice(sz(x))))
+        }
+        if let Some(x) = self.slices_per_container {
+            v.push((
+                "slices_per_container",
+                HtsFmtOption::CramSlicesPerContainer(sz(x)),
+            ))
+        }
+        for (name, b, f) in [
+            (
+                "use_bzip2",
+                self.use_bzip2,
+                HtsFmtOption::CramUseBzip2 as fn(bool) -> HtsFmtOption<'b>,
+            ),
+            ("use_lzma", self.use_lzma, HtsFmtOption::CramUseLzma),
+            ("use_rans", self.use_rans, HtsFmtOption::CramUseRans),
+            ("use_tok", self.use_tok, HtsFmtOption::CramUseTok),
+            ("use_fqz", self.use_fqz, HtsFmtOption::CramUseFqz),
+            ("use_arith", self.use_arith, HtsFmtOption::CramUseArith),
+            (
+                "lossy_read_names",
+                self.lossy_read_names,
+                HtsFmtOption::CramLossyReadNames,
+            ),
+            ("store_md", self.store_md, HtsFmtOption::CramStoreMd),
+            ("store_nm", self.store_nm, HtsFmtOption::CramStoreNm),
+        ] {
+            if let Some(b) = b {
+                v.push((name, f(b)))
+            }
+        }
+        if let Some(tp) = self.thread_pool.as_deref_mut() {
+            v.push((
+                "thread_pool",
+                if cram_fd {
+                    HtsFmtOption::CramThreadPool(tp)
+                } else {
+                    HtsFmtOption::HtsThreadPool(tp)
+                },
+            ))
+        }
+        v
+    }
+
+    fn apply<F>(&mut self, cram_fd: bool, mut f: F) -> Result<(), HtsError>
+    where
+        F: FnMut(&mut HtsFmtOption) -> Result<(), HtsError>,
+    {
+        self.validate()?;
+        let version = self
+            .version
+            .map(|(a, b)| CString::new(format!("{a}.{b}")).unwrap())
+            .unwrap_or_default();
+        for (name, mut opt) in self.options(&version, cram_fd) {
+            f(&mut opt).map_err(|e| bad_opts(&format!("option {name} rejected ({e})")))?
+        }
+        Ok(())
+    }
+
+    /// Apply the options to `fp`, which must be a CRAM file opened for writing
+    pub fn apply_to_hts_file(&mut self, fp: &mut HtsFileRaw) -> Result<(), HtsError> {
+        if !fp.is_cram() {
+            return Err(bad_opts("file is not in CRAM format"));
+        }
+        if !fp.is_write() {
+            return Err(bad_opts("file is not opened for writing"));
+        }
+        let fd = fp
+            .cram_fd()
+            .ok_or_else(|| bad_opts("missing CRAM file descriptor"))?;
+        check_no_header(fd)?;
+        self.apply(false, |o| fp.set_opt(o))
+    }
+
+    /// Apply the options to the CRAM file `fd`, which must be opened for writing
+    pub fn apply_to_cram_fd(&mut self, fd: &mut CramFdRaw) -> Result<(), HtsError> {
+        check_no_header(fd)?;
+        self.apply(true, |o| fd.set_opt(o))
+    }
+}
+
+// The header is set when a CRAM file is opened for reading or once it has been written, and
+// the options can not be changed after either
+fn check_no_header(fd: &CramFdRaw) -> Result<(), HtsError> {
+    if fd.get_header().is_some() {
+        Err(bad_opts(
+            "options must be applied to a file opened for writing before the header is written",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cram::CramFd, hts::HtsFile, sam::SamHdr};
+
+    #[test]
+    fn validate() {
+        let mut o = CramEncodeOptions::new();
+        o.set_profile(HtsProfileOption::Small).set_use_fqz(true);
+        assert!(matches!(o.validate(), Err(HtsError::InvalidCramOptions(_))));
+        o.set_version(3, 1);
+        assert!(o.validate().is_ok());
+        o.set_version(2, 1).set_use_fqz(false).set_use_rans(true);
+        assert!(o.validate().is_err());
+        o.set_version(3, 0).set_seqs_per_slice(0);
+        assert!(o.validate().is_err());
+        o.set_seqs_per_slice(5000).set_embed_ref(c"");
+        assert!(o.validate().is_err());
+        o.set_no_ref();
+        assert!(o.validate().is_ok());
+        o.set_version(4, 2);
+        assert!(o.validate().is_err());
+    }
+
+    #[test]
+    fn apply() {
+        let path = std::env::temp_dir().join(format!("cram_opts_test_{}.cram", std::process::id()));
+        let name = CString::new(path.to_str().unwrap()).unwrap();
+        let mut o = CramEncodeOptions::new();
+        o.set_version(3, 1)
+            .set_profile(HtsProfileOption::Archive)
+            .set_reference(c"test/realn01.fa")
+            .set_seqs_per_slice(1000)
+            .set_use_tok(true)
+            .set_store_nm(true);
+        {
+            let mut f = HtsFile::open(&name, c"wc").expect("Couldn't open output file");
+            o.apply_to_hts_file(&mut f).expect("Couldn't apply options");
+        }
+        {
+            let mut c = CramFd::open(&name, c"wb").expect("Couldn't open output file");
+            o.apply_to_cram_fd(&mut c).expect("Couldn't apply options");
+            assert_eq!((c.major_version(), c.minor_version()), (3, 1));
+        }
+        {
+            // Options can not be changed once the header has been written
+            let mut f = HtsFile::open(c"test/realn01.sam", c"r").expect("Couldn't open SAM file");
+            let hdr = SamHdr::read(&mut f).expect("Couldn't read header");
+            let mut c = CramFd::open(&name, c"wb").expect("Couldn't open output file");
+            c.load_reference(c"test/realn01.fa")
+                .expect("Couldn't load reference");
+            hdr.write_cram(&mut c).expect("Couldn't write header");
+            assert!(matches!(
+                o.apply_to_cram_fd(&mut c),
+                Err(HtsError::InvalidCramOptions(_))
+            ));
+        }
+        let _ = std::fs::remove_file(&path);
+
+        {
+            // A missing reference leaves the file unchanged
+            let mut c = CramFd::open(&name, c"wb").expect("Couldn't open output file");
+            let v = (c.major_version(), c.minor_version());
+            let mut o1 = CramEncodeOptions::new();
+            o1.set_version(2, 1).set_reference(c"test/no_such_file.fa");
+            assert!(matches!(
+                o1.apply_to_cram_fd(&mut c),
+                Err(HtsError::InvalidCramOptions(s)) if s.contains("reference")
+            ));
+            assert_eq!((c.major_version(), c.minor_version()), v);
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let mut f = HtsFile::open(c"test/realn01.sam", c"r").expect("Couldn't open SAM file");
+        assert!(o.apply_to_hts_file(&mut f).is_err());
+        let mut f =
+            HtsFile::open(c"test/test_input_1_a.cram", c"r").expect("Couldn't open CRAM file");
+        assert!(matches!(
+            o.apply_to_hts_file(&mut f),
+            Err(HtsError::InvalidCramOptions(s)) if s.contains("writing")
+        ));
+        let mut c =
+            CramFd::open(c"test/test_input_1_a.cram", c"r").expect("Couldn't open CRAM file");
+        assert!(o.apply_to_cram_fd(&mut c).is_err());
+    }
+}
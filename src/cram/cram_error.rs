@@ -20,4 +20,8 @@ pub enum CramError {
     CramVersionHasNoEOF,
     #[error("Unknown error")]
     UnknownError,
+    #[error("Error reading CRAM record")]
+    ReadError,
+    #[error("Error writing CRAM record")]
+    WriteError,
 }
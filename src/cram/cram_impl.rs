@@ -1,7 +1,11 @@
 use crate::{
     error::HtsError,
-    hts::{cram_file_set_opt, HFile, HFileRaw, HtsFmtOption, Whence},
-    sam::sam_hdr::SamHdrRaw,
+    hts::{
+        cram_file_set_opt,
+        traits::{ReadRec, WriteRec},
+        HFile, HFileRaw, HtsFileRaw, HtsFmtOption, HtsPos, Whence,
+    },
+    sam::{record::bam1::bam1_t, sam_hdr::SamHdrRaw, BamRec},
     CramError,
 };
 
@@ -22,9 +26,37 @@ pub struct Refs {
     _unused: [u8; 0],
 }
 
+/// Reference range for CRAM decoding (see [CramFdRaw::set_range])
 #[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct CramRange {
-    _unused: [u8; 0],
+    refid: c_int,
+    start: HtsPos,
+    end: HtsPos,
+}
+
+impl CramRange {
+    /// Range covering `start..=end` (1 based) on contig `tid`
+    pub fn new(tid: usize, start: HtsPos, end: HtsPos) -> Self {
+        Self {
+            refid: tid as c_int,
+            start,
+            end,
+        }
+    }
+
+    /// Range covering the whole of contig `tid`
+    pub fn contig(tid: usize) -> Self {
+        Self::new(tid, 1, HtsPos::MAX)
+    }
+}
+
+impl Refs {
+    /// Reference sequences used by the CRAM file `fp` (if any).  These can be shared with
+    /// other CRAM files using [CramFdRaw::set_shared_ref]
+    pub fn from_hts_file(fp: &mut HtsFileRaw) -> Option<&mut Self> {
+        unsafe { cram_get_refs(fp).as_mut() }
+    }
 }
 
 #[link(name = "hts")]
@@ -41,6 +73,12 @@ unsafe extern "C" {
     fn cram_eof(fd: *mut CramFdRaw) -> c_int;
     fn cram_set_header(fd: *mut CramFdRaw, hdr: *const SamHdrRaw) -> c_int;
     fn cram_check_EOF(fd: *mut CramFdRaw) -> c_int;
+    fn cram_load_reference(fd: *mut CramFdRaw, fn_: *const c_char) -> c_int;
+    fn cram_index_load(fd: *mut CramFdRaw, fn_: *const c_char, fn_idx: *const c_char) -> c_int;
+    fn cram_write_SAM_hdr(fd: *mut CramFdRaw, hdr: *mut SamHdrRaw) -> c_int;
+    fn cram_get_bam_seq(fd: *mut CramFdRaw, bam: *mut *mut bam1_t) -> c_int;
+    fn cram_put_bam_seq(fd: *mut CramFdRaw, b: *mut bam1_t) -> c_int;
+    fn cram_get_refs(fd: *mut HtsFileRaw) -> *mut Refs;
 }
 
 impl CramFdRaw {
@@ -107,6 +145,51 @@ impl CramFdRaw {
             _ => Err(CramError::IoError),
         }
     }
+    /// Load the reference from the FASTA file `name`
+    pub fn load_reference(&mut self, name: &CStr) -> Result<(), CramError> {
+        if unsafe { cram_load_reference(self, name.as_ptr()) } == 0 {
+            Ok(())
+        } else {
+            Err(CramError::OperationFailed)
+        }
+    }
+    /// Load the index for the CRAM file `name`.  If `idx_name` is None then the index file
+    /// name is derived from `name`
+    pub fn load_index(&mut self, name: &CStr, idx_name: Option<&CStr>) -> Result<(), CramError> {
+        let idx = idx_name.map(|s| s.as_ptr()).unwrap_or(std::ptr::null());
+        if unsafe { cram_index_load(self, name.as_ptr(), idx) } == 0 {
+            Ok(())
+        } else {
+            Err(CramError::OperationFailed)
+        }
+    }
+    /// Set the header and write it to the output file (along with the file definition if
+    /// required).  The reference should be set (if required) before calling this.  See also
+    /// [crate::sam::SamHdr::write_cram]
+    pub fn write_header(&mut self, hdr: &SamHdrRaw) -> Result<(), CramError> {
+        self.set_header(hdr)?;
+        let h = unsafe { cram_fd_get_header(self) };
+        if unsafe { cram_write_SAM_hdr(self, h) } == 0 {
+            Ok(())
+        } else {
+            Err(CramError::IoError)
+        }
+    }
+    /// Limit decoding to records overlapping `range`.  This requires the index to have
+    /// been loaded (see [CramFdRaw::load_index]), and seeks to the start of the range
+    pub fn set_range(&mut self, range: &mut CramRange) -> Result<(), HtsError> {
+        self.set_opt(&mut HtsFmtOption::CramRange(range))
+    }
+    /// Limit decoding to records overlapping `range` without seeking, so that containers
+    /// not overlapping the range are skipped as the file is read.  No index is required
+    pub fn set_range_noseek(&mut self, range: &mut CramRange) -> Result<(), HtsError> {
+        self.set_opt(&mut HtsFmtOption::CramRangeNoSeek(range))
+    }
+    /// Use the reference sequences in `refs` (i.e., from another CRAM file) rather than
+    /// loading a separate copy
+    pub fn set_shared_ref(&mut self, refs: &mut Refs) -> Result<(), HtsError> {
+        self.set_opt(&mut HtsFmtOption::CramSharedRef(refs))
+    }
 }
 
 pub struct CramFd {
@@ -162,9 +245,43 @@ impl CramFd {
     }
 }
 
+impl ReadRec for CramFd {
+    type Rec = BamRec;
+    type Err = CramError;
+
+    fn read_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        let mut p = rec.as_mut_ptr();
+        if unsafe { cram_get_bam_seq(self.deref_mut(), &mut p) } >= 0 {
+            Ok(Some(()))
+        } else if unsafe { cram_eof(self.deref_mut()) } != 0 {
+            // cram_eof() returns 2 if the end of a range has been reached
+            Ok(None)
+        } else {
+            Err(CramError::ReadError)
+        }
+    }
+}
+
+impl WriteRec for CramFd {
+    type Rec = BamRec;
+    type Err = CramError;
+
+    fn write_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        if unsafe { cram_put_bam_seq(self.deref_mut(), rec.as_mut_ptr()) } == 0 {
+            Ok(Some(()))
+        } else {
+            Err(CramError::WriteError)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        hts::HtsFile,
+        sam::{SamHdr, SamReader},
+    };
 
     #[test]
     fn open_and_read() {
@@ -179,4 +296,91 @@ mod tests {
         let j = c.minor_version();
         assert_eq!((v, i, j), (768, 3, 0));
     }
+
+    #[test]
+    fn read_recs() {
+        let mut c =
+            CramFd::open(c"test/test_input_1_a.cram", c"r").expect("Couldn't open CRAM file");
+        let mut rec = BamRec::new();
+        let mut n = 0;
+        while c.read_rec(&mut rec).expect("Error reading record").is_some() {
+            n += 1
+        }
+        assert_eq!(n, 15);
+
+        // Range limited decoding
+        let mut c =
+            CramFd::open(c"test/test_input_1_a.cram", c"r").expect("Couldn't open CRAM file");
+        let mut r = CramRange::contig(1);
+        c.set_range_noseek(&mut r).expect("Couldn't set range");
+        let mut n = 0;
+        while c.read_rec(&mut rec).expect("Error reading record").is_some() {
+            n += 1
+        }
+        assert_eq!(n, 6);
+    }
+
+    #[test]
+    fn read_range() {
+        let name = c"test/test_input_1_a.cram";
+        let mut c = CramFd::open(name, c"r").expect("Couldn't open CRAM file");
+        c.load_index(name, None).expect("Couldn't load index");
+        let mut rec = BamRec::new();
+        for (mut r, ct) in [
+            (CramRange::contig(1), 6),
+            (CramRange::new(2, 25, HtsPos::MAX), 4),
+        ] {
+            c.set_range(&mut r).expect("Couldn't set range");
+            let mut n = 0;
+            while c.read_rec(&mut rec).expect("Error reading record").is_some() {
+                assert_eq!(rec.tid(), Some(r.refid as usize));
+                n += 1
+            }
+            assert_eq!(n, ct);
+        }
+    }
+
+    #[test]
+    fn shared_ref() {
+        let name = c"test/test_input_1_a.cram";
+        let mut f = HtsFile::open(name, c"r").expect("Couldn't open CRAM file");
+        let refs = Refs::from_hts_file(&mut f).expect("No references for CRAM file");
+        let mut c = CramFd::open(name, c"r").expect("Couldn't open CRAM file");
+        c.set_shared_ref(refs).expect("Couldn't set shared reference");
+        let mut rec = BamRec::new();
+        let mut n = 0;
+        while c.read_rec(&mut rec).expect("Error reading record").is_some() {
+            n += 1
+        }
+        assert_eq!(n, 15);
+    }
+
+    #[test]
+    fn write_recs() {
+        let mut f = HtsFile::open(c"test/realn01.sam", c"r").expect("Couldn't open SAM file");
+        let hdr = SamHdr::read(&mut f).expect("Couldn't read header");
+        let mut rd = SamReader::new(&mut f, &hdr);
+
+        let path = std::env::temp_dir().join(format!("cram_fd_test_{}.cram", std::process::id()));
+        let name = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        let mut rec = BamRec::new();
+        let mut n = 0;
+        {
+            let mut c = CramFd::open(&name, c"wb").expect("Couldn't open CRAM file for output");
+            c.load_reference(c"test/realn01.fa").expect("Couldn't load reference");
+            hdr.write_cram(&mut c).expect("Couldn't write header");
+            while rd.read_rec(&mut rec).expect("Error reading record").is_some() {
+                c.write_rec(&mut rec).expect("Error writing record");
+                n += 1
+            }
+        }
+        let mut c = CramFd::open(&name, c"r").expect("Couldn't open CRAM file");
+        let mut m = 0;
+        while c.read_rec(&mut rec).expect("Error reading record").is_some() {
+            m += 1
+        }
+        let _ = std::fs::remove_file(&path);
+        assert!(n > 0);
+        assert_eq!(n, m);
+    }
 }
@@ -0,0 +1,401 @@
+use std::{fmt, ptr::NonNull};
+
+use libc::{c_int, off_t};
+
+use crate::{
+    CramError,
+    hts::{HFileRaw, HtsPos},
+};
+
+use super::CramFdRaw;
+
+#[repr(C)]
+pub struct CramContainerRaw {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+pub struct CramBlockRaw {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+pub struct CramSliceHdrRaw {
+    _unused: [u8; 0],
+}
+
+#[link(name = "hts")]
+unsafe extern "C" {
+    fn cram_fd_get_fp(fd: *mut CramFdRaw) -> *mut HFileRaw;
+    fn cram_read_container(fd: *mut CramFdRaw) -> *mut CramContainerRaw;
+    fn cram_free_container(c: *mut CramContainerRaw);
+    fn cram_container_is_empty(fd: *mut CramFdRaw) -> c_int;
+    fn cram_container_get_length(c: *mut CramContainerRaw) -> i32;
+    fn cram_container_get_num_records(c: *mut CramContainerRaw) -> i32;
+    fn cram_container_get_num_bases(c: *mut CramContainerRaw) -> i64;
+    fn cram_container_get_landmarks(c: *mut CramContainerRaw, num_landmarks: *mut i32) -> *mut i32;
+    fn cram_container_get_coords(
+        c: *mut CramContainerRaw,
+        refid: *mut c_int,
+        start: *mut HtsPos,
+        span: *mut HtsPos,
+    );
+    fn cram_read_block(fd: *mut CramFdRaw) -> *mut CramBlockRaw;
+    fn cram_free_block(b: *mut CramBlockRaw);
+    fn cram_block_get_content_id(b: *mut CramBlockRaw) -> i32;
+    fn cram_block_get_comp_size(b: *mut CramBlockRaw) -> i32;
+    fn cram_block_get_uncomp_size(b: *mut CramBlockRaw) -> i32;
+    fn cram_block_get_content_type(b: *mut CramBlockRaw) -> c_int;
+    fn cram_block_get_method(b: *mut CramBlockRaw) -> c_int;
+    fn cram_decode_slice_header(fd: *mut CramFdRaw, b: *mut CramBlockRaw) -> *mut CramSliceHdrRaw;
+    fn cram_free_slice_header(hdr: *mut CramSliceHdrRaw);
+    fn cram_slice_hdr_get_num_blocks(hdr: *mut CramSliceHdrRaw) -> i32;
+    fn cram_slice_hdr_get_embed_ref_id(h: *mut CramSliceHdrRaw) -> c_int;
+    fn cram_slice_hdr_get_coords(
+        h: *mut CramSliceHdrRaw,
+        refid: *mut c_int,
+        start: *mut HtsPos,
+        span: *mut HtsPos,
+    );
+}
+
+/// Compression method used for a CRAM block
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CramBlockMethod {
+    Raw,
+    Gzip,
+    Bzip2,
+    Lzma,
+    Rans4x8,
+    RansNx16,
+    Arith,
+    Fqzcomp,
+    Tok3,
+    Unknown(c_int),
+}
+
+impl CramBlockMethod {
+    fn from_c_int(x: c_int) -> Self {
+        match x {
+            0 => Self::Raw,
+            1 => Self::Gzip,
+            2 => Self::Bzip2,
+            3 => Self::Lzma,
+            4 => Self::Rans4x8,
+            5 => Self::RansNx16,
+            6 => Self::Arith,
+            7 => Self::Fqzcomp,
+            8 => Self::Tok3,
+            _ => Self::Unknown(x),
+        }
+    }
+}
+
+impl fmt::Display for CramBlockMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raw => write!(f, "raw"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Bzip2 => write!(f, "bzip2"),
+            Self::Lzma => write!(f, "lzma"),
+            Self::Rans4x8 => write!(f, "rans4x8"),
+            Self::RansNx16 => write!(f, "ransNx16"),
+            Self::Arith => write!(f, "arith"),
+            Self::Fqzcomp => write!(f, "fqzcomp"),
+            Self::Tok3 => write!(f, "tok3"),
+            Self::Unknown(x) => write!(f, "unknown({x})"),
+        }
+    }
+}
+
+/// Content type of a CRAM block
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CramContentType {
+    FileHeader,
+    CompressionHeader,
+    SliceHeader,
+    External,
+    Core,
+    Unknown(c_int),
+}
+
+impl CramContentType {
+    fn from_c_int(x: c_int) -> Self {
+        match x {
+            0 => Self::FileHeader,
+            1 => Self::CompressionHeader,
+            2 => Self::SliceHeader,
+            4 => Self::External,
+            5 => Self::Core,
+            _ => Self::Unknown(x),
+        }
+    }
+}
+
+/// Reference sequence covered by a CRAM container or slice
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CramRefId {
+    Contig(usize),
+    Unmapped,
+    Multi,
+}
+
+impl CramRefId {
+    fn from_c_int(x: c_int) -> Self {
+        match x {
+            -1 => Self::Unmapped,
+            x if x < 0 => Self::Multi,
+            x => Self::Contig(x as usize),
+        }
+    }
+}
+
+/// Summary of a CRAM block
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CramBlockInfo {
+    pub content_type: CramContentType,
+    /// Content ID (for external blocks this identifies the data series stored in the block)
+    pub content_id: i32,
+    pub method: CramBlockMethod,
+    pub comp_size: usize,
+    pub uncomp_size: usize,
+}
+
+impl CramBlockInfo {
+    fn from_raw(b: *mut CramBlockRaw) -> Self {
+        unsafe {
+            Self {
+                content_type: CramContentType::from_c_int(cram_block_get_content_type(b)),
+                content_id: cram_block_get_content_id(b),
+                method: CramBlockMethod::from_c_int(cram_block_get_method(b)),
+                comp_size: cram_block_get_comp_size(b).max(0) as usize,
+                uncomp_size: cram_block_get_uncomp_size(b).max(0) as usize,
+            }
+        }
+    }
+}
+
+/// Summary of a CRAM slice, with the data blocks belonging to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CramSliceInfo {
+    pub ref_id: CramRefId,
+    pub start: HtsPos,
+    pub span: HtsPos,
+    /// Content ID of the block holding the embedded reference (if present)
+    pub embed_ref_id: Option<i32>,
+    /// The slice header block
+    pub header: CramBlockInfo,
+    pub blocks: Vec<CramBlockInfo>,
+}
+
+/// Summary of a CRAM container (see [CramFdRaw::containers])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CramContainerInfo {
+    /// File offset of the container
+    pub offset: off_t,
+    /// Length of the container data (excluding the container header)
+    pub length: usize,
+    pub ref_id: CramRefId,
+    pub start: HtsPos,
+    pub span: HtsPos,
+    pub n_records: usize,
+    pub n_bases: u64,
+    pub compression_header: Option<CramBlockInfo>,
+    pub slices: Vec<CramSliceInfo>,
+}
+
+impl CramContainerInfo {
+    #[inline]
+    pub fn n_slices(&self) -> usize {
+        self.slices.len()
+    }
+
+    /// Iterator over all blocks in the container, including the compression and slice headers
+    pub fn blocks(&self) -> impl Iterator<Item = &CramBlockInfo> {
+        self.compression_header.iter().chain(
+            self.slices
+                .iter()
+                .flat_map(|s| std::iter::once(&s.header).chain(s.blocks.iter())),
+        )
+    }
+}
+
+// Owned htslib objects that are freed when dropped
+struct Container(NonNull<CramContainerRaw>);
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        unsafe { cram_free_container(self.0.as_ptr()) }
+    }
+}
+
+struct Block(NonNull<CramBlockRaw>);
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        unsafe { cram_free_block(self.0.as_ptr()) }
+    }
+}
+
+struct SliceHdr(NonNull<CramSliceHdrRaw>);
+
+impl Drop for SliceHdr {
+    fn drop(&mut self) {
+        unsafe { cram_free_slice_header(self.0.as_ptr()) }
+    }
+}
+
+/// Iterator over the containers in a CRAM file, returned by [CramFdRaw::containers].
+/// The empty container marking the end of the file is not returned
+pub struct CramContainerIter<'a> {
+    fd: &'a mut CramFdRaw,
+    done: bool,
+}
+
+impl CramContainerIter<'_> {
+    fn read_block(&mut self) -> Result<Block, CramError> {
+        NonNull::new(unsafe { cram_read_block(self.fd) })
+            .map(Block)
+            .ok_or(CramError::ReadError)
+    }
+
+    fn read_container(&mut self) -> Result<Option<CramContainerInfo>, CramError> {
+        let fp = unsafe { cram_fd_get_fp(self.fd) };
+        let offset = unsafe { fp.as_ref() }.map(|f| f.tell()).unwrap_or(-1);
+
+        let Some(c) = NonNull::new(unsafe { cram_read_container(self.fd) }).map(Container) else {
+            return if self.fd.eof()? {
+                Ok(None)
+            } else {
+                Err(CramError::ReadError)
+            };
+        };
+        if unsafe { cram_container_is_empty(self.fd) } != 0 {
+            return Ok(None);
+        }
+        let cp = c.0.as_ptr();
+        let (mut refid, mut start, mut span) = (0, 0, 0);
+        let mut n_landmarks = 0;
+        let (length, n_records, n_bases) = unsafe {
+            cram_container_get_coords(cp, &mut refid, &mut start, &mut span);
+            cram_container_get_landmarks(cp, &mut n_landmarks);
+            (
+                cram_container_get_length(cp).max(0) as usize,
+                cram_container_get_num_records(cp).max(0) as usize,
+                cram_container_get_num_bases(cp).max(0) as u64,
+            )
+        };
+        let mut info = CramContainerInfo {
+            offset,
+            length,
+            ref_id: CramRefId::from_c_int(refid),
+            start,
+            span,
+            n_records,
+            n_bases,
+            compression_header: None,
+            slices: Vec::with_capacity(n_landmarks.max(0) as usize),
+        };
+
+        // The compression header is followed by the header and data blocks of each slice
+        // (one slice per landmark)
+        let b = self.read_block()?;
+        info.compression_header = Some(CramBlockInfo::from_raw(b.0.as_ptr()));
+        for _ in 0..n_landmarks {
+            let b = self.read_block()?;
+            let bp = b.0.as_ptr();
+            let header = CramBlockInfo::from_raw(bp);
+            let h = NonNull::new(unsafe { cram_decode_slice_header(self.fd, bp) })
+                .map(SliceHdr)
+                .ok_or(CramError::ReadError)?;
+            let hp = h.0.as_ptr();
+            let (mut refid, mut start, mut span) = (0, 0, 0);
+            let (nb, embed) = unsafe {
+                cram_slice_hdr_get_coords(hp, &mut refid, &mut start, &mut span);
+                (
+                    cram_slice_hdr_get_num_blocks(hp).max(0) as usize,
+                    cram_slice_hdr_get_embed_ref_id(hp),
+                )
+            };
+            let blocks = (0..nb)
+                .map(|_| {
+                    self.read_block()
+                        .map(|b| CramBlockInfo::from_raw(b.0.as_ptr()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            info.slices.push(CramSliceInfo {
+                ref_id: CramRefId::from_c_int(refid),
+                start,
+                span,
+                embed_ref_id: (embed >= 0).then_some(embed),
+                header,
+                blocks,
+            })
+        }
+        Ok(Some(info))
+    }
+}
+
+impl Iterator for CramContainerIter<'_> {
+    type Item = Result<CramContainerInfo, CramError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let r = self.read_container().transpose();
+        if !matches!(r, Some(Ok(_))) {
+            self.done = true
+        }
+        r
+    }
+}
+
+impl CramFdRaw {
+    /// Iterator over the remaining containers in the file.  The file should be positioned at
+    /// the start of a container (i.e., directly after opening).  The block data is read but
+    /// not decoded, so records cannot be read from the file while iterating
+    pub fn containers(&mut self) -> CramContainerIter<'_> {
+        CramContainerIter {
+            fd: self,
+            done: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cram::CramFd;
+
+    #[test]
+    fn inspect() {
+        let mut c =
+            CramFd::open(c"test/test_input_1_a.cram", c"r").expect("Couldn't open CRAM file");
+        let v = c
+            .containers()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Error reading containers");
+        assert!(!v.is_empty());
+        assert_eq!(v.iter().map(|c| c.n_records).sum::<usize>(), 15);
+        for ctr in v.iter() {
+            assert!(ctr.n_slices() > 0);
+            assert_eq!(
+                ctr.compression_header.map(|b| b.content_type),
+                Some(CramContentType::CompressionHeader)
+            );
+            for s in ctr.slices.iter() {
+                assert_eq!(s.header.content_type, CramContentType::SliceHeader);
+                assert!(
+                    s.blocks
+                        .iter()
+                        .any(|b| b.content_type == CramContentType::Core)
+                );
+            }
+            assert_eq!(
+                ctr.blocks().count(),
+                1 + ctr.slices.iter().map(|s| s.blocks.len() + 1).sum::<usize>()
+            );
+        }
+    }
+}
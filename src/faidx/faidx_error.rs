@@ -12,4 +12,6 @@ pub enum FaidxError {
     ErrorLoadingFaidx,
     #[error("Error building FASTA/FASTQ index")]
     ErrorBuildingFaidx,
+    #[error("Out of memory")]
+    OutOfMemory,
 }
\ No newline at end of file
@@ -13,7 +13,7 @@ use crate::{
     bgzf::BgzfRaw,
     from_c,
     hts::{
-        HtsPos, HtsTPoolRaw, HtsThreadPool,
+        HtsMd5, HtsPos, HtsTPoolRaw, HtsThreadPool, md5_hex,
         traits::{HdrType, HtsHdrType, IdMap, SeqId},
     },
     khash::{KHashMap, KHashMapRaw},
@@ -70,6 +70,9 @@ unsafe extern "C" {
     -> c_int;
 }
 
+// Size of sequence chunks loaded when calculating MD5 checksums
+const MD5_CHUNK_SIZE: usize = 1 << 20;
+
 impl FaidxRaw {
     fn nseq(&self) -> usize {
         let l = unsafe { faidx_nseq(self) };
@@ -146,6 +149,31 @@ impl FaidxRaw {
             Err(FaidxError::UnknownSequence)
         }
     }
+
+    /// Calculate the MD5 checksum of sequence `cname` as used for the M5 tag in SAM @SQ lines
+    /// (i.e., the sequence is converted to uppercase with any whitespace removed).  The
+    /// checksum is returned as a lowercase hex string
+    pub fn seq_md5<S: AsRef<CStr>>(&mut self, cname: S) -> Result<String, FaidxError> {
+        let cname = cname.as_ref();
+        let len = self.get_seq_len(cname).ok_or(FaidxError::UnknownSequence)?;
+        let mut ctx = HtsMd5::new().map_err(|_| FaidxError::OutOfMemory)?;
+        let mut buf = Vec::with_capacity(MD5_CHUNK_SIZE);
+        let mut x = 1;
+        while x <= len {
+            let y = (x + MD5_CHUNK_SIZE - 1).min(len);
+            let s = self.fetch_seq(cname, x, Some(y))?;
+            buf.clear();
+            buf.extend(
+                s.seq()
+                    .iter()
+                    .filter(|c| !c.is_ascii_whitespace())
+                    .map(|c| c.to_ascii_uppercase()),
+            );
+            ctx.update(&buf);
+            x = y + 1
+        }
+        Ok(md5_hex(&ctx.finish()))
+    }
 }
 
 unsafe impl Send for Faidx {}
@@ -6,20 +6,26 @@ pub mod hts_error;
 pub mod hts_format;
 pub mod hts_idx;
 pub mod hts_itr;
+pub mod hts_md5;
 pub mod hts_ocstr;
 pub mod hts_opt;
 pub mod hts_region;
 pub mod hts_thread_pool;
+pub mod read_ahead;
+pub mod rec_iter;
 pub mod htsfile;
 pub mod traits;
 
 pub use hfile::*;
 pub use hts_format::*;
 pub use hts_idx::*;
+pub use hts_md5::*;
 pub use hts_region::*;
 // pub use hts_ocstr::*;
 pub use hts_opt::*;
 pub use hts_thread_pool::*;
+pub use read_ahead::*;
+pub use rec_iter::*;
 pub use htsfile::*;
 pub use traits::*;
 
@@ -78,4 +78,6 @@ pub enum HtsError {
     UnknownContig(CString),
     #[error("RegionList Argument not normalized")]
     RegionListArgumentNotNormalized,
+    #[error("Invalid CRAM options: {0}")]
+    InvalidCramOptions(String),
 }
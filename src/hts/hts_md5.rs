@@ -0,0 +1,87 @@
+use std::{fmt::Write, ptr::NonNull};
+
+use libc::{c_uchar, c_ulong, c_void};
+
+use super::hts_error::HtsError;
+
+#[repr(C)]
+pub struct HtsMd5Raw {
+    _unused: [u8; 0],
+}
+
+#[link(name = "hts")]
+unsafe extern "C" {
+    fn hts_md5_init() -> *mut HtsMd5Raw;
+    fn hts_md5_update(ctx: *mut HtsMd5Raw, data: *const c_void, size: c_ulong);
+    fn hts_md5_final(digest: *mut c_uchar, ctx: *mut HtsMd5Raw);
+    fn hts_md5_reset(ctx: *mut HtsMd5Raw);
+    fn hts_md5_destroy(ctx: *mut HtsMd5Raw);
+}
+
+/// MD5 context using the htslib implementation
+pub struct HtsMd5 {
+    inner: NonNull<HtsMd5Raw>,
+}
+
+impl Drop for HtsMd5 {
+    fn drop(&mut self) {
+        unsafe { hts_md5_destroy(self.inner.as_ptr()) }
+    }
+}
+
+impl HtsMd5 {
+    pub fn new() -> Result<Self, HtsError> {
+        NonNull::new(unsafe { hts_md5_init() })
+            .map(|inner| Self { inner })
+            .ok_or(HtsError::OutOfMemory)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe {
+            hts_md5_update(
+                self.inner.as_ptr(),
+                data.as_ptr() as *const c_void,
+                data.len() as c_ulong,
+            )
+        }
+    }
+
+    /// Get the digest of the data seen so far, and reset the context ready for re-use
+    pub fn finish(&mut self) -> [u8; 16] {
+        let mut d = [0; 16];
+        unsafe {
+            hts_md5_final(d.as_mut_ptr(), self.inner.as_ptr());
+            hts_md5_reset(self.inner.as_ptr())
+        }
+        d
+    }
+
+    pub fn reset(&mut self) {
+        unsafe { hts_md5_reset(self.inner.as_ptr()) }
+    }
+}
+
+/// Lowercase hex representation of an MD5 digest (as used for @SQ M5 tags)
+pub fn md5_hex(digest: &[u8; 16]) -> String {
+    let mut s = String::with_capacity(32);
+    for x in digest {
+        write!(s, "{x:02x}").unwrap()
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn md5() -> Result<(), HtsError> {
+        let mut ctx = HtsMd5::new()?;
+        ctx.update(b"AC");
+        ctx.update(b"GT");
+        assert_eq!(md5_hex(&ctx.finish()), "f1f8f4bf413b16ad135722aa4591043e");
+        ctx.update(b"ACGT");
+        assert_eq!(md5_hex(&ctx.finish()), "f1f8f4bf413b16ad135722aa4591043e");
+        Ok(())
+    }
+}
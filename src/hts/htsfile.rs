@@ -197,6 +197,16 @@ impl HtsFileRaw {
     pub fn is_cram(&self) -> bool {
         self._is_cram() != 0
     } 
+
+    /// The underlying CRAM file descriptor if this is a CRAM file
+    #[inline]
+    pub(crate) fn cram_fd(&mut self) -> Option<&mut CramFdRaw> {
+        if self.is_cram() {
+            unsafe { self.fp.cram_fd.as_mut() }
+        } else {
+            None
+        }
+    }
 }
 
 pub struct HtsFile<'a> {
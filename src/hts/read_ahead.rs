@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    mem,
+    sync::mpsc::{Receiver, SyncSender, sync_channel},
+    thread,
+};
+
+use super::traits::{HdrType, HtsHdrType, IdMap, ReadRec, SeqId};
+
+/// Owned copy of the sequence dictionary of a header, used so that contigs can be resolved
+/// by a [ReadAheadReader] while the original source is in use by the reading thread.
+///
+/// Note that only the primary sequence names are stored, so any alternative names recognized
+/// by the original source will not be found by [SeqId::seq_id]
+#[derive(Debug, Clone)]
+pub struct HdrSnapshot {
+    hdr_type: HtsHdrType,
+    names: Vec<CString>,
+    lens: Vec<usize>,
+    ids: HashMap<CString, usize>,
+}
+
+impl HdrSnapshot {
+    pub fn new<T: IdMap>(h: &T) -> Self {
+        let n = h.num_seqs();
+        let mut names = Vec::with_capacity(n);
+        let mut lens = Vec::with_capacity(n);
+        let mut ids = HashMap::with_capacity(n);
+        for i in 0..n {
+            let s = h.seq_name(i).unwrap_or_default().to_owned();
+            ids.entry(s.clone()).or_insert(i);
+            names.push(s);
+            lens.push(h.seq_len(i).unwrap_or(0));
+        }
+        Self {
+            hdr_type: h.hdr_type(),
+            names,
+            lens,
+            ids,
+        }
+    }
+}
+
+impl HdrType for HdrSnapshot {
+    fn hdr_type(&self) -> HtsHdrType {
+        self.hdr_type
+    }
+}
+
+impl SeqId for HdrSnapshot {
+    fn seq_id(&self, s: &CStr) -> Option<usize> {
+        self.ids.get(s).copied()
+    }
+}
+
+impl IdMap for HdrSnapshot {
+    fn seq_name(&self, i: usize) -> Option<&CStr> {
+        self.names.get(i).map(|s| s.as_c_str())
+    }
+
+    fn seq_len(&self, i: usize) -> Option<usize> {
+        self.lens.get(i).copied()
+    }
+
+    fn num_seqs(&self) -> usize {
+        self.names.len()
+    }
+}
+
+// Batch of records filled by the reading thread.  Records are kept between uses so only
+// the first n entries are valid
+struct Batch<T> {
+    recs: Vec<T>,
+    n: usize,
+}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Self {
+        Self {
+            recs: Vec::new(),
+            n: 0,
+        }
+    }
+}
+
+/// Read records on a background thread.
+///
+/// A dedicated thread reads records from the source into batches, which are passed to the
+/// consuming thread over a bounded channel.  Consumed batches are sent back to be refilled,
+/// so that record buffers are recycled and at most `n_batches` batches are held in memory.
+/// The consuming thread therefore does not block on I/O or decompression unless it is
+/// processing records faster than they can be read.
+///
+/// As the source may borrow from the caller (i.e., a [crate::sam::SamReader]), the reader is
+/// only available within a closure passed to [ReadAhead::run] or [ReadAhead::run_with_hdr].
+pub struct ReadAhead {
+    batch_size: usize,
+    n_batches: usize,
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadAhead {
+    pub fn new() -> Self {
+        Self {
+            batch_size: 256,
+            n_batches: 4,
+        }
+    }
+
+    /// Number of records in each batch (default 256)
+    pub fn set_batch_size(&mut self, n: usize) -> &mut Self {
+        self.batch_size = n.max(1);
+        self
+    }
+
+    /// Number of batches (default 4).  This, together with the batch size, limits the
+    /// number of records held in memory
+    pub fn set_n_batches(&mut self, n: usize) -> &mut Self {
+        self.n_batches = n.max(1);
+        self
+    }
+
+    /// Read from `rd` on a background thread, calling `f` with a reader returning the
+    /// records.  The reading thread is stopped when `f` returns, and the result of `f` is
+    /// returned
+    pub fn run<R, T, F>(&self, rd: &mut R, f: F) -> T
+    where
+        R: ReadRec + Send,
+        R::Rec: Default + Send,
+        F: FnOnce(&mut ReadAheadReader<R::Rec, R::Err>) -> T,
+    {
+        self.run_inner(rd, (), f)
+    }
+
+    /// As [ReadAhead::run], but the reader passed to `f` also implements [IdMap] and
+    /// [SeqId] using a copy of the sequence dictionary from `rd`
+    pub fn run_with_hdr<R, T, F>(&self, rd: &mut R, f: F) -> T
+    where
+        R: ReadRec + IdMap + Send,
+        R::Rec: Default + Send,
+        F: FnOnce(&mut ReadAheadReader<R::Rec, R::Err, HdrSnapshot>) -> T,
+    {
+        let hdr = HdrSnapshot::new(rd);
+        self.run_inner(rd, hdr, f)
+    }
+
+    fn run_inner<R, H, T, F>(&self, rd: &mut R, hdr: H, f: F) -> T
+    where
+        R: ReadRec + Send,
+        R::Rec: Default + Send,
+        F: FnOnce(&mut ReadAheadReader<R::Rec, R::Err, H>) -> T,
+    {
+        let (free_tx, free_rx) = sync_channel(self.n_batches);
+        let (full_tx, full_rx) = sync_channel(self.n_batches);
+        for _ in 0..self.n_batches {
+            free_tx.send(Batch::default()).expect("Channel closed")
+        }
+        let size = self.batch_size;
+
+        thread::scope(|sc| {
+            sc.spawn(move || read_thread(rd, size, free_rx, full_tx));
+            let mut rdr = ReadAheadReader {
+                rx: full_rx,
+                free_tx,
+                batch: Batch::default(),
+                pos: 0,
+                done: false,
+                hdr,
+            };
+            // Dropping the reader closes the channels, which stops the reading thread
+            f(&mut rdr)
+        })
+    }
+}
+
+fn read_thread<R>(
+    rd: &mut R,
+    size: usize,
+    free_rx: Receiver<Batch<R::Rec>>,
+    full_tx: SyncSender<Result<Batch<R::Rec>, R::Err>>,
+) where
+    R: ReadRec,
+    R::Rec: Default,
+{
+    // recv() or send() will fail if the consumer has finished
+    while let Ok(mut b) = free_rx.recv() {
+        b.n = 0;
+        let mut eof = false;
+        while b.n < size {
+            if b.n == b.recs.len() {
+                b.recs.push(R::Rec::default())
+            }
+            match rd.read_rec(&mut b.recs[b.n]) {
+                Ok(Some(())) => b.n += 1,
+                Ok(None) => {
+                    eof = true;
+                    break;
+                }
+                Err(e) => {
+                    let _ = full_tx.send(Err(e));
+                    return;
+                }
+            }
+        }
+        if (b.n > 0 && full_tx.send(Ok(b)).is_err()) || eof {
+            break;
+        }
+    }
+}
+
+/// Consumer side of a [ReadAhead].  If a copy of the header was taken (see
+/// [ReadAhead::run_with_hdr]) then the header traits are also implemented
+pub struct ReadAheadReader<Rec, E, H = ()> {
+    rx: Receiver<Result<Batch<Rec>, E>>,
+    free_tx: SyncSender<Batch<Rec>>,
+    batch: Batch<Rec>,
+    pos: usize,
+    done: bool,
+    hdr: H,
+}
+
+impl<Rec, E, H> ReadAheadReader<Rec, E, H> {
+    /// Copy of the sequence dictionary taken before reading started
+    pub fn hdr(&self) -> &H {
+        &self.hdr
+    }
+
+    // Return the current batch for refilling, and get the next one
+    fn next_batch(&mut self) -> Result<bool, E> {
+        let b = mem::take(&mut self.batch);
+        // The reading thread may already have finished, in which case the batch is not needed
+        let _ = self.free_tx.send(b);
+        self.pos = 0;
+        match self.rx.recv() {
+            Ok(Ok(b)) => {
+                self.batch = b;
+                Ok(true)
+            }
+            Ok(Err(e)) => {
+                self.done = true;
+                Err(e)
+            }
+            Err(_) => {
+                self.done = true;
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl<Rec, E, H> ReadRec for ReadAheadReader<Rec, E, H>
+where
+    E: std::fmt::Debug + std::error::Error + Send + Sync + 'static,
+{
+    type Rec = Rec;
+    type Err = E;
+
+    fn read_rec(&mut self, rec: &mut Rec) -> Result<Option<()>, E> {
+        while self.pos >= self.batch.n {
+            if self.done || !self.next_batch()? {
+                return Ok(None);
+            }
+        }
+        // Swapping leaves the old record in the batch, so its storage is reused
+        mem::swap(rec, &mut self.batch.recs[self.pos]);
+        self.pos += 1;
+        Ok(Some(()))
+    }
+}
+
+impl<Rec, E> HdrType for ReadAheadReader<Rec, E, HdrSnapshot> {
+    fn hdr_type(&self) -> HtsHdrType {
+        self.hdr.hdr_type()
+    }
+}
+
+impl<Rec, E> SeqId for ReadAheadReader<Rec, E, HdrSnapshot> {
+    fn seq_id(&self, s: &CStr) -> Option<usize> {
+        self.hdr.seq_id(s)
+    }
+}
+
+impl<Rec, E> IdMap for ReadAheadReader<Rec, E, HdrSnapshot> {
+    fn seq_name(&self, i: usize) -> Option<&CStr> {
+        self.hdr.seq_name(i)
+    }
+
+    fn seq_len(&self, i: usize) -> Option<usize> {
+        self.hdr.seq_len(i)
+    }
+
+    fn num_seqs(&self) -> usize {
+        self.hdr.num_seqs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        SamError,
+        hts::HtsFile,
+        sam::{BamRec, SamHdr, SamReader},
+    };
+
+    fn qnames<R: ReadRec<Rec = BamRec, Err = SamError>>(
+        rd: &mut R,
+    ) -> Result<Vec<CString>, SamError> {
+        let mut rec = BamRec::new();
+        let mut v = Vec::new();
+        while rd.read_rec(&mut rec)?.is_some() {
+            v.push(rec.qname().unwrap().to_owned())
+        }
+        Ok(v)
+    }
+
+    #[test]
+    fn read_ahead() -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut h)?;
+        let expected = qnames(&mut SamReader::new(&mut h, &hdr))?;
+
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rd = SamReader::new(&mut h, &hdr);
+        let mut ra = ReadAhead::new();
+        ra.set_batch_size(3).set_n_batches(2);
+        let v = ra.run_with_hdr(&mut rd, |r| {
+            assert_eq!(r.num_seqs(), hdr.num_seqs());
+            assert_eq!(r.seq_id(c"ref2"), hdr.seq_id(c"ref2"));
+            qnames(r)
+        })?;
+        assert_eq!(v, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn early_exit() -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rd = SamReader::new(&mut h, &hdr);
+        let mut ra = ReadAhead::new();
+        ra.set_batch_size(1).set_n_batches(1);
+        let mut rec = BamRec::new();
+        let first = ra.run(&mut rd, |r| r.read_rec(&mut rec))?;
+        assert!(first.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use std::{ffi::CStr, iter::FusedIterator};
+
+use super::traits::{HdrType, HtsHdrType, IdMap, ReadRec, SeqId};
+
+/// Convenience adapters for [ReadRec] sources (i.e., SamReader, HtsRegionIter, etc.)
+pub trait ReadRecExt: ReadRec + Sized {
+    /// Lending style iteration, where records are read into a single internal buffer
+    /// that is reused for each record
+    fn rec_reader(self) -> RecReader<Self>
+    where
+        Self::Rec: Default,
+    {
+        RecReader {
+            rd: self,
+            rec: Self::Rec::default(),
+        }
+    }
+
+    /// Iterator returning owned records.  This allocates a new record for each
+    /// item, so [ReadRecExt::rec_reader] should be preferred where possible
+    fn into_rec_iter(self) -> RecIntoIter<Self>
+    where
+        Self::Rec: Default,
+    {
+        RecIntoIter {
+            rd: self,
+            done: false,
+        }
+    }
+
+    /// Only return records for which `pred` returns true
+    fn filter_rec<P>(self, pred: P) -> FilterRec<Self, P>
+    where
+        P: FnMut(&Self::Rec) -> bool,
+    {
+        FilterRec { rd: self, pred }
+    }
+
+    /// Call `f` for each record, stopping at the first error
+    fn try_for_each_rec<F, E>(&mut self, mut f: F) -> Result<(), E>
+    where
+        Self::Rec: Default,
+        F: FnMut(&mut Self::Rec) -> Result<(), E>,
+        E: From<Self::Err>,
+    {
+        let mut rec = Self::Rec::default();
+        while self.read_rec(&mut rec)?.is_some() {
+            f(&mut rec)?
+        }
+        Ok(())
+    }
+}
+
+impl<R: ReadRec> ReadRecExt for R {}
+
+/// Lending iterator over a [ReadRec] source (see [ReadRecExt::rec_reader])
+pub struct RecReader<R: ReadRec> {
+    rd: R,
+    rec: R::Rec,
+}
+
+impl<R: ReadRec> RecReader<R> {
+    /// Read the next record, returning a reference to the internal buffer, or
+    /// None at the end of input
+    pub fn next_rec(&mut self) -> Result<Option<&R::Rec>, R::Err> {
+        Ok(self.rd.read_rec(&mut self.rec)?.map(|_| &self.rec))
+    }
+
+    /// As [RecReader::next_rec] but returning a mutable reference
+    pub fn next_rec_mut(&mut self) -> Result<Option<&mut R::Rec>, R::Err> {
+        Ok(self.rd.read_rec(&mut self.rec)?.map(|_| &mut self.rec))
+    }
+
+    /// Call `f` for each remaining record, stopping at the first error
+    pub fn try_for_each<F, E>(&mut self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&R::Rec) -> Result<(), E>,
+        E: From<R::Err>,
+    {
+        while let Some(rec) = self.next_rec()? {
+            f(rec)?
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &R {
+        &self.rd
+    }
+
+    #[inline]
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.rd
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rd
+    }
+}
+
+/// Iterator over owned records from a [ReadRec] source (see [ReadRecExt::into_rec_iter]).
+/// Iteration stops after the first error
+pub struct RecIntoIter<R> {
+    rd: R,
+    done: bool,
+}
+
+impl<R> RecIntoIter<R> {
+    pub fn into_inner(self) -> R {
+        self.rd
+    }
+}
+
+impl<R> Iterator for RecIntoIter<R>
+where
+    R: ReadRec,
+    R::Rec: Default,
+{
+    type Item = Result<R::Rec, R::Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut rec = R::Rec::default();
+        match self.rd.read_rec(&mut rec) {
+            Ok(Some(())) => Some(Ok(rec)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<R> FusedIterator for RecIntoIter<R>
+where
+    R: ReadRec,
+    R::Rec: Default,
+{
+}
+
+/// [ReadRec] source returning only records matching a predicate (see [ReadRecExt::filter_rec])
+pub struct FilterRec<R, P> {
+    rd: R,
+    pred: P,
+}
+
+impl<R, P> FilterRec<R, P> {
+    pub fn into_inner(self) -> R {
+        self.rd
+    }
+}
+
+impl<R, P> ReadRec for FilterRec<R, P>
+where
+    R: ReadRec,
+    P: FnMut(&R::Rec) -> bool,
+{
+    type Rec = R::Rec;
+    type Err = R::Err;
+
+    fn read_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        while self.rd.read_rec(rec)?.is_some() {
+            if (self.pred)(rec) {
+                return Ok(Some(()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Pass through header information so that contigs can still be resolved
+
+impl<R: ReadRec + HdrType> HdrType for RecReader<R> {
+    fn hdr_type(&self) -> HtsHdrType {
+        self.rd.hdr_type()
+    }
+}
+
+impl<R: ReadRec + SeqId> SeqId for RecReader<R> {
+    fn seq_id(&self, s: &CStr) -> Option<usize> {
+        self.rd.seq_id(s)
+    }
+}
+
+impl<R: ReadRec + IdMap> IdMap for RecReader<R> {
+    fn seq_name(&self, i: usize) -> Option<&CStr> {
+        self.rd.seq_name(i)
+    }
+
+    fn seq_len(&self, i: usize) -> Option<usize> {
+        self.rd.seq_len(i)
+    }
+
+    fn num_seqs(&self) -> usize {
+        self.rd.num_seqs()
+    }
+}
+
+impl<R: HdrType, P> HdrType for FilterRec<R, P> {
+    fn hdr_type(&self) -> HtsHdrType {
+        self.rd.hdr_type()
+    }
+}
+
+impl<R: SeqId, P> SeqId for FilterRec<R, P> {
+    fn seq_id(&self, s: &CStr) -> Option<usize> {
+        self.rd.seq_id(s)
+    }
+}
+
+impl<R: IdMap, P> IdMap for FilterRec<R, P> {
+    fn seq_name(&self, i: usize) -> Option<&CStr> {
+        self.rd.seq_name(i)
+    }
+
+    fn seq_len(&self, i: usize) -> Option<usize> {
+        self.rd.seq_len(i)
+    }
+
+    fn num_seqs(&self) -> usize {
+        self.rd.num_seqs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        SamError,
+        hts::HtsFile,
+        region::Reg,
+        sam::{SamHdr, SamReader},
+    };
+
+    #[test]
+    fn rec_reader() -> Result<(), SamError> {
+        let mut h =
+            HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to read test/realn01.sam");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rd = SamReader::new(&mut h, &hdr).rec_reader();
+        let mut n = 0;
+        while let Some(rec) = rd.next_rec()? {
+            assert!(rec.qname().is_some());
+            n += 1
+        }
+        assert_eq!(n, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn rec_iter() -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut h)?;
+        let reg = Reg::from_u8_slice(b"ref2:25-").unwrap();
+        let it = SamReader::new(&mut h, &hdr).region_iter(&reg)?;
+        let v = it
+            .filter_rec(|r| !r.is_reversed())
+            .into_rec_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(v.len(), 4);
+        assert!(v.iter().all(|r| !r.is_reversed()));
+        Ok(())
+    }
+}
@@ -4,18 +4,60 @@ pub mod cigar;
 pub mod cigar_buf;
 pub mod cigar_error;
 mod cigar_validate;
+pub mod depth;
+pub mod dict_compat;
+pub mod fixmate;
+pub mod hdr_model;
+pub mod hdr_validate;
+pub mod markdup;
+pub mod mate_pair;
+pub mod merger;
+pub mod mod_pileup;
+pub mod pg_chain;
+pub mod pileup;
+pub mod pipeline;
 pub mod record;
+pub mod ref_md5;
+pub mod reheader;
 pub mod sam_error;
 pub mod sam_hdr;
+pub mod sam_index;
+pub mod sam_stats;
+pub mod seq_dict;
 pub mod seq_iter;
+pub mod shard;
+pub mod sorter;
+pub mod template;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 pub use bam_data::*;
 pub use base_mods::*;
 pub use cigar::*;
 pub use cigar_buf::*;
+pub use depth::*;
+pub use dict_compat::*;
+pub use fixmate::*;
+pub use hdr_model::*;
+pub use hdr_validate::*;
+pub use markdup::*;
+pub use mate_pair::*;
+pub use merger::*;
+pub use mod_pileup::*;
+pub use pg_chain::*;
+pub use pileup::*;
+pub use pipeline::*;
 pub use record::bam1::aux_iter::*;
 pub use record::*;
+pub use ref_md5::*;
+pub use reheader::*;
 pub use sam_hdr::*;
+pub use sam_index::*;
+pub use sam_stats::*;
 pub use record::sam_reader::*;
 pub use record::sam_writer::*;
+pub use seq_dict::*;
 pub use seq_iter::*;
+pub use shard::*;
+pub use sorter::*;
+pub use template::*;
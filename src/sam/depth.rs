@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::{
+    SamError,
+    hts::{
+        HtsPos, HtsRegion,
+        traits::{IdMap, ReadRecIter},
+    },
+    region::{Reg, RegionList},
+    sam::{
+        BamRec, CigarOp, PILEUP_DEFAULT_FLAG_FILTER, SamReader,
+        record::bam1::{BAM_FMUNMAP, BAM_FPAIRED, BAM_FSUPPLEMENTARY},
+    },
+};
+
+/// Per base depth for a region
+#[derive(Debug, Clone)]
+pub struct RegionDepth {
+    tid: usize,
+    ctg: String,
+    start: HtsPos,
+    depth: Vec<u32>,
+}
+
+impl RegionDepth {
+    #[inline]
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    #[inline]
+    pub fn contig(&self) -> &str {
+        &self.ctg
+    }
+
+    /// Start of region (zero offset)
+    #[inline]
+    pub fn start(&self) -> HtsPos {
+        self.start
+    }
+
+    /// End of region (half open, so one past the last position in the region)
+    #[inline]
+    pub fn end(&self) -> HtsPos {
+        self.start + self.depth.len() as HtsPos
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.depth.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.depth.is_empty()
+    }
+
+    /// Depth at each position in the region
+    #[inline]
+    pub fn depth(&self) -> &[u32] {
+        &self.depth
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.depth.is_empty() {
+            0.0
+        } else {
+            self.depth.iter().map(|x| *x as u64).sum::<u64>() as f64 / self.depth.len() as f64
+        }
+    }
+
+    pub fn median(&self) -> f64 {
+        let n = self.depth.len();
+        if n == 0 {
+            return 0.0;
+        }
+        let mut v = self.depth.clone();
+        let (lo, m, _) = v.select_nth_unstable(n >> 1);
+        let m = *m as f64;
+        if n & 1 == 1 {
+            m
+        } else {
+            (m + *lo.iter().max().unwrap() as f64) / 2.0
+        }
+    }
+
+    /// Fraction of positions in region with depth >= `t`
+    pub fn frac_at_least(&self, t: u32) -> f64 {
+        if self.depth.is_empty() {
+            0.0
+        } else {
+            self.depth.iter().filter(|x| **x >= t).count() as f64 / self.depth.len() as f64
+        }
+    }
+
+    /// Write per base depth in a BED like format (contig, start, end, depth)
+    pub fn write_per_base<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (x, d) in (self.start..).zip(self.depth.iter()) {
+            writeln!(w, "{}\t{}\t{}\t{}", self.ctg, x, x + 1, d)?
+        }
+        Ok(())
+    }
+}
+
+/// Calculate depth over regions from an indexed SAM/BAM/CRAM file
+#[derive(Debug, Clone)]
+pub struct DepthCalc {
+    min_mapq: u8,
+    min_base_qual: u8,
+    flag_filter: u16,
+    count_deletions: bool,
+    dedup_overlaps: bool,
+    thresholds: Vec<u32>,
+}
+
+impl Default for DepthCalc {
+    fn default() -> Self {
+        Self {
+            min_mapq: 0,
+            min_base_qual: 0,
+            flag_filter: PILEUP_DEFAULT_FLAG_FILTER,
+            count_deletions: false,
+            dedup_overlaps: false,
+            thresholds: vec![1, 10, 20, 30],
+        }
+    }
+}
+
+impl DepthCalc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_min_mapq(&mut self, q: u8) -> &mut Self {
+        self.min_mapq = q;
+        self
+    }
+
+    /// Bases with quality below `q` are not counted
+    pub fn set_min_base_qual(&mut self, q: u8) -> &mut Self {
+        self.min_base_qual = q;
+        self
+    }
+
+    /// Records with any of the bits in `flag` set are skipped
+    /// (default [PILEUP_DEFAULT_FLAG_FILTER])
+    pub fn set_flag_filter(&mut self, flag: u16) -> &mut Self {
+        self.flag_filter = flag;
+        self
+    }
+
+    /// Count deletions towards the depth (default false)
+    pub fn set_count_deletions(&mut self, x: bool) -> &mut Self {
+        self.count_deletions = x;
+        self
+    }
+
+    /// If set, positions covered by both reads of an overlapping pair are only counted once
+    /// (default false)
+    pub fn set_dedup_overlaps(&mut self, x: bool) -> &mut Self {
+        self.dedup_overlaps = x;
+        self
+    }
+
+    /// Set depth thresholds used for summary output (default 1, 10, 20, 30)
+    pub fn set_thresholds(&mut self, t: &[u32]) -> &mut Self {
+        self.thresholds = t.to_vec();
+        self
+    }
+
+    pub fn thresholds(&self) -> &[u32] {
+        &self.thresholds
+    }
+
+    /// Calculate depth for all regions in `regions` (which must be normalized).  Unmapped
+    /// regions are ignored.
+    ///
+    /// The per base depths for all regions are kept in memory (4 bytes per base), so this
+    /// should not be used for large regions; see [DepthCalc::region_depth_windows]
+    pub fn calc(
+        &self,
+        rdr: &mut SamReader,
+        regions: &RegionList,
+    ) -> Result<Vec<RegionDepth>, SamError> {
+        let mut v = Vec::new();
+        for reg in regions.regions() {
+            v.extend(self.region_depth(rdr, &reg)?)
+        }
+        Ok(v)
+    }
+
+    /// Calculate depth for a single region.  If the region covers all contigs then
+    /// one [RegionDepth] is returned per contig.
+    ///
+    /// The per base depth for the whole region is kept in memory (4 bytes per base), so for a
+    /// region covering all contigs of a human genome this needs around 12GB, and around 1GB for
+    /// chr1 alone.  Use [DepthCalc::region_depth_windows] for large regions
+    pub fn region_depth(
+        &self,
+        rdr: &mut SamReader,
+        reg: &Reg,
+    ) -> Result<Vec<RegionDepth>, SamError> {
+        self.htslib_regions(rdr, reg)?
+            .into_iter()
+            .map(|hreg| self.depth(rdr, hreg))
+            .collect()
+    }
+
+    /// Calculate depth for a single region in windows of at most `window` bases, calling `f`
+    /// with the [RegionDepth] for each window in turn.  Memory use is limited by the window
+    /// size rather than the size of the region.  Records overlapping more than one window are
+    /// read once for each window
+    pub fn region_depth_windows<F>(
+        &self,
+        rdr: &mut SamReader,
+        reg: &Reg,
+        window: usize,
+        mut f: F,
+    ) -> Result<(), SamError>
+    where
+        F: FnMut(RegionDepth) -> Result<(), SamError>,
+    {
+        let window = window.max(1) as HtsPos;
+        for hreg in self.htslib_regions(rdr, reg)? {
+            let mut x = hreg.start();
+            while x < hreg.end() {
+                let y = (x + window).min(hreg.end());
+                f(self.depth(rdr, HtsRegion::new(hreg.tid(), x, y))?)?;
+                x = y
+            }
+        }
+        Ok(())
+    }
+
+    // Convert `reg` into a list of htslib regions (one per contig if `reg` covers all contigs)
+    fn htslib_regions(&self, rdr: &SamReader, reg: &Reg) -> Result<Vec<HtsRegion>, SamError> {
+        if reg.is_unmapped() {
+            Ok(Vec::new())
+        } else if reg.is_all() {
+            Ok((0..rdr.num_seqs())
+                .map(|tid| {
+                    let len = rdr.seq_len(tid).unwrap_or(0) as HtsPos;
+                    HtsRegion::new(tid as i32, 0, len)
+                })
+                .collect())
+        } else {
+            let hreg = reg
+                .make_htslib_region(rdr)
+                .map_err(|e| SamError::InvalidRegion(format!("{reg}: {e}")))?;
+            Ok(vec![hreg])
+        }
+    }
+
+    fn depth(&self, rdr: &mut SamReader, hreg: HtsRegion) -> Result<RegionDepth, SamError> {
+        let tid = hreg.tid() as usize;
+        let (start, end) = (hreg.start(), hreg.end());
+        let ctg = rdr
+            .seq_name(tid)
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut depth = vec![0u32; (end - start) as usize];
+        let mut itr = rdr.query(&hreg)?;
+        let mut rec = BamRec::new();
+
+        // Positions counted from the first read of overlapping pairs (indexed by query name)
+        let mut overlaps: HashMap<Box<[u8]>, (HtsPos, Vec<bool>)> = HashMap::new();
+
+        while rdr.read_rec_iter(&mut itr, &mut rec)?.is_some() {
+            if rec.flag() & self.flag_filter != 0 || rec.mapq() < self.min_mapq {
+                continue;
+            }
+            let (Some(cigar), Some(mut rpos)) = (rec.cigar(), rec.pos()) else {
+                continue;
+            };
+
+            // Check for overlapping mates
+            let mut skip = None;
+            let mut store = None;
+            if self.dedup_overlaps
+                && rec.flag() & (BAM_FPAIRED | BAM_FMUNMAP | BAM_FSUPPLEMENTARY) == BAM_FPAIRED
+                && rec.mtid() == rec.tid()
+            {
+                let qn: Box<[u8]> = rec.qname().map(|s| s.to_bytes()).unwrap_or(&[]).into();
+                if let Some(x) = overlaps.remove(&qn) {
+                    skip = Some(x)
+                } else if let Some(mp) = rec.mpos()
+                    && mp >= rpos
+                    && mp < rec.endpos()
+                {
+                    store = Some((qn, mp, vec![false; (rec.endpos() - mp) as usize]))
+                }
+            }
+
+            let qual = rec.qual_slice();
+            let mut add = |p: HtsPos, q: Option<u8>| {
+                if p < start || p >= end {
+                    return;
+                }
+                if let Some(q) = q
+                    && q != 0xff
+                    && q < self.min_base_qual
+                {
+                    return;
+                }
+                if let Some((s, v)) = skip.as_ref()
+                    && p >= *s
+                    && v.get((p - s) as usize).copied().unwrap_or(false)
+                {
+                    return;
+                }
+                depth[(p - start) as usize] += 1;
+                if let Some((_, mp, v)) = store.as_mut()
+                    && p >= *mp
+                {
+                    v[(p - *mp) as usize] = true
+                }
+            };
+
+            let mut qpos = 0;
+            for e in cigar.as_elems() {
+                let (op, len) = e.op_pair();
+                let len = len as usize;
+                match op {
+                    CigarOp::Match | CigarOp::Equal | CigarOp::Diff => {
+                        for i in 0..len {
+                            add(rpos + i as HtsPos, qual.get(qpos + i).copied())
+                        }
+                    }
+                    CigarOp::Del if self.count_deletions => {
+                        for i in 0..len {
+                            add(rpos + i as HtsPos, None)
+                        }
+                    }
+                    _ => {}
+                }
+                if e.consumes_reference() {
+                    rpos += len as HtsPos
+                }
+                if e.consumes_query() {
+                    qpos += len
+                }
+            }
+            if let Some((qn, mp, v)) = store {
+                overlaps.insert(qn, (mp, v));
+            }
+        }
+        Ok(RegionDepth {
+            tid,
+            ctg,
+            start,
+            depth,
+        })
+    }
+
+    /// Write summary for each region in a BED like format (contig, start, end, mean, median,
+    /// followed by the fraction of bases at or above each threshold)
+    pub fn write_summary<W: Write>(&self, w: &mut W, regions: &[RegionDepth]) -> io::Result<()> {
+        write!(w, "#chrom\tstart\tend\tmean\tmedian")?;
+        for t in self.thresholds.iter() {
+            write!(w, "\tfrac_ge_{t}")?
+        }
+        writeln!(w)?;
+        for r in regions {
+            write!(
+                w,
+                "{}\t{}\t{}\t{:.3}\t{:.1}",
+                r.ctg,
+                r.start,
+                r.end(),
+                r.mean(),
+                r.median()
+            )?;
+            for t in self.thresholds.iter() {
+                write!(w, "\t{:.4}", r.frac_at_least(*t))?
+            }
+            writeln!(w)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hts::HtsFile, sam::SamHdr};
+
+    #[test]
+    fn depth() -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rdr = SamReader::new(&mut h, &hdr);
+
+        let mut rl = RegionList::new();
+        rl.add("ref1").unwrap();
+        rl.add("ref2:25-").unwrap();
+        rl.normalize();
+
+        let dc = DepthCalc::new();
+        let v = dc.calc(&mut rdr, &rl)?;
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[1].start(), 24);
+        assert!(v.iter().all(|r| r.mean() > 0.0));
+        assert!(v[0].frac_at_least(1) <= 1.0);
+
+        let mut w = Vec::new();
+        dc.write_summary(&mut w, &v).unwrap();
+        assert_eq!(w.iter().filter(|c| **c == b'\n').count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn depth_windows() -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rdr = SamReader::new(&mut h, &hdr);
+
+        let dc = DepthCalc::new();
+        let reg = Reg::from_u8_slice(b"ref1").expect("Bad region");
+        let v = dc.region_depth(&mut rdr, &reg)?;
+        let mut w = Vec::new();
+        dc.region_depth_windows(&mut rdr, &reg, 10, |r| {
+            w.push(r);
+            Ok(())
+        })?;
+        assert_eq!(w.len(), v[0].len().div_ceil(10));
+        assert!(w.iter().all(|r| r.len() <= 10 && r.tid() == v[0].tid()));
+        assert_eq!(w[1].start(), 10);
+        let d: Vec<_> = w.iter().flat_map(|r| r.depth().iter().copied()).collect();
+        assert_eq!(d, v[0].depth());
+
+        let all = Reg::from_u8_slice(b".").expect("Bad region");
+        let mut n = 0;
+        dc.region_depth_windows(&mut rdr, &all, 20, |r| {
+            assert!(r.len() <= 20);
+            n += r.len();
+            Ok(())
+        })?;
+        let total: usize = (0..hdr.num_seqs()).map(|i| hdr.seq_len(i).unwrap()).sum();
+        assert_eq!(n, total);
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    ffi::{CStr, CString},
+    fmt,
+};
+
+use super::{ContigMap, TidRemap};
+use crate::hts::traits::{IdMap, SeqId};
+
+/// Contig present in both dictionaries with different lengths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LenMismatch {
+    pub name: String,
+    pub len1: usize,
+    pub len2: usize,
+}
+
+/// Result of comparing two sequence dictionaries with [compare_dicts]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DictCompat {
+    /// Number of contigs present in both dictionaries (including those matched by [DictCompat::chr_matches])
+    pub n_shared: usize,
+    /// Contigs only found in the first dictionary
+    pub only_in_first: Vec<String>,
+    /// Contigs only found in the second dictionary
+    pub only_in_second: Vec<String>,
+    /// Contigs present in both dictionaries whose lengths differ
+    pub len_mismatches: Vec<LenMismatch>,
+    /// Contigs whose names only differ by a `chr` prefix (name in first, name in second)
+    pub chr_matches: Vec<(String, String)>,
+    /// True if the shared contigs do not occur in the same order in both dictionaries
+    pub order_differs: bool,
+}
+
+impl DictCompat {
+    /// True if the dictionaries have the same contigs with the same lengths in the same order
+    pub fn is_identical(&self) -> bool {
+        self.is_compatible() && self.only_in_first.is_empty() && self.only_in_second.is_empty()
+    }
+
+    /// True if the contigs present in both dictionaries have the same names and lengths and
+    /// occur in the same order, so that data for the shared contigs can be used together
+    /// without translation
+    pub fn is_compatible(&self) -> bool {
+        self.len_mismatches.is_empty() && self.chr_matches.is_empty() && !self.order_differs
+    }
+
+    /// Mapping of names in the first dictionary to the matching names in the second
+    /// dictionary for contigs whose names differ by a `chr` prefix
+    pub fn contig_map(&self) -> ContigMap {
+        let mut m = ContigMap::new();
+        for (a, b) in self.chr_matches.iter() {
+            m.insert(a, b);
+        }
+        m
+    }
+
+    /// Table converting tids in `first` to tids in `second`, where `first` and `second`
+    /// are the dictionaries that were compared.  Contigs whose names differ by a `chr` prefix
+    /// are matched
+    pub fn tid_remap<A: IdMap, B: SeqId>(&self, first: &A, second: &B) -> TidRemap {
+        TidRemap::new(first, second, &self.contig_map())
+    }
+}
+
+impl fmt::Display for DictCompat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Shared contigs: {}", self.n_shared)?;
+        for s in self.only_in_first.iter() {
+            writeln!(f, "Only in first: {s}")?
+        }
+        for s in self.only_in_second.iter() {
+            writeln!(f, "Only in second: {s}")?
+        }
+        for l in self.len_mismatches.iter() {
+            writeln!(f, "Length mismatch: {} ({} vs {})", l.name, l.len1, l.len2)?
+        }
+        for (a, b) in self.chr_matches.iter() {
+            writeln!(f, "Names differ by chr prefix: {a} vs {b}")?
+        }
+        if self.order_differs {
+            writeln!(f, "Contig order differs")?
+        }
+        Ok(())
+    }
+}
+
+// Alternative name with the chr prefix added or removed (including MT <-> chrM)
+fn chr_alternative(s: &str) -> Option<String> {
+    match s {
+        "MT" => Some("chrM".to_owned()),
+        "chrM" => Some("MT".to_owned()),
+        _ => match s.strip_prefix("chr") {
+            Some("") => None,
+            Some(t) => Some(t.to_owned()),
+            None => Some(format!("chr{s}")),
+        },
+    }
+}
+
+fn lookup<B: SeqId>(b: &B, s: &str) -> Option<usize> {
+    CString::new(s).ok().and_then(|c| b.seq_id(&c))
+}
+
+fn name_string(s: Option<&CStr>) -> String {
+    s.map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Compare the contig dictionaries of two [IdMap] implementors (i.e., [crate::sam::SamHdr],
+/// [crate::faidx::Faidx] etc.), reporting contigs missing from either dictionary, length
+/// mismatches, order differences and names that only differ by a `chr` prefix
+pub fn compare_dicts<A, B>(first: &A, second: &B) -> DictCompat
+where
+    A: IdMap,
+    B: IdMap + SeqId,
+{
+    let mut res = DictCompat::default();
+    let mut matched = vec![false; second.num_seqs()];
+    let mut last_ix = None;
+
+    for i in 0..first.num_seqs() {
+        let name = name_string(first.seq_name(i));
+        let (j, chr) = match lookup(second, &name) {
+            Some(j) => (Some(j), false),
+            None => (
+                chr_alternative(&name).and_then(|s| lookup(second, &s)),
+                true,
+            ),
+        };
+        let Some(j) = j.filter(|j| !matched[*j]) else {
+            res.only_in_first.push(name);
+            continue;
+        };
+        matched[j] = true;
+        res.n_shared += 1;
+        if chr {
+            res.chr_matches
+                .push((name.clone(), name_string(second.seq_name(j))))
+        }
+        let (len1, len2) = (first.seq_len(i), second.seq_len(j));
+        if len1 != len2 {
+            res.len_mismatches.push(LenMismatch {
+                name,
+                len1: len1.unwrap_or(0),
+                len2: len2.unwrap_or(0),
+            })
+        }
+        if last_ix.is_some_and(|k| j < k) {
+            res.order_differs = true
+        }
+        last_ix = Some(j)
+    }
+    res.only_in_second = matched
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !**m)
+        .map(|(j, _)| name_string(second.seq_name(j)))
+        .collect();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        SamError,
+        faidx::Faidx,
+        hts::HtsFile,
+        sam::{SamHdr, test_util::make_hdr},
+    };
+
+    #[test]
+    fn compare() -> Result<(), SamError> {
+        let h1 = make_hdr(
+            c"@SQ\tSN:1\tLN:1000\n@SQ\tSN:2\tLN:2000\n@SQ\tSN:3\tLN:3000\n@SQ\tSN:MT\tLN:16569\n@SQ\tSN:Un1\tLN:5",
+        )?;
+        let h2 = make_hdr(
+            c"@SQ\tSN:chr2\tLN:2000\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr3\tLN:3001\n@SQ\tSN:chrM\tLN:16569\n@SQ\tSN:chrX\tLN:10",
+        )?;
+        let c = compare_dicts(&h1, &h2);
+        assert_eq!(c.n_shared, 4);
+        assert_eq!(c.only_in_first, ["Un1"]);
+        assert_eq!(c.only_in_second, ["chrX"]);
+        assert_eq!(c.chr_matches.len(), 4);
+        assert_eq!(
+            c.len_mismatches,
+            [LenMismatch {
+                name: "3".to_owned(),
+                len1: 3000,
+                len2: 3001
+            }]
+        );
+        assert!(c.order_differs);
+        assert!(!c.is_compatible());
+
+        let r = c.tid_remap(&h1, &h2);
+        assert_eq!(r.get(0), Some(1));
+        assert_eq!(r.get(3), Some(3));
+        assert_eq!(r.get(4), None);
+        Ok(())
+    }
+
+    #[test]
+    fn compare_faidx() -> Result<(), SamError> {
+        let fai = Faidx::load("test/realn01.fa")?;
+        let mut f = HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to open file");
+        let hdr = SamHdr::read(&mut f)?;
+        let c = compare_dicts(&hdr, &fai);
+        assert!(c.is_identical(), "{c}");
+        Ok(())
+    }
+}
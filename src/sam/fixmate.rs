@@ -0,0 +1,394 @@
+use crate::{
+    SamError,
+    hts::HtsPos,
+    sam::{
+        BamRec,
+        record::bam1::{
+            BAM_FMREVERSE, BAM_FMUNMAP, BAM_FPAIRED, BAM_FPROPER_PAIR, BAM_FREAD1, BAM_FREAD2,
+            BAM_FREVERSE, BAM_FSECONDARY, BAM_FSUPPLEMENTARY, BAM_FUNMAP,
+        },
+    },
+};
+
+/// Minimum base quality counted towards the mate score (ms tag), as used by samtools fixmate
+const MATE_SCORE_MIN_QUAL: u8 = 15;
+
+/// Fill in mate information for name collated read pairs.
+///
+/// This performs the same job as `samtools fixmate`, synchronizing the mate fields of each
+/// member of a pair (mtid, mpos, the mate reverse and mate unmapped flags), calculating the
+/// template length and adding the MC (mate cigar) and MQ (mate mapping quality) tags. Optionally
+/// the ms (mate score) tag can also be added, and the proper pair flag can be cleared for pairs
+/// that can not be properly paired.
+///
+/// Unmapped reads with a mapped mate are placed at the position of their mate.
+///
+/// Secondary and supplementary records are updated using the information from the primary
+/// record of the other read in the pair (see [FixMate::fix_template]).
+#[derive(Debug)]
+pub struct FixMate {
+    add_mate_score: bool,
+    proper_pair_check: bool,
+    cigar_buf: String,
+}
+
+impl Default for FixMate {
+    fn default() -> Self {
+        Self {
+            add_mate_score: false,
+            proper_pair_check: true,
+            cigar_buf: String::new(),
+        }
+    }
+}
+
+/// Information from a primary record required to update the mate fields of the other read
+/// in the pair
+#[derive(Debug, Default, Clone)]
+struct MateInfo {
+    tid: Option<usize>,
+    raw_pos: HtsPos,
+    flag: u16,
+    mapq: u8,
+    cigar: Option<String>,
+    score: i64,
+}
+
+impl MateInfo {
+    fn from_rec(rec: &BamRec, buf: &mut String, add_mate_score: bool) -> Self {
+        let mapped = rec.is_mapped();
+        let cigar = if mapped {
+            rec.cigar().map(|c| {
+                buf.clear();
+                use std::fmt::Write;
+                let _ = write!(buf, "{c}");
+                buf.clone()
+            })
+        } else {
+            None
+        };
+        let score = if add_mate_score {
+            calc_mate_score(rec)
+        } else {
+            0
+        };
+
+        Self {
+            tid: rec.tid(),
+            raw_pos: rec.raw_pos(),
+            flag: rec.flag(),
+            mapq: rec.mapq(),
+            cigar,
+            score,
+        }
+    }
+
+    #[inline]
+    fn is_mapped(&self) -> bool {
+        self.flag & BAM_FUNMAP == 0
+    }
+
+    fn apply(&self, rec: &mut BamRec, add_mate_score: bool) -> Result<(), SamError> {
+        let mut flag = rec.flag() & !(BAM_FMUNMAP | BAM_FMREVERSE);
+        if !self.is_mapped() {
+            flag |= BAM_FMUNMAP
+        }
+        if self.flag & BAM_FREVERSE != 0 {
+            flag |= BAM_FMREVERSE
+        }
+        rec.set_flag(flag | BAM_FPAIRED);
+        rec.set_mtid(self.tid);
+        rec.set_mpos(if self.tid.is_some() {
+            Some(self.raw_pos)
+        } else {
+            None
+        });
+
+        if self.is_mapped() {
+            rec.update_int_tag("MQ", self.mapq as i64)?;
+            if let Some(c) = self.cigar.as_deref() {
+                rec.update_str_tag("MC", c.as_bytes())?
+            } else {
+                rec.del_tag("MC")?;
+            }
+        } else {
+            rec.del_tags(&["MC", "MQ"])?;
+        }
+        if add_mate_score {
+            rec.update_int_tag("ms", self.score)?
+        }
+        Ok(())
+    }
+}
+
+impl FixMate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// If set, the ms (mate score) tag is added to each record. This is required if
+    /// the output is to be used for duplicate marking by samtools markdup.
+    pub fn set_add_mate_score(&mut self, x: bool) -> &mut Self {
+        self.add_mate_score = x;
+        self
+    }
+
+    /// If set (the default), the proper pair flag is cleared from pairs that are not mapped
+    /// to the same contig in forward/reverse orientation
+    pub fn set_proper_pair_check(&mut self, x: bool) -> &mut Self {
+        self.proper_pair_check = x;
+        self
+    }
+
+    /// Synchronize the mate information for the two primary records `a` and `b` from a read pair
+    pub fn fix_pair(&mut self, a: &mut BamRec, b: &mut BamRec) -> Result<(), SamError> {
+        check_pair(a, b)?;
+        sync_unmapped_pos(a, b);
+        sync_unmapped_pos(b, a);
+
+        let info_a = MateInfo::from_rec(a, &mut self.cigar_buf, self.add_mate_score);
+        let info_b = MateInfo::from_rec(b, &mut self.cigar_buf, self.add_mate_score);
+        info_b.apply(a, self.add_mate_score)?;
+        info_a.apply(b, self.add_mate_score)?;
+
+        if self.proper_pair_check && !plausibly_properly_paired(a, b) {
+            a.set_flag(a.flag() & !BAM_FPROPER_PAIR);
+            b.set_flag(b.flag() & !BAM_FPROPER_PAIR);
+        }
+
+        let (tlen_a, tlen_b) = template_len(a, b);
+        a.set_template_len(tlen_a);
+        b.set_template_len(tlen_b);
+        Ok(())
+    }
+
+    /// Update the mate fields of the secondary or supplementary record `rec` using the primary
+    /// record of the mate `mate`.  The template length is taken from the primary record of the
+    /// same read as `rec` (`primary`) if this is available, otherwise it is set to 0
+    pub fn fix_non_primary(
+        &mut self,
+        rec: &mut BamRec,
+        mate: &BamRec,
+        primary: Option<&BamRec>,
+    ) -> Result<(), SamError> {
+        if rec.qname() != mate.qname() {
+            return Err(SamError::MateQnameMismatch);
+        }
+        let info = MateInfo::from_rec(mate, &mut self.cigar_buf, self.add_mate_score);
+        info.apply(rec, self.add_mate_score)?;
+        rec.set_template_len(primary.map(|p| p.template_len()).unwrap_or(0));
+        Ok(())
+    }
+
+    /// Fix all of the records for a template (i.e., all records sharing the same query name).
+    ///
+    /// The primary read 1 and read 2 records are located and the mate information for these is
+    /// synchronized using [FixMate::fix_pair].  All other (secondary or supplementary) records are
+    /// then updated with the information from the primary record of the other read.  If the
+    /// template is not paired or either primary record is missing then the records are left
+    /// untouched.
+    ///
+    /// Returns true if the template was updated
+    pub fn fix_template(&mut self, recs: &mut [BamRec]) -> Result<bool, SamError> {
+        let find_primary = |recs: &[BamRec], f: u16| {
+            recs.iter().position(|r| {
+                let flag = r.flag();
+                flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) == 0
+                    && flag & (BAM_FREAD1 | BAM_FREAD2) == f
+                    && flag & BAM_FPAIRED != 0
+            })
+        };
+
+        let (i, j) = match (
+            find_primary(recs, BAM_FREAD1),
+            find_primary(recs, BAM_FREAD2),
+        ) {
+            (Some(i), Some(j)) => (i, j),
+            _ => return Ok(false),
+        };
+
+        {
+            let (a, b) = get_two_mut(recs, i, j);
+            self.fix_pair(a, b)?;
+        }
+
+        let info1 = MateInfo::from_rec(&recs[i], &mut self.cigar_buf, self.add_mate_score);
+        let info2 = MateInfo::from_rec(&recs[j], &mut self.cigar_buf, self.add_mate_score);
+        let (tlen1, tlen2) = (recs[i].template_len(), recs[j].template_len());
+
+        for (k, rec) in recs.iter_mut().enumerate() {
+            if k == i || k == j {
+                continue;
+            }
+            let (info, tlen) = match rec.flag() & (BAM_FREAD1 | BAM_FREAD2) {
+                BAM_FREAD1 => (&info2, tlen1),
+                BAM_FREAD2 => (&info1, tlen2),
+                _ => continue,
+            };
+            info.apply(rec, self.add_mate_score)?;
+            rec.set_template_len(tlen);
+        }
+        Ok(true)
+    }
+}
+
+fn get_two_mut<T>(v: &mut [T], i: usize, j: usize) -> (&mut T, &mut T) {
+    assert_ne!(i, j);
+    if i < j {
+        let (s1, s2) = v.split_at_mut(j);
+        (&mut s1[i], &mut s2[0])
+    } else {
+        let (s1, s2) = v.split_at_mut(i);
+        (&mut s2[0], &mut s1[j])
+    }
+}
+
+fn check_pair(a: &BamRec, b: &BamRec) -> Result<(), SamError> {
+    let fa = a.flag();
+    let fb = b.flag();
+    if a.qname() != b.qname() {
+        Err(SamError::MateQnameMismatch)
+    } else if (fa | fb) & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) != 0
+        || fa & (BAM_FREAD1 | BAM_FREAD2) == fb & (BAM_FREAD1 | BAM_FREAD2)
+    {
+        Err(SamError::NotReadPair)
+    } else {
+        Ok(())
+    }
+}
+
+/// If `dest` is unmapped and `src` is mapped then set the position of `dest` to that of `src`
+fn sync_unmapped_pos(src: &BamRec, dest: &mut BamRec) {
+    if !dest.is_mapped() && src.is_mapped() {
+        dest.set_tid(src.tid());
+        dest.set_pos(src.pos());
+    }
+}
+
+/// Position of the 5' end of a mapped read
+#[inline]
+fn five_prime_pos(rec: &BamRec) -> HtsPos {
+    if rec.is_reversed() {
+        rec.endpos()
+    } else {
+        rec.raw_pos()
+    }
+}
+
+fn plausibly_properly_paired(a: &BamRec, b: &BamRec) -> bool {
+    if !(a.is_mapped() && b.is_mapped()) || a.tid() != b.tid() {
+        false
+    } else {
+        let (first, second) = if five_prime_pos(a) > five_prime_pos(b) {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        !first.is_reversed() && second.is_reversed()
+    }
+}
+
+/// Calculate the template length as defined in the SAM specification (leftmost mapped base to
+/// rightmost mapped base).  The leftmost record gets a positive value and the rightmost a
+/// negative value.  If both reads start at the same position, then the forward read (or read 1
+/// if both are on the same strand) gets the positive value.
+fn template_len(a: &BamRec, b: &BamRec) -> (HtsPos, HtsPos) {
+    match (a.pos(), b.pos()) {
+        (Some(pa), Some(pb)) if a.tid() == b.tid() => {
+            let end = a.endpos().max(b.endpos());
+            let tlen = end - pa.min(pb);
+            let a_first = match pa.cmp(&pb) {
+                std::cmp::Ordering::Less => true,
+                std::cmp::Ordering::Greater => false,
+                std::cmp::Ordering::Equal => match (a.is_reversed(), b.is_reversed()) {
+                    (false, true) => true,
+                    (true, false) => false,
+                    _ => a.flag() & BAM_FREAD1 != 0,
+                },
+            };
+            if a_first {
+                (tlen, -tlen)
+            } else {
+                (-tlen, tlen)
+            }
+        }
+        _ => (0, 0),
+    }
+}
+
+/// Sum of base qualities >= 15 (matches samtools calc_mate_score())
+pub(super) fn calc_mate_score(rec: &BamRec) -> i64 {
+    rec.qual_slice()
+        .iter()
+        .filter(|q| **q >= MATE_SCORE_MIN_QUAL && **q != 0xff)
+        .map(|q| *q as i64)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::{
+        BamAuxVal,
+        test_util::{make_hdr, parse_rec},
+    };
+
+    const HDR: &std::ffi::CStr = c"@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:100000";
+
+    #[test]
+    fn fix_pair() -> Result<(), SamError> {
+        let mut hdr = make_hdr(HDR)?;
+        let mut a = parse_rec(
+            &mut hdr,
+            b"rd1\t67\tchr1\t101\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+        )?;
+        let mut b = parse_rec(
+            &mut hdr,
+            b"rd1\t147\tchr1\t191\t30\t5S5M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+        )?;
+
+        let mut fm = FixMate::new();
+        fm.set_add_mate_score(true);
+        fm.fix_pair(&mut a, &mut b)?;
+
+        assert_eq!(a.mtid(), Some(0));
+        assert_eq!(a.mpos(), Some(190));
+        assert_eq!(b.mpos(), Some(100));
+        assert_ne!(a.flag() & BAM_FMREVERSE, 0);
+        assert_eq!(b.flag() & BAM_FMREVERSE, 0);
+        assert_eq!(a.template_len(), 95);
+        assert_eq!(b.template_len(), -95);
+        assert_ne!(a.flag() & BAM_FPROPER_PAIR, 0);
+
+        let mc = a.get_tag("MC")?.expect("Missing MC tag");
+        assert!(matches!(mc.get_val()?, BamAuxVal::String(s) if s == c"5S5M"));
+        let mq = b.get_tag("MQ")?.expect("Missing MQ tag");
+        assert!(matches!(mq.get_val()?, BamAuxVal::Int(60)));
+        let ms = a.get_tag("ms")?.expect("Missing ms tag");
+        assert!(matches!(ms.get_val()?, BamAuxVal::Int(400)));
+        Ok(())
+    }
+
+    #[test]
+    fn fix_unmapped_mate() -> Result<(), SamError> {
+        let mut hdr = make_hdr(HDR)?;
+        let mut a = parse_rec(
+            &mut hdr,
+            b"rd2\t65\tchr1\t101\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+        )?;
+        let mut b = parse_rec(
+            &mut hdr,
+            b"rd2\t133\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tMC:Z:10M",
+        )?;
+
+        FixMate::new().fix_pair(&mut a, &mut b)?;
+
+        assert_eq!(b.tid(), Some(0));
+        assert_eq!(b.mpos(), Some(100));
+        assert_ne!(a.flag() & BAM_FMUNMAP, 0);
+        assert_eq!(a.template_len(), 0);
+        assert!(a.get_tag("MC")?.is_none());
+        assert!(b.get_tag("MC")?.is_some());
+        Ok(())
+    }
+}
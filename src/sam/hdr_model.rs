@@ -0,0 +1,495 @@
+use std::{
+    ffi::CString,
+    fmt::{self, Formatter},
+    str::FromStr,
+};
+
+use crate::{SamError, sam::SamHdr};
+
+/// Tag/value pair for tags that do not have a dedicated field in a typed header line.
+/// These are kept (in input order) so that they are preserved when the header is written
+pub type OtherTags = Vec<(String, String)>;
+
+// Tag/value pairs from a header line being parsed
+pub(super) type TagList<'a> = Vec<(&'a str, &'a str)>;
+
+// Split a header line into its record type and tag/value pairs
+pub(super) fn split_line(line: &str) -> Result<(&str, TagList<'_>), SamError> {
+    let bad = || SamError::MalformedHeaderLine(line.to_owned());
+    let mut it = line.split('\t');
+    let typ = it
+        .next()
+        .and_then(|s| s.strip_prefix('@'))
+        .filter(|s| s.len() == 2)
+        .ok_or_else(bad)?;
+    let tags = it
+        .map(|s| match s.split_at_checked(2) {
+            Some((t, v)) if v.starts_with(':') => Ok((t, &v[1..])),
+            _ => Err(bad()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((typ, tags))
+}
+
+fn write_opt(f: &mut Formatter<'_>, tag: &str, v: &Option<String>) -> fmt::Result {
+    match v {
+        Some(s) => write!(f, "\t{tag}:{s}"),
+        None => Ok(()),
+    }
+}
+
+fn write_other(f: &mut Formatter<'_>, v: &OtherTags) -> fmt::Result {
+    for (t, s) in v {
+        write!(f, "\t{t}:{s}")?
+    }
+    Ok(())
+}
+
+fn required(v: Option<String>, tag: &str, line: &str) -> Result<String, SamError> {
+    v.ok_or_else(|| SamError::MissingHeaderTag(tag.to_owned(), line.to_owned()))
+}
+
+/// @HD line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HdLine {
+    /// Format version (VN)
+    pub version: String,
+    /// Sort order (SO)
+    pub sort_order: Option<String>,
+    /// Grouping of alignments (GO)
+    pub group_order: Option<String>,
+    /// Sub-sort order (SS)
+    pub sub_sort: Option<String>,
+    pub other: OtherTags,
+}
+
+impl HdLine {
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn from_tags(tags: TagList, line: &str) -> Result<Self, SamError> {
+        let mut version = None;
+        let mut h = Self::default();
+        for (t, v) in tags {
+            let v = v.to_owned();
+            match t {
+                "VN" => version = Some(v),
+                "SO" => h.sort_order = Some(v),
+                "GO" => h.group_order = Some(v),
+                "SS" => h.sub_sort = Some(v),
+                _ => h.other.push((t.to_owned(), v)),
+            }
+        }
+        h.version = required(version, "VN", line)?;
+        Ok(h)
+    }
+}
+
+impl fmt::Display for HdLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "@HD\tVN:{}", self.version)?;
+        write_opt(f, "SO", &self.sort_order)?;
+        write_opt(f, "GO", &self.group_order)?;
+        write_opt(f, "SS", &self.sub_sort)?;
+        write_other(f, &self.other)
+    }
+}
+
+/// @SQ line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SqLine {
+    /// Sequence name (SN)
+    pub name: String,
+    /// Sequence length (LN)
+    pub len: usize,
+    /// MD5 checksum of the sequence (M5)
+    pub md5: Option<String>,
+    /// Genome assembly identifier (AS)
+    pub assembly: Option<String>,
+    /// Species (SP)
+    pub species: Option<String>,
+    /// Alternative names for the sequence (AN)
+    pub alt_names: Vec<String>,
+    /// Alternate locus (AH)
+    pub alt_locus: Option<String>,
+    /// URI of the sequence (UR)
+    pub uri: Option<String>,
+    pub other: OtherTags,
+}
+
+impl SqLine {
+    pub fn new(name: &str, len: usize) -> Self {
+        Self {
+            name: name.to_owned(),
+            len,
+            ..Default::default()
+        }
+    }
+
+    fn from_tags(tags: TagList, line: &str) -> Result<Self, SamError> {
+        let mut name = None;
+        let mut len = None;
+        let mut s = Self::default();
+        for (t, v) in tags {
+            match t {
+                "SN" => name = Some(v.to_owned()),
+                "LN" => len = Some(v.to_owned()),
+                "M5" => s.md5 = Some(v.to_owned()),
+                "AS" => s.assembly = Some(v.to_owned()),
+                "SP" => s.species = Some(v.to_owned()),
+                "AN" => s.alt_names = v.split(',').map(|x| x.to_owned()).collect(),
+                "AH" => s.alt_locus = Some(v.to_owned()),
+                "UR" => s.uri = Some(v.to_owned()),
+                _ => s.other.push((t.to_owned(), v.to_owned())),
+            }
+        }
+        s.name = required(name, "SN", line)?;
+        s.len = required(len, "LN", line)?.parse()?;
+        Ok(s)
+    }
+}
+
+impl fmt::Display for SqLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "@SQ\tSN:{}\tLN:{}", self.name, self.len)?;
+        write_opt(f, "M5", &self.md5)?;
+        write_opt(f, "AS", &self.assembly)?;
+        write_opt(f, "SP", &self.species)?;
+        if !self.alt_names.is_empty() {
+            write!(f, "\tAN:{}", self.alt_names.join(","))?
+        }
+        write_opt(f, "AH", &self.alt_locus)?;
+        write_opt(f, "UR", &self.uri)?;
+        write_other(f, &self.other)
+    }
+}
+
+/// @RG line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RgLine {
+    /// Read group identifier (ID)
+    pub id: String,
+    /// Sample (SM)
+    pub sample: Option<String>,
+    /// Library (LB)
+    pub library: Option<String>,
+    /// Platform (PL)
+    pub platform: Option<String>,
+    /// Platform unit (PU)
+    pub platform_unit: Option<String>,
+    /// Sequencing center (CN)
+    pub center: Option<String>,
+    /// Description (DS)
+    pub description: Option<String>,
+    /// Date of the run (DT)
+    pub date: Option<String>,
+    pub other: OtherTags,
+}
+
+impl RgLine {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn from_tags(tags: TagList, line: &str) -> Result<Self, SamError> {
+        let mut id = None;
+        let mut r = Self::default();
+        for (t, v) in tags {
+            let v = v.to_owned();
+            match t {
+                "ID" => id = Some(v),
+                "SM" => r.sample = Some(v),
+                "LB" => r.library = Some(v),
+                "PL" => r.platform = Some(v),
+                "PU" => r.platform_unit = Some(v),
+                "CN" => r.center = Some(v),
+                "DS" => r.description = Some(v),
+                "DT" => r.date = Some(v),
+                _ => r.other.push((t.to_owned(), v)),
+            }
+        }
+        r.id = required(id, "ID", line)?;
+        Ok(r)
+    }
+}
+
+impl fmt::Display for RgLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "@RG\tID:{}", self.id)?;
+        write_opt(f, "SM", &self.sample)?;
+        write_opt(f, "LB", &self.library)?;
+        write_opt(f, "PL", &self.platform)?;
+        write_opt(f, "PU", &self.platform_unit)?;
+        write_opt(f, "CN", &self.center)?;
+        write_opt(f, "DS", &self.description)?;
+        write_opt(f, "DT", &self.date)?;
+        write_other(f, &self.other)
+    }
+}
+
+/// @PG line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PgLine {
+    /// Program record identifier (ID)
+    pub id: String,
+    /// Program name (PN)
+    pub name: Option<String>,
+    /// Command line (CL)
+    pub command_line: Option<String>,
+    /// ID of the previous PG line in the chain (PP)
+    pub prev_id: Option<String>,
+    /// Description (DS)
+    pub description: Option<String>,
+    /// Program version (VN)
+    pub version: Option<String>,
+    pub other: OtherTags,
+}
+
+impl PgLine {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn from_tags(tags: TagList, line: &str) -> Result<Self, SamError> {
+        let mut id = None;
+        let mut p = Self::default();
+        for (t, v) in tags {
+            let v = v.to_owned();
+            match t {
+                "ID" => id = Some(v),
+                "PN" => p.name = Some(v),
+                "CL" => p.command_line = Some(v),
+                "PP" => p.prev_id = Some(v),
+                "DS" => p.description = Some(v),
+                "VN" => p.version = Some(v),
+                _ => p.other.push((t.to_owned(), v)),
+            }
+        }
+        p.id = required(id, "ID", line)?;
+        Ok(p)
+    }
+}
+
+impl fmt::Display for PgLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "@PG\tID:{}", self.id)?;
+        write_opt(f, "PN", &self.name)?;
+        write_opt(f, "PP", &self.prev_id)?;
+        write_opt(f, "VN", &self.version)?;
+        write_opt(f, "DS", &self.description)?;
+        write_opt(f, "CL", &self.command_line)?;
+        write_other(f, &self.other)
+    }
+}
+
+/// Typed representation of a SAM header.
+///
+/// Unlike [SamHdr], which is a wrapper around the htslib header structure and is manipulated
+/// using tag strings, this is a plain Rust structure that can be edited directly and then
+/// converted back to a [SamHdr] for writing.  Lines are grouped by type, so on output the
+/// @HD line comes first followed by the @SQ, @RG and @PG lines, the comments, and finally any
+/// lines with other record types.  The order of lines of the same type is preserved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SamHeader {
+    pub hd: Option<HdLine>,
+    pub sq: Vec<SqLine>,
+    pub rg: Vec<RgLine>,
+    pub pg: Vec<PgLine>,
+    /// Text of @CO lines
+    pub comments: Vec<String>,
+    /// Lines with record types other than HD, SQ, RG, PG or CO (stored as the complete line)
+    pub other_lines: Vec<String>,
+}
+
+impl SamHeader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_sam_hdr(hdr: &SamHdr) -> Result<Self, SamError> {
+        match hdr.text() {
+            Some(s) => s.to_str()?.parse(),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Make a new [SamHdr] from the typed header
+    pub fn to_sam_hdr(&self) -> Result<SamHdr, SamError> {
+        let s = CString::new(self.to_string()).map_err(|_| SamError::IllegalHeaderChars)?;
+        if s.is_empty() {
+            SamHdr::try_init()
+        } else {
+            SamHdr::parse(&s)
+        }
+    }
+
+    pub fn sq_by_name(&self, name: &str) -> Option<&SqLine> {
+        self.sq.iter().find(|s| s.name == name)
+    }
+
+    pub fn sq_by_name_mut(&mut self, name: &str) -> Option<&mut SqLine> {
+        self.sq.iter_mut().find(|s| s.name == name)
+    }
+
+    pub fn rg_by_id(&self, id: &str) -> Option<&RgLine> {
+        self.rg.iter().find(|r| r.id == id)
+    }
+
+    pub fn rg_by_id_mut(&mut self, id: &str) -> Option<&mut RgLine> {
+        self.rg.iter_mut().find(|r| r.id == id)
+    }
+
+    pub fn pg_by_id(&self, id: &str) -> Option<&PgLine> {
+        self.pg.iter().find(|p| p.id == id)
+    }
+
+    pub fn pg_by_id_mut(&mut self, id: &str) -> Option<&mut PgLine> {
+        self.pg.iter_mut().find(|p| p.id == id)
+    }
+
+    fn add_line(&mut self, line: &str) -> Result<(), SamError> {
+        if let Some(s) = line.strip_prefix("@CO") {
+            self.comments
+                .push(s.strip_prefix('\t').unwrap_or(s).to_owned());
+            return Ok(());
+        }
+        let (typ, tags) = split_line(line)?;
+        match typ {
+            "HD" => self.hd = Some(HdLine::from_tags(tags, line)?),
+            "SQ" => self.sq.push(SqLine::from_tags(tags, line)?),
+            "RG" => self.rg.push(RgLine::from_tags(tags, line)?),
+            "PG" => self.pg.push(PgLine::from_tags(tags, line)?),
+            _ => self.other_lines.push(line.to_owned()),
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SamHeader {
+    type Err = SamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut h = Self::default();
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            h.add_line(line)?
+        }
+        Ok(h)
+    }
+}
+
+impl TryFrom<&SamHdr> for SamHeader {
+    type Error = SamError;
+
+    fn try_from(hdr: &SamHdr) -> Result<Self, Self::Error> {
+        Self::from_sam_hdr(hdr)
+    }
+}
+
+impl TryFrom<&SamHeader> for SamHdr {
+    type Error = SamError;
+
+    fn try_from(h: &SamHeader) -> Result<Self, Self::Error> {
+        h.to_sam_hdr()
+    }
+}
+
+impl fmt::Display for SamHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(h) = &self.hd {
+            writeln!(f, "{h}")?
+        }
+        for s in &self.sq {
+            writeln!(f, "{s}")?
+        }
+        for r in &self.rg {
+            writeln!(f, "{r}")?
+        }
+        for p in &self.pg {
+            writeln!(f, "{p}")?
+        }
+        for c in &self.comments {
+            writeln!(f, "@CO\t{c}")?
+        }
+        for l in &self.other_lines {
+            writeln!(f, "{l}")?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hts::{HtsFile, traits::IdMap};
+
+    #[test]
+    fn parse_and_write() -> Result<(), SamError> {
+        let txt = "@HD\tVN:1.6\tSO:coordinate\n\
+            @SQ\tSN:chr1\tLN:248956422\tM5:6aef897c3d6ff0c78aff06ac189178dd\tAS:GRCh38\n\
+            @SQ\tSN:chr1_KI270706v1_random\tLN:175055\tAN:KI270706.1,x1\tXX:foo\n\
+            @RG\tID:rg1\tSM:NA12878\tPL:ILLUMINA\n\
+            @PG\tID:bwa\tPN:bwa\tVN:0.7.17\tCL:bwa mem ref.fa r1.fq r2.fq\n\
+            @CO\tA comment\n";
+        let mut h: SamHeader = txt.parse()?;
+        assert_eq!(
+            h.hd.as_ref().and_then(|h| h.sort_order.as_deref()),
+            Some("coordinate")
+        );
+        assert_eq!(h.sq.len(), 2);
+        let sq = h.sq_by_name("chr1_KI270706v1_random").unwrap();
+        assert_eq!(sq.len, 175055);
+        assert_eq!(sq.alt_names, ["KI270706.1", "x1"]);
+        assert_eq!(sq.other, [("XX".to_owned(), "foo".to_owned())]);
+        assert_eq!(
+            h.rg_by_id("rg1").and_then(|r| r.sample.as_deref()),
+            Some("NA12878")
+        );
+        assert_eq!(h.comments, ["A comment"]);
+
+        h.sq_by_name_mut("chr1").unwrap().species = Some("Homo sapiens".to_owned());
+        h.pg.push(PgLine {
+            prev_id: Some("bwa".to_owned()),
+            ..PgLine::new("samtools")
+        });
+        let hdr = h.to_sam_hdr()?;
+        assert_eq!(hdr.num_seqs(), 2);
+        assert_eq!(hdr.seq_len(1), Some(175055));
+        let h1 = SamHeader::from_sam_hdr(&hdr)?;
+        assert_eq!(h, h1);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), SamError> {
+        let mut f = HtsFile::open(c"test/test_input_1_a.cram", c"r")
+            .expect("Failed to read test/test_input_1_a.cram");
+        let hdr = SamHdr::read(&mut f)?;
+        let h = SamHeader::try_from(&hdr)?;
+        assert_eq!(h.sq.len(), hdr.num_seqs());
+        let hdr1 = SamHdr::try_from(&h)?;
+        assert_eq!(SamHeader::try_from(&hdr1)?, h);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            "@SQ\tSN:chr1".parse::<SamHeader>(),
+            Err(SamError::MissingHeaderTag(t, _)) if t == "LN"
+        ));
+        assert!(matches!(
+            "@SQ\tSN:chr1\tLN".parse::<SamHeader>(),
+            Err(SamError::MalformedHeaderLine(_))
+        ));
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, mem,
+    sync::LazyLock,
+};
+
+use regex::Regex;
+
+use crate::sam::{SamHdr, SamHeader, hdr_model::split_line};
+
+static RE_VERSION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[0-9]+\.[0-9]+$").unwrap());
+
+static RE_SUB_SORT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(coordinate|queryname|unsorted)(:[A-Za-z0-9_-]+)+$").unwrap());
+
+/// Reference sequence names from the SAM spec (section 1.2.1)
+static RE_SEQ_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[0-9A-Za-z!#$%&+./:;?@^_|~-][0-9A-Za-z!#$%&*+./:;=?@^_|~-]*$").unwrap()
+});
+
+const SORT_ORDERS: [&str; 4] = ["unknown", "unsorted", "queryname", "coordinate"];
+const GROUP_ORDERS: [&str; 3] = ["none", "query", "reference"];
+const MAX_SEQ_LEN: u64 = (1 << 31) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HdrSeverity {
+    Warning,
+    Error,
+}
+
+/// Problems found by [validate_header_text]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HdrIssue {
+    /// Line could not be split into record type and tag:value fields
+    MalformedLine,
+    /// @HD line is not the first line of the header
+    HdNotFirst,
+    MultipleHd,
+    InvalidVersion(String),
+    InvalidSortOrder(String),
+    InvalidGroupOrder(String),
+    InvalidSubSort(String),
+    /// Sort order in SS does not match SO (SS, SO)
+    SubSortMismatch(String, String),
+    /// Required tag missing (record type, tag)
+    MissingTag(String, String),
+    /// Tag occurs more than once on the same line
+    DuplicateTag(String),
+    InvalidSeqName(String),
+    /// LN value not an integer in the range 1..=2^31-1
+    InvalidSeqLen(String),
+    DuplicateSeqName(String),
+    /// AN name clashes with a sequence name or another alternative name
+    AltNameClash(String),
+    DuplicateRgId(String),
+    DuplicatePgId(String),
+    /// PP tag refers to a PG ID that is not present
+    MissingPpRef(String),
+}
+
+impl HdrIssue {
+    pub fn severity(&self) -> HdrSeverity {
+        match self {
+            Self::SubSortMismatch(..) | Self::DuplicateTag(_) => HdrSeverity::Warning,
+            _ => HdrSeverity::Error,
+        }
+    }
+}
+
+impl fmt::Display for HdrIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedLine => write!(f, "malformed header line"),
+            Self::HdNotFirst => write!(f, "@HD line is not the first line"),
+            Self::MultipleHd => write!(f, "multiple @HD lines"),
+            Self::InvalidVersion(s) => write!(f, "invalid VN value '{s}'"),
+            Self::InvalidSortOrder(s) => write!(f, "invalid SO value '{s}'"),
+            Self::InvalidGroupOrder(s) => write!(f, "invalid GO value '{s}'"),
+            Self::InvalidSubSort(s) => write!(f, "invalid SS value '{s}'"),
+            Self::SubSortMismatch(ss, so) => {
+                write!(f, "SS value '{ss}' does not match SO value '{so}'")
+            }
+            Self::MissingTag(t, k) => write!(f, "@{t} line missing required {k} tag"),
+            Self::DuplicateTag(k) => write!(f, "tag {k} occurs more than once"),
+            Self::InvalidSeqName(s) => write!(f, "invalid reference sequence name '{s}'"),
+            Self::InvalidSeqLen(s) => write!(f, "invalid LN value '{s}'"),
+            Self::DuplicateSeqName(s) => write!(f, "duplicate @SQ SN value '{s}'"),
+            Self::AltNameClash(s) => write!(f, "AN name '{s}' clashes with another sequence name"),
+            Self::DuplicateRgId(s) => write!(f, "duplicate @RG ID value '{s}'"),
+            Self::DuplicatePgId(s) => write!(f, "duplicate @PG ID value '{s}'"),
+            Self::MissingPpRef(s) => write!(f, "PP tag refers to missing @PG ID '{s}'"),
+        }
+    }
+}
+
+/// An issue found by [validate_header_text], with the (1 based) line number in the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HdrDiagnostic {
+    pub line: usize,
+    pub issue: HdrIssue,
+}
+
+impl HdrDiagnostic {
+    #[inline]
+    pub fn severity(&self) -> HdrSeverity {
+        self.issue.severity()
+    }
+
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.severity() == HdrSeverity::Error
+    }
+}
+
+impl fmt::Display for HdrDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self.severity() {
+            HdrSeverity::Warning => "Warning",
+            HdrSeverity::Error => "Error",
+        };
+        write!(f, "{s} (header line {}): {}", self.line, self.issue)
+    }
+}
+
+#[derive(Default)]
+struct Validator {
+    diags: Vec<HdrDiagnostic>,
+    seq_names: HashSet<String>,
+    // Alternative names with the line number where they occur
+    alt_names: Vec<(String, usize)>,
+    rg_ids: HashSet<String>,
+    pg_ids: HashSet<String>,
+    // PP values with the line number where they occur
+    pp_refs: Vec<(String, usize)>,
+    have_hd: bool,
+}
+
+impl Validator {
+    fn add(&mut self, line: usize, issue: HdrIssue) {
+        self.diags.push(HdrDiagnostic { line, issue })
+    }
+
+    fn line(&mut self, ln: usize, s: &str) {
+        if s.starts_with("@CO") {
+            return;
+        }
+        let Ok((typ, tags)) = split_line(s) else {
+            self.add(ln, HdrIssue::MalformedLine);
+            return;
+        };
+        let mut tag_map = HashMap::with_capacity(tags.len());
+        for (k, v) in tags {
+            if tag_map.insert(k, v).is_some() {
+                self.add(ln, HdrIssue::DuplicateTag(k.to_owned()))
+            }
+        }
+        let mut req = |k: &str| {
+            let v = tag_map.get(k).copied();
+            if v.is_none() {
+                self.add(ln, HdrIssue::MissingTag(typ.to_owned(), k.to_owned()))
+            }
+            v
+        };
+        match typ {
+            "HD" => {
+                let vn = req("VN");
+                self.hd(ln, vn, &tag_map)
+            }
+            "SQ" => {
+                let (sn, len) = (req("SN"), req("LN"));
+                self.sq(ln, sn, len, tag_map.get("AN").copied())
+            }
+            "RG" => {
+                if let Some(id) = req("ID")
+                    && !self.rg_ids.insert(id.to_owned())
+                {
+                    self.add(ln, HdrIssue::DuplicateRgId(id.to_owned()))
+                }
+            }
+            "PG" => {
+                if let Some(id) = req("ID")
+                    && !self.pg_ids.insert(id.to_owned())
+                {
+                    self.add(ln, HdrIssue::DuplicatePgId(id.to_owned()))
+                }
+                if let Some(pp) = tag_map.get("PP") {
+                    self.pp_refs.push((pp.to_string(), ln))
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn hd(&mut self, ln: usize, vn: Option<&str>, tags: &HashMap<&str, &str>) {
+        if self.have_hd {
+            self.add(ln, HdrIssue::MultipleHd)
+        }
+        self.have_hd = true;
+        if ln != 1 {
+            self.add(ln, HdrIssue::HdNotFirst)
+        }
+        if let Some(v) = vn
+            && !RE_VERSION.is_match(v)
+        {
+            self.add(ln, HdrIssue::InvalidVersion(v.to_owned()))
+        }
+        let so = tags.get("SO").copied();
+        if let Some(v) = so
+            && !SORT_ORDERS.contains(&v)
+        {
+            self.add(ln, HdrIssue::InvalidSortOrder(v.to_owned()))
+        }
+        if let Some(v) = tags.get("GO")
+            && !GROUP_ORDERS.contains(v)
+        {
+            self.add(ln, HdrIssue::InvalidGroupOrder(v.to_string()))
+        }
+        if let Some(v) = tags.get("SS") {
+            if !RE_SUB_SORT.is_match(v) {
+                self.add(ln, HdrIssue::InvalidSubSort(v.to_string()))
+            } else if let Some(so) = so
+                && !v.starts_with(so)
+            {
+                self.add(ln, HdrIssue::SubSortMismatch(v.to_string(), so.to_owned()))
+            }
+        }
+    }
+
+    fn sq(&mut self, ln: usize, sn: Option<&str>, len: Option<&str>, an: Option<&str>) {
+        if let Some(sn) = sn {
+            if !RE_SEQ_NAME.is_match(sn) {
+                self.add(ln, HdrIssue::InvalidSeqName(sn.to_owned()))
+            }
+            if !self.seq_names.insert(sn.to_owned()) {
+                self.add(ln, HdrIssue::DuplicateSeqName(sn.to_owned()))
+            }
+        }
+        if let Some(l) = len
+            && !matches!(l.parse::<u64>(), Ok(x) if (1..=MAX_SEQ_LEN).contains(&x))
+        {
+            self.add(ln, HdrIssue::InvalidSeqLen(l.to_owned()))
+        }
+        for a in an.into_iter().flat_map(|s| s.split(',')) {
+            if !RE_SEQ_NAME.is_match(a) {
+                self.add(ln, HdrIssue::InvalidSeqName(a.to_owned()))
+            }
+            self.alt_names.push((a.to_owned(), ln))
+        }
+    }
+
+    // Checks that can only be done once all lines have been seen
+    fn finish(mut self) -> Vec<HdrDiagnostic> {
+        let mut seen = HashSet::new();
+        for (a, ln) in mem::take(&mut self.alt_names) {
+            if self.seq_names.contains(&a) || !seen.insert(a.clone()) {
+                self.add(ln, HdrIssue::AltNameClash(a))
+            }
+        }
+        for (pp, ln) in mem::take(&mut self.pp_refs) {
+            if !self.pg_ids.contains(&pp) {
+                self.add(ln, HdrIssue::MissingPpRef(pp))
+            }
+        }
+        self.diags.sort_by_key(|d| d.line);
+        self.diags
+    }
+}
+
+/// Check SAM header text against the SAM specification, returning a list of the issues
+/// found, ordered by line number.  An empty list means that no issues were found.
+///
+/// The following are checked: the @HD VN, SO, GO and SS values; the presence of required
+/// tags for each line type; duplicate @SQ SN, @RG ID and @PG ID values; PP tags referring to
+/// missing @PG IDs; @SQ LN ranges; and AN names clashing with other sequence names
+pub fn validate_header_text(s: &str) -> Vec<HdrDiagnostic> {
+    let mut v = Validator::default();
+    for (i, l) in s.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+        v.line(i + 1, l)
+    }
+    v.finish()
+}
+
+impl SamHdr {
+    /// Check header against the SAM specification (see [validate_header_text])
+    pub fn validate(&self) -> Vec<HdrDiagnostic> {
+        match self.text().map(|s| s.to_string_lossy()) {
+            Some(s) => validate_header_text(&s),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl SamHeader {
+    /// Check header against the SAM specification (see [validate_header_text]).  Line
+    /// numbers refer to the header as output by [SamHeader::to_string]
+    pub fn validate(&self) -> Vec<HdrDiagnostic> {
+        validate_header_text(&self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::{PgLine, SqLine};
+
+    #[test]
+    fn valid() {
+        let d = validate_header_text(
+            "@HD\tVN:1.6\tSO:coordinate\tSS:coordinate:batch\n\
+             @SQ\tSN:chr1\tLN:1000\tAN:1\n\
+             @RG\tID:rg1\tSM:x\n\
+             @PG\tID:bwa\n\
+             @PG\tID:samtools\tPP:bwa\n\
+             @CO\tanything goes here\n",
+        );
+        assert!(d.is_empty(), "{d:?}");
+    }
+
+    #[test]
+    fn invalid() {
+        let d = validate_header_text(
+            "@SQ\tSN:chr1\tLN:0\n\
+             @HD\tVN:1\tSO:sorted\tGO:reference\n\
+             @SQ\tSN:chr2\tLN:100\tAN:chr1,2\n\
+             @SQ\tSN:chr2\tLN:3000000000\n\
+             @SQ\tLN:100\n\
+             @RG\tID:a\n\
+             @RG\tID:a\n\
+             @PG\tID:p\tPP:q\n\
+             @SQ\tSN\n",
+        );
+        let issues: Vec<_> = d.iter().map(|x| (x.line, x.issue.clone())).collect();
+        let s = |x: &str| x.to_owned();
+        assert_eq!(
+            issues,
+            [
+                (1, HdrIssue::InvalidSeqLen(s("0"))),
+                (2, HdrIssue::HdNotFirst),
+                (2, HdrIssue::InvalidVersion(s("1"))),
+                (2, HdrIssue::InvalidSortOrder(s("sorted"))),
+                (3, HdrIssue::AltNameClash(s("chr1"))),
+                (4, HdrIssue::DuplicateSeqName(s("chr2"))),
+                (4, HdrIssue::InvalidSeqLen(s("3000000000"))),
+                (5, HdrIssue::MissingTag(s("SQ"), s("SN"))),
+                (7, HdrIssue::DuplicateRgId(s("a"))),
+                (8, HdrIssue::MissingPpRef(s("q"))),
+                (9, HdrIssue::MalformedLine),
+            ]
+        );
+        assert!(d.iter().all(|x| x.is_error()));
+    }
+
+    #[test]
+    fn typed_header() {
+        let mut h = SamHeader::new();
+        h.sq.push(SqLine::new("chr1", 100));
+        h.sq.push(SqLine::new("chr1", 200));
+        h.pg.push(PgLine {
+            prev_id: Some("bwa".to_owned()),
+            ..PgLine::new("samtools")
+        });
+        let d = h.validate();
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].issue, HdrIssue::DuplicateSeqName("chr1".to_owned()));
+        assert_eq!(d[1].issue, HdrIssue::MissingPpRef("bwa".to_owned()));
+    }
+}
@@ -0,0 +1,794 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    io::{self, Write},
+};
+
+use crate::{
+    SamError,
+    hts::{
+        HtsPos,
+        traits::{ReadRec, WriteRec},
+    },
+    sam::{
+        BamAuxVal, BamRec, Cigar, CigarBuf, CigarOp, SamHdr,
+        fixmate::calc_mate_score,
+        record::bam1::{
+            BAM_FDUP, BAM_FMREVERSE, BAM_FMUNMAP, BAM_FPAIRED, BAM_FREAD1, BAM_FSECONDARY,
+            BAM_FSUPPLEMENTARY,
+        },
+    },
+};
+
+/// Library name used for reads without a read group or whose read group has no LB tag
+pub const UNKNOWN_LIBRARY: &str = "Unknown Library";
+
+/// Duplication metrics for a single library, following the definitions used by Picard
+/// MarkDuplicates
+#[derive(Debug, Default, Clone)]
+pub struct DupMetrics {
+    pub library: String,
+    pub unpaired_reads_examined: u64,
+    pub read_pairs_examined: u64,
+    pub secondary_or_supplementary_reads: u64,
+    pub unmapped_reads: u64,
+    pub unpaired_read_duplicates: u64,
+    pub read_pair_duplicates: u64,
+    pub read_pair_optical_duplicates: u64,
+}
+
+impl DupMetrics {
+    const HEADER: &'static str = "LIBRARY\tUNPAIRED_READS_EXAMINED\tREAD_PAIRS_EXAMINED\t\
+        SECONDARY_OR_SUPPLEMENTARY_RDS\tUNMAPPED_READS\tUNPAIRED_READ_DUPLICATES\t\
+        READ_PAIR_DUPLICATES\tREAD_PAIR_OPTICAL_DUPLICATES\tPERCENT_DUPLICATION\t\
+        ESTIMATED_LIBRARY_SIZE";
+
+    fn new(library: &str) -> Self {
+        Self {
+            library: library.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Fraction of examined mapped reads that are duplicates
+    pub fn percent_duplication(&self) -> f64 {
+        let examined = self.unpaired_reads_examined + 2 * self.read_pairs_examined;
+        if examined == 0 {
+            0.0
+        } else {
+            (self.unpaired_read_duplicates + 2 * self.read_pair_duplicates) as f64 / examined as f64
+        }
+    }
+
+    /// Estimate the number of unique molecules in the library from the read pair counts using
+    /// the Lander-Waterman equation (as Picard).  Returns None if there are no read pair
+    /// duplicates or the estimate does not converge.
+    pub fn estimated_library_size(&self) -> Option<u64> {
+        let n = self
+            .read_pairs_examined
+            .saturating_sub(self.read_pair_optical_duplicates) as f64;
+        let c = self
+            .read_pairs_examined
+            .saturating_sub(self.read_pair_duplicates) as f64;
+
+        if n <= 0.0 || c >= n {
+            return None;
+        }
+        let f = |x: f64| c / x - 1.0 + (-n / x).exp();
+        let mut lo = 1.0;
+        let mut hi = 100.0;
+        if f(lo * c) < 0.0 {
+            return None;
+        }
+        while f(hi * c) > 0.0 {
+            hi *= 10.0
+        }
+        for _ in 0..40 {
+            let r = (lo + hi) / 2.0;
+            let u = f(r * c);
+            if u == 0.0 {
+                break;
+            } else if u > 0.0 {
+                lo = r
+            } else {
+                hi = r
+            }
+        }
+        Some((c * (lo + hi) / 2.0) as u64)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.unpaired_reads_examined
+            + self.read_pairs_examined
+            + self.secondary_or_supplementary_reads
+            + self.unmapped_reads
+            == 0
+    }
+}
+
+impl fmt::Display for DupMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t",
+            self.library,
+            self.unpaired_reads_examined,
+            self.read_pairs_examined,
+            self.secondary_or_supplementary_reads,
+            self.unmapped_reads,
+            self.unpaired_read_duplicates,
+            self.read_pair_duplicates,
+            self.read_pair_optical_duplicates,
+            self.percent_duplication()
+        )?;
+        if let Some(x) = self.estimated_library_size() {
+            write!(f, "{x}")?
+        }
+        Ok(())
+    }
+}
+
+/// One end of a fragment: contig, unclipped 5' position and strand
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+struct End {
+    tid: usize,
+    pos: HtsPos,
+    rev: bool,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct FragKey {
+    lib: usize,
+    end: End,
+    umi: Option<Box<[u8]>>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct PairKey {
+    lib: usize,
+    ends: [End; 2],
+    umi: Option<Box<[u8]>>,
+}
+
+#[derive(Debug, Clone)]
+enum GroupKey {
+    Frag(FragKey),
+    Pair(PairKey),
+}
+
+/// Tile and x, y coordinates parsed from an Illumina style read name
+#[derive(Debug)]
+struct OpticalLoc {
+    tile: Box<[u8]>,
+    x: i64,
+    y: i64,
+}
+
+#[derive(Debug)]
+struct Member {
+    idx: u64,
+    score: i64,
+    loc: Option<OpticalLoc>,
+}
+
+#[derive(Debug, Default)]
+struct FragGroup {
+    has_pair: bool,
+    members: Vec<Member>,
+}
+
+#[derive(Debug)]
+struct PairGroup {
+    fin_pos: HtsPos,
+    members: Vec<Member>,
+}
+
+#[derive(Debug)]
+enum Template {
+    // Records (mates, unmapped reads) waiting for the decision on the template
+    Pending(Vec<u64>),
+    Decided(bool),
+}
+
+#[derive(Debug)]
+struct Entry {
+    rec: BamRec,
+    dup: Option<bool>,
+}
+
+/// Duplicate marking for coordinate sorted input
+///
+/// Records are added in coordinate order using [MarkDup::push] and retrieved, with the
+/// `BAM_FDUP` flag set or cleared, using [MarkDup::pop].  Records are buffered until all
+/// potential duplicates have been seen, so a call to `pop` will often return `None` even though
+/// records have been pushed.  At the end of the input [MarkDup::finish] should be called, after
+/// which all remaining records can be retrieved with `pop`.  [MarkDup::mark_dups] performs all
+/// of these steps from a reader to a writer.
+///
+/// Reads are grouped on library (from the LB tag of the `@RG` header line matching the RG tag
+/// of the record), contig, unclipped 5' position and strand, and optionally on a UMI tag (i.e.,
+/// RX or MI).  For pairs where both reads are mapped, the positions and strands of both reads
+/// are used; the mate cigar (MC tag) is required for this so the input should have been
+/// processed by [FixMate](crate::sam::FixMate) or `samtools fixmate`.  The record with the
+/// highest score (sum of base qualities >= 15, plus the ms tag for pairs) in each group is kept
+/// and the rest are marked as duplicates.  Single reads (or reads with an unmapped mate) are
+/// marked as duplicates if they coincide with one end of a pair.
+///
+/// Secondary, supplementary and unmapped reads (without a mapped mate) are never marked as
+/// duplicates.
+#[derive(Debug)]
+pub struct MarkDup {
+    remove_dups: bool,
+    optical_distance: Option<u32>,
+    umi_tag: Option<String>,
+
+    rg_lib: HashMap<Box<[u8]>, usize>,
+    metrics: Vec<DupMetrics>,
+
+    buf: VecDeque<Entry>,
+    base: u64,
+    last: Option<(usize, HtsPos)>,
+    window: HtsPos,
+
+    frag_groups: HashMap<FragKey, FragGroup>,
+    pair_groups: HashMap<PairKey, PairGroup>,
+    pending: BTreeMap<(usize, HtsPos), Vec<GroupKey>>,
+    templates: HashMap<Box<[u8]>, Template>,
+    cigar_buf: CigarBuf,
+}
+
+impl MarkDup {
+    /// Create a new duplicate marker, taking the read group to library mapping from `hdr`
+    pub fn new(hdr: &SamHdr) -> Self {
+        let mut metrics = vec![DupMetrics::new(UNKNOWN_LIBRARY)];
+        let mut lib_ix: HashMap<String, usize> = HashMap::new();
+        let mut rg_lib = HashMap::new();
+
+        for i in 0..hdr.count_lines(c"RG").unwrap_or(0) {
+            let Some(id) = hdr.find_tag_pos(c"RG", i, c"ID") else {
+                continue;
+            };
+            let ix = match hdr.find_tag_pos(c"RG", i, c"LB") {
+                Some(lb) => {
+                    let lb = lb.as_cstr().to_string_lossy().into_owned();
+                    *lib_ix.entry(lb).or_insert_with_key(|lb| {
+                        metrics.push(DupMetrics::new(lb));
+                        metrics.len() - 1
+                    })
+                }
+                None => 0,
+            };
+            rg_lib.insert(id.as_slice().into(), ix);
+        }
+
+        Self {
+            remove_dups: false,
+            optical_distance: None,
+            umi_tag: None,
+            rg_lib,
+            metrics,
+            buf: VecDeque::new(),
+            base: 0,
+            last: None,
+            window: 0,
+            frag_groups: HashMap::new(),
+            pair_groups: HashMap::new(),
+            pending: BTreeMap::new(),
+            templates: HashMap::new(),
+            cigar_buf: CigarBuf::new(),
+        }
+    }
+
+    /// If set, duplicate records are removed from the output rather than being flagged
+    pub fn set_remove_dups(&mut self, x: bool) -> &mut Self {
+        self.remove_dups = x;
+        self
+    }
+
+    /// Set the maximum distance (in pixels) between duplicate clusters on the same tile for
+    /// them to be counted as optical duplicates.  If `None` (the default) optical duplicates
+    /// are not identified
+    pub fn set_optical_distance(&mut self, d: Option<u32>) -> &mut Self {
+        self.optical_distance = d;
+        self
+    }
+
+    /// Use the string tag `tag` (typically RX or MI) as a UMI when grouping reads
+    pub fn set_umi_tag(&mut self, tag: Option<&str>) -> Result<&mut Self, SamError> {
+        if tag.is_some_and(|t| t.len() != 2) {
+            return Err(SamError::IllegalTagLength);
+        }
+        self.umi_tag = tag.map(|s| s.to_owned());
+        Ok(self)
+    }
+
+    /// Duplication metrics, one entry per library.  Libraries with no reads are omitted
+    pub fn metrics(&self) -> impl Iterator<Item = &DupMetrics> {
+        self.metrics.iter().filter(|m| !m.is_empty())
+    }
+
+    /// Write the duplication metrics as a tab separated table
+    pub fn write_metrics<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{}", DupMetrics::HEADER)?;
+        for m in self.metrics() {
+            writeln!(w, "{m}")?
+        }
+        Ok(())
+    }
+
+    /// Read all records from `rd`, writing them to `wr` after duplicate marking
+    pub fn mark_dups<R, W>(&mut self, rd: &mut R, wr: &mut W) -> Result<(), SamError>
+    where
+        R: ReadRec<Rec = BamRec, Err = SamError>,
+        W: WriteRec<Rec = BamRec, Err = SamError>,
+    {
+        let mut rec = BamRec::new();
+        while rd.read_rec(&mut rec)?.is_some() {
+            self.push(std::mem::take(&mut rec))?;
+            while let Some(mut r) = self.pop() {
+                wr.write_rec(&mut r)?;
+            }
+        }
+        self.finish()?;
+        while let Some(mut r) = self.pop() {
+            wr.write_rec(&mut r)?;
+        }
+        Ok(())
+    }
+
+    /// Add a record.  Records must be added in coordinate order
+    pub fn push(&mut self, mut rec: BamRec) -> Result<(), SamError> {
+        let flag = rec.flag() & !BAM_FDUP;
+        rec.set_flag(flag);
+
+        let cur = (rec.tid().unwrap_or(usize::MAX), rec.raw_pos());
+        if self.last.is_some_and(|l| cur < l) {
+            return Err(SamError::NotCoordinateSorted);
+        }
+        self.last = Some(cur);
+        self.finalize_groups(cur)?;
+
+        let lib = self.library(&rec)?;
+        let idx = self.base + self.buf.len() as u64;
+        let paired = flag & BAM_FPAIRED != 0;
+        let mate_mapped = paired && flag & BAM_FMUNMAP == 0;
+
+        let dup = if flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) != 0 {
+            self.metrics[lib].secondary_or_supplementary_reads += 1;
+            Some(false)
+        } else if !rec.is_mapped() {
+            self.metrics[lib].unmapped_reads += 1;
+            if mate_mapped && rec.tid().is_some() {
+                self.add_follower(&rec, idx)
+            } else {
+                Some(false)
+            }
+        } else {
+            if let Some(c) = rec.cigar() {
+                self.window = self
+                    .window
+                    .max(c.query_len_including_hard_clips() as HtsPos)
+            }
+            self.window = self.window.max(rec.seq_len() as HtsPos);
+
+            let umi = self.get_umi(&rec)?;
+            let end = five_prime_end(&rec, rec.cigar());
+            if mate_mapped {
+                self.register_pair_end(lib, end, umi.clone());
+                if is_driver(&rec) {
+                    self.metrics[lib].read_pairs_examined += 1;
+                    self.add_pair(&rec, idx, lib, end, umi)?;
+                    self.templates
+                        .entry(qname_key(&rec))
+                        .or_insert(Template::Pending(Vec::new()));
+                    None
+                } else {
+                    self.add_follower(&rec, idx)
+                }
+            } else {
+                self.metrics[lib].unpaired_reads_examined += 1;
+                self.add_frag(&rec, idx, lib, end, umi);
+                if paired {
+                    self.templates
+                        .entry(qname_key(&rec))
+                        .or_insert(Template::Pending(Vec::new()));
+                }
+                None
+            }
+        };
+
+        if dup == Some(true) {
+            rec.set_flag(flag | BAM_FDUP)
+        }
+        self.buf.push_back(Entry { rec, dup });
+        Ok(())
+    }
+
+    /// Get the next record that is ready for output, or None if no records are ready
+    pub fn pop(&mut self) -> Option<BamRec> {
+        while self.buf.front().is_some_and(|e| e.dup.is_some()) {
+            let e = self.buf.pop_front().unwrap();
+            self.base += 1;
+            if !(self.remove_dups && e.dup == Some(true)) {
+                return Some(e.rec);
+            }
+        }
+        None
+    }
+
+    /// Signal the end of input.  All remaining groups are resolved and all buffered records
+    /// become available from [MarkDup::pop]
+    pub fn finish(&mut self) -> Result<(), SamError> {
+        self.finalize_groups((usize::MAX, HtsPos::MAX))?;
+        for e in self.buf.iter_mut().filter(|e| e.dup.is_none()) {
+            e.dup = Some(false)
+        }
+        self.templates.clear();
+        self.last = None;
+        Ok(())
+    }
+
+    fn library(&self, rec: &BamRec) -> Result<usize, SamError> {
+        Ok(match rec.get_tag("RG")? {
+            Some(tag) => match tag.get_val()? {
+                BamAuxVal::String(s) => self.rg_lib.get(s.to_bytes()).copied().unwrap_or(0),
+                _ => 0,
+            },
+            None => 0,
+        })
+    }
+
+    fn get_umi(&self, rec: &BamRec) -> Result<Option<Box<[u8]>>, SamError> {
+        if let Some(t) = self.umi_tag.as_deref()
+            && let Some(tag) = rec.get_tag(t)?
+            && let BamAuxVal::String(s) = tag.get_val()?
+        {
+            return Ok(Some(s.to_bytes().into()));
+        }
+        Ok(None)
+    }
+
+    fn add_follower(&mut self, rec: &BamRec, idx: u64) -> Option<bool> {
+        let key = qname_key(rec);
+        match self.templates.get_mut(&key) {
+            Some(Template::Decided(dup)) => {
+                let dup = *dup;
+                self.templates.remove(&key);
+                Some(dup)
+            }
+            Some(Template::Pending(v)) => {
+                v.push(idx);
+                None
+            }
+            None => {
+                // If the mate is before this record in the input, it should already have been
+                // seen so it must be missing
+                let mate = (rec.mtid().unwrap_or(usize::MAX), rec.mpos().unwrap_or(-1));
+                if mate < (rec.tid().unwrap_or(usize::MAX), rec.raw_pos()) {
+                    Some(false)
+                } else {
+                    self.templates.insert(key, Template::Pending(vec![idx]));
+                    None
+                }
+            }
+        }
+    }
+
+    fn register_pair_end(&mut self, lib: usize, end: End, umi: Option<Box<[u8]>>) {
+        let key = FragKey { lib, end, umi };
+        self.frag_group(key).has_pair = true;
+    }
+
+    fn frag_group(&mut self, key: FragKey) -> &mut FragGroup {
+        if !self.frag_groups.contains_key(&key) {
+            self.pending
+                .entry((key.end.tid, key.end.pos))
+                .or_default()
+                .push(GroupKey::Frag(key.clone()));
+        }
+        self.frag_groups.entry(key).or_default()
+    }
+
+    fn add_frag(&mut self, rec: &BamRec, idx: u64, lib: usize, end: End, umi: Option<Box<[u8]>>) {
+        let m = self.make_member(rec, idx, 0);
+        self.frag_group(FragKey { lib, end, umi }).members.push(m)
+    }
+
+    fn add_pair(
+        &mut self,
+        rec: &BamRec,
+        idx: u64,
+        lib: usize,
+        end: End,
+        umi: Option<Box<[u8]>>,
+    ) -> Result<(), SamError> {
+        let mc = match rec.get_tag("MC")? {
+            Some(tag) => match tag.get_val()? {
+                BamAuxVal::String(s) => s,
+                _ => return Err(SamError::MissingMateCigar),
+            },
+            None => return Err(SamError::MissingMateCigar),
+        };
+        self.cigar_buf.parse(mc.to_bytes())?;
+        let mate_end = End {
+            tid: rec.mtid().unwrap_or(usize::MAX),
+            ..five_prime(
+                rec.mpos().unwrap_or(0),
+                Some(&self.cigar_buf),
+                rec.flag() & BAM_FMREVERSE != 0,
+            )
+        };
+        let mate_score = match rec.get_tag("ms")? {
+            Some(tag) => match tag.get_val()? {
+                BamAuxVal::Int(x) => x,
+                _ => 0,
+            },
+            None => 0,
+        };
+        let m = self.make_member(rec, idx, mate_score);
+        let ends = if end <= mate_end {
+            [end, mate_end]
+        } else {
+            [mate_end, end]
+        };
+        let key = PairKey { lib, ends, umi };
+        let fin = (end.tid, end.pos);
+        match self.pair_groups.get_mut(&key) {
+            Some(g) => {
+                g.members.push(m);
+                if fin.1 > g.fin_pos {
+                    g.fin_pos = fin.1;
+                    self.pending
+                        .entry(fin)
+                        .or_default()
+                        .push(GroupKey::Pair(key));
+                }
+            }
+            None => {
+                self.pending
+                    .entry(fin)
+                    .or_default()
+                    .push(GroupKey::Pair(key.clone()));
+                self.pair_groups.insert(
+                    key,
+                    PairGroup {
+                        fin_pos: fin.1,
+                        members: vec![m],
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn make_member(&self, rec: &BamRec, idx: u64, extra_score: i64) -> Member {
+        let loc = if self.optical_distance.is_some() {
+            rec.qname().and_then(|s| parse_optical_loc(s.to_bytes()))
+        } else {
+            None
+        };
+        Member {
+            idx,
+            score: calc_mate_score(rec) + extra_score,
+            loc,
+        }
+    }
+
+    // Resolve all groups that can not receive any more members given that the
+    // input has reached position cur
+    fn finalize_groups(&mut self, cur: (usize, HtsPos)) -> Result<(), SamError> {
+        while let Some(e) = self.pending.first_entry() {
+            let (tid, pos) = *e.key();
+            if !(tid < cur.0 || (tid == cur.0 && pos.saturating_add(self.window) < cur.1)) {
+                break;
+            }
+            for k in e.remove() {
+                match k {
+                    GroupKey::Frag(k) => {
+                        if let Some(g) = self.frag_groups.remove(&k) {
+                            self.resolve_frag_group(k.lib, g)?
+                        }
+                    }
+                    GroupKey::Pair(k) => {
+                        if self.pair_groups.get(&k).is_some_and(|g| g.fin_pos == pos) {
+                            let g = self.pair_groups.remove(&k).unwrap();
+                            self.resolve_pair_group(k.lib, g)?
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_frag_group(&mut self, lib: usize, g: FragGroup) -> Result<(), SamError> {
+        let best = if g.has_pair {
+            None
+        } else {
+            best_member(&g.members)
+        };
+        for (i, m) in g.members.iter().enumerate() {
+            let dup = Some(i) != best;
+            if dup {
+                self.metrics[lib].unpaired_read_duplicates += 1
+            }
+            self.set_status(m.idx, dup)?
+        }
+        Ok(())
+    }
+
+    fn resolve_pair_group(&mut self, lib: usize, g: PairGroup) -> Result<(), SamError> {
+        let best = best_member(&g.members);
+        for (i, m) in g.members.iter().enumerate() {
+            let dup = Some(i) != best;
+            if dup {
+                self.metrics[lib].read_pair_duplicates += 1;
+                if let (Some(d), Some(loc)) = (self.optical_distance, m.loc.as_ref())
+                    && g.members.iter().enumerate().any(|(j, m1)| {
+                        j != i && m1.loc.as_ref().is_some_and(|l| is_optical(loc, l, d))
+                    })
+                {
+                    self.metrics[lib].read_pair_optical_duplicates += 1
+                }
+            }
+            self.set_status(m.idx, dup)?
+        }
+        Ok(())
+    }
+
+    // Set the duplicate status of a record and of any records from the same template
+    // waiting on this decision
+    fn set_status(&mut self, idx: u64, dup: bool) -> Result<(), SamError> {
+        let key = {
+            let e = self.entry_mut(idx)?;
+            e.dup = Some(dup);
+            if dup {
+                e.rec.set_flag(e.rec.flag() | BAM_FDUP)
+            }
+            if e.rec.flag() & BAM_FPAIRED == 0 {
+                return Ok(());
+            }
+            qname_key(&e.rec)
+        };
+        if let Some(Template::Pending(v)) = self.templates.remove(&key) {
+            if v.is_empty() {
+                self.templates.insert(key, Template::Decided(dup));
+            } else {
+                for ix in v {
+                    let e = self.entry_mut(ix)?;
+                    e.dup = Some(dup);
+                    if dup {
+                        e.rec.set_flag(e.rec.flag() | BAM_FDUP)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_mut(&mut self, idx: u64) -> Result<&mut Entry, SamError> {
+        idx.checked_sub(self.base)
+            .and_then(|i| self.buf.get_mut(i as usize))
+            .ok_or(SamError::OperationFailed)
+    }
+}
+
+fn qname_key(rec: &BamRec) -> Box<[u8]> {
+    rec.qname().map(|s| s.to_bytes()).unwrap_or_default().into()
+}
+
+// The driver of a mapped pair is the leftmost read (read 1 if both reads start at
+// the same position).  Its mate follows the decision made for the driver.
+fn is_driver(rec: &BamRec) -> bool {
+    let own = (rec.tid(), rec.raw_pos());
+    let mate = (rec.mtid(), rec.mpos().unwrap_or(-1));
+    own < mate || (own == mate && rec.flag() & BAM_FREAD1 != 0)
+}
+
+fn five_prime_end(rec: &BamRec, cigar: Option<&Cigar>) -> End {
+    End {
+        tid: rec.tid().unwrap_or(usize::MAX),
+        ..five_prime(rec.raw_pos(), cigar, rec.is_reversed())
+    }
+}
+
+// Unclipped 5' position and strand of an alignment
+fn five_prime(pos: HtsPos, cigar: Option<&Cigar>, rev: bool) -> End {
+    let clip = |it: &mut dyn Iterator<Item = &crate::sam::CigarElem>| {
+        it.take_while(|e| matches!(e.op(), CigarOp::SoftClip | CigarOp::HardClip))
+            .map(|e| e.op_len() as HtsPos)
+            .sum::<HtsPos>()
+    };
+    let pos = match cigar {
+        Some(c) if rev => {
+            pos + (c.reference_len() as HtsPos - 1).max(0) + clip(&mut c.iter().rev())
+        }
+        Some(c) => pos - clip(&mut c.iter()),
+        None => pos,
+    };
+    End { tid: 0, pos, rev }
+}
+
+// Highest score, taking the first in case of ties
+fn best_member(v: &[Member]) -> Option<usize> {
+    v.iter()
+        .enumerate()
+        .fold(None, |best: Option<(usize, i64)>, (i, m)| match best {
+            Some((_, s)) if s >= m.score => best,
+            _ => Some((i, m.score)),
+        })
+        .map(|(i, _)| i)
+}
+
+// Parse tile and coordinates from Illumina read names, where the last three colon separated
+// fields are tile, x and y.  All fields up to and including the tile are used to identify the
+// tile (so flowcell and lane are also compared if present)
+fn parse_optical_loc(qname: &[u8]) -> Option<OpticalLoc> {
+    let mut it = qname.rsplitn(3, |c| *c == b':');
+    let y = parse_coord(it.next()?)?;
+    let x = parse_coord(it.next()?)?;
+    let tile = it.next()?;
+    Some(OpticalLoc {
+        tile: tile.into(),
+        x,
+        y,
+    })
+}
+
+fn parse_coord(s: &[u8]) -> Option<i64> {
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+fn is_optical(a: &OpticalLoc, b: &OpticalLoc, d: u32) -> bool {
+    a.tile == b.tile && (a.x - b.x).abs() <= d as i64 && (a.y - b.y).abs() <= d as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::{make_hdr, parse_recs};
+
+    #[test]
+    fn mark_pairs() -> Result<(), SamError> {
+        let mut hdr =
+            make_hdr(c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n@RG\tID:rg1\tLB:lib1")?;
+        let lines: [&[u8]; 7] = [
+            b"M1:1:1101:10:10\t99\tchr1\t101\t60\t10M\t=\t191\t100\tACGTACGTAC\tIIIIIIIIII\tRG:Z:rg1\tMC:Z:10M",
+            b"M1:1:1101:12:15\t99\tchr1\t103\t60\t2S8M\t=\t191\t98\tACGTACGTAC\tIIIIIIIII#\tRG:Z:rg1\tMC:Z:10M",
+            b"c\t0\tchr1\t101\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tRG:Z:rg1",
+            b"d\t0\tchr1\t121\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tRG:Z:rg1",
+            b"e\t0\tchr1\t121\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIII#\tRG:Z:rg1",
+            b"M1:1:1101:10:10\t147\tchr1\t191\t60\t10M\t=\t101\t-100\tACGTACGTAC\tIIIIIIIIII\tRG:Z:rg1\tMC:Z:10M",
+            b"M1:1:1101:12:15\t147\tchr1\t191\t60\t10M\t=\t103\t-98\tACGTACGTAC\tIIIIIIIIII\tRG:Z:rg1\tMC:Z:2S8M",
+        ];
+
+        let recs = parse_recs(&mut hdr, lines)?;
+
+        let mut md = MarkDup::new(&hdr);
+        md.set_optical_distance(Some(100));
+        let mut out = Vec::new();
+        for b in recs {
+            md.push(b)?;
+            while let Some(r) = md.pop() {
+                out.push(r)
+            }
+        }
+        md.finish()?;
+        while let Some(r) = md.pop() {
+            out.push(r)
+        }
+        assert_eq!(out.len(), 7);
+        let dups: Vec<_> = out.iter().map(|r| r.flag() & BAM_FDUP != 0).collect();
+        assert_eq!(dups, [false, true, true, false, true, false, true]);
+
+        let m: Vec<_> = md.metrics().collect();
+        assert_eq!(m.len(), 1);
+        assert_eq!(m[0].library, "lib1");
+        assert_eq!(m[0].read_pairs_examined, 2);
+        assert_eq!(m[0].read_pair_duplicates, 1);
+        assert_eq!(m[0].read_pair_optical_duplicates, 1);
+        assert_eq!(m[0].unpaired_reads_examined, 3);
+        assert_eq!(m[0].unpaired_read_duplicates, 2);
+        Ok(())
+    }
+}
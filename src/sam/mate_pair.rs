@@ -0,0 +1,240 @@
+use std::{collections::VecDeque, mem};
+
+use crate::{
+    SamError,
+    hts::traits::ReadRec,
+    khash::KHashMap,
+    sam::{
+        BamRec,
+        record::bam1::{BAM_FPAIRED, BAM_FREAD2, BAM_FSECONDARY, BAM_FSUPPLEMENTARY},
+    },
+};
+
+/// A pair of mates, or an orphan record whose mate was not found, from a [MateReader]
+#[derive(Default, Debug)]
+pub struct MatePair {
+    recs: [BamRec; 2],
+    orphan: bool,
+}
+
+impl MatePair {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn is_orphan(&self) -> bool {
+        self.orphan
+    }
+
+    /// The record that occurred first in the input
+    #[inline]
+    pub fn first(&self) -> &BamRec {
+        &self.recs[0]
+    }
+
+    /// The record that occurred second in the input (None for orphans)
+    #[inline]
+    pub fn second(&self) -> Option<&BamRec> {
+        if self.orphan {
+            None
+        } else {
+            Some(&self.recs[1])
+        }
+    }
+
+    pub fn first_mut(&mut self) -> &mut BamRec {
+        &mut self.recs[0]
+    }
+
+    pub fn second_mut(&mut self) -> Option<&mut BamRec> {
+        if self.orphan {
+            None
+        } else {
+            Some(&mut self.recs[1])
+        }
+    }
+
+    /// The records ordered as (read 1, read 2)
+    pub fn reads(&self) -> (Option<&BamRec>, Option<&BamRec>) {
+        let r2 = |r: &BamRec| r.flag() & BAM_FREAD2 != 0;
+        match self.second() {
+            Some(b) if r2(&self.recs[0]) => (Some(b), Some(&self.recs[0])),
+            Some(b) => (Some(&self.recs[0]), Some(b)),
+            None if r2(&self.recs[0]) => (None, Some(&self.recs[0])),
+            None => (Some(&self.recs[0]), None),
+        }
+    }
+}
+
+/// Pairs mates while streaming a coordinate sorted input.
+///
+/// Primary alignments of paired reads are held in a cache (keyed on query name) until their
+/// mate is seen, at which point the pair is returned.  At the end of each contig, cached
+/// records whose mate should already have been seen are returned as orphans, as are any
+/// remaining records at the end of input.  Records whose mate is on a later contig stay in
+/// the cache.  Unpaired reads and secondary and supplementary alignments are skipped.
+pub struct MateReader<R> {
+    rd: R,
+    rec: BamRec,
+    cache: KHashMap<String, BamRec>,
+    max_cache: usize,
+    orphans: VecDeque<BamRec>,
+    // Spare records to avoid reallocations
+    pool: Vec<BamRec>,
+    curr_tid: Option<usize>,
+    eof: bool,
+    n_skipped: u64,
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> MateReader<R> {
+    pub fn new(rd: R) -> Self {
+        Self {
+            rd,
+            rec: BamRec::new(),
+            cache: KHashMap::new(),
+            max_cache: 1_000_000,
+            orphans: VecDeque::new(),
+            pool: Vec::new(),
+            curr_tid: None,
+            eof: false,
+            n_skipped: 0,
+        }
+    }
+
+    /// Maximum number of records held waiting for their mates (default 1000000).  If this
+    /// is exceeded then an error is returned
+    pub fn set_max_cache(&mut self, n: usize) -> &mut Self {
+        self.max_cache = n;
+        self
+    }
+
+    /// Number of records currently waiting for their mates
+    pub fn cache_len(&self) -> usize {
+        self.cache.len() as usize
+    }
+
+    /// Number of input records skipped as not being primary alignments of paired reads
+    pub fn n_skipped(&self) -> u64 {
+        self.n_skipped
+    }
+
+    // Move cached records whose mates should have been seen by the end of contig tid to the
+    // orphan list.  If tid is None, all cached records are moved
+    fn flush_orphans(&mut self, tid: Option<usize>) {
+        let done = |r: &BamRec| match (tid, r.mtid().or(r.tid())) {
+            (None, _) | (_, None) => true,
+            (Some(t), Some(m)) => m <= t,
+        };
+        let keys: Vec<String> = self
+            .cache
+            .iter()
+            .filter(|(_, r)| done(r))
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut v: Vec<BamRec> = keys.iter().filter_map(|k| self.cache.delete(k)).collect();
+        v.sort_by_key(|r| (r.tid(), r.raw_pos()));
+        self.orphans.extend(v)
+    }
+
+    fn take_orphan(&mut self, pair: &mut MatePair) -> bool {
+        if let Some(r) = self.orphans.pop_front() {
+            self.pool.push(mem::replace(&mut pair.recs[0], r));
+            pair.orphan = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> ReadRec for MateReader<R> {
+    type Rec = MatePair;
+    type Err = SamError;
+
+    fn read_rec(&mut self, pair: &mut MatePair) -> Result<Option<()>, SamError> {
+        loop {
+            if self.take_orphan(pair) {
+                return Ok(Some(()));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            if self.rd.read_rec(&mut self.rec)?.is_none() {
+                self.eof = true;
+                self.flush_orphans(None);
+                continue;
+            }
+            let flag = self.rec.flag();
+            if flag & (BAM_FPAIRED | BAM_FSECONDARY | BAM_FSUPPLEMENTARY) != BAM_FPAIRED {
+                self.n_skipped += 1;
+                continue;
+            }
+            let tid = self.rec.tid();
+            if tid != self.curr_tid {
+                if self.curr_tid.is_some() {
+                    self.flush_orphans(self.curr_tid);
+                }
+                self.curr_tid = tid;
+            }
+            let key =
+                String::from_utf8_lossy(self.rec.qname().map(|s| s.to_bytes()).unwrap_or(b"*"))
+                    .into_owned();
+            if let Some(mate) = self.cache.delete(&key) {
+                self.pool.push(mem::replace(&mut pair.recs[0], mate));
+                mem::swap(&mut pair.recs[1], &mut self.rec);
+                pair.orphan = false;
+                return Ok(Some(()));
+            }
+            if self.cache_len() >= self.max_cache {
+                return Err(SamError::MateCacheFull(self.max_cache));
+            }
+            let r = mem::replace(&mut self.rec, self.pool.pop().unwrap_or_default());
+            self.cache
+                .insert(key, r)
+                .map_err(|_| SamError::OutOfMemory)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::VecReader;
+
+    #[test]
+    fn mate_pairs() -> Result<(), SamError> {
+        let rd = VecReader::from_lines(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n@SQ\tSN:chr2\tLN:100000",
+            [
+                &b"rd1\t99\tchr1\t101\t60\t10M\t=\t191\t100\tACGTACGTAC\tIIIIIIIIII"[..],
+                b"rd2\t65\tchr1\t151\t60\t10M\tchr2\t101\t0\tACGTACGTAC\tIIIIIIIIII",
+                b"rd3\t97\tchr1\t161\t60\t10M\t=\t501\t0\tACGTACGTAC\tIIIIIIIIII",
+                b"rd1\t147\tchr1\t191\t60\t10M\t=\t101\t-100\tACGTACGTAC\tIIIIIIIIII",
+                b"rd4\t0\tchr1\t201\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+                b"rd2\t145\tchr2\t101\t60\t10M\tchr1\t151\t0\tACGTACGTAC\tIIIIIIIIII",
+            ],
+        )?;
+        let mut mr = MateReader::new(rd);
+        let mut pair = MatePair::new();
+
+        mr.read_rec(&mut pair)?.expect("Missing pair");
+        assert!(!pair.is_orphan());
+        assert_eq!(pair.first().qname(), Some(c"rd1"));
+        assert_eq!(pair.reads().1.and_then(|r| r.pos()), Some(190));
+
+        // rd3 is an orphan at the end of chr1, while rd2 is kept as its mate is on chr2
+        mr.read_rec(&mut pair)?.expect("Missing orphan");
+        assert!(pair.is_orphan());
+        assert_eq!(pair.first().qname(), Some(c"rd3"));
+
+        mr.read_rec(&mut pair)?.expect("Missing pair");
+        assert!(!pair.is_orphan());
+        assert_eq!(pair.first().qname(), Some(c"rd2"));
+        assert_eq!(pair.second().and_then(|r| r.tid()), Some(1));
+
+        assert!(mr.read_rec(&mut pair)?.is_none());
+        assert_eq!(mr.n_skipped(), 1);
+        Ok(())
+    }
+}
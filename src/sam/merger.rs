@@ -0,0 +1,457 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+};
+
+use crate::{
+    SamError,
+    hts::traits::{ReadRec, WriteRec},
+    sam::{BamAuxVal, BamRec, SamHdr, SortOrder, hdr_model::split_line, sorter::MergeRecs},
+};
+
+/// Mapping from the contigs, read groups and program IDs of one input to those of the
+/// merged header
+#[derive(Debug, Default)]
+struct InputMap {
+    tids: Vec<usize>,
+    rg: HashMap<Vec<u8>, Vec<u8>>,
+    pg: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl InputMap {
+    fn remap(&self, rec: &mut BamRec) -> Result<(), SamError> {
+        let map_tid = |t: Option<usize>| -> Result<Option<usize>, SamError> {
+            t.map(|i| self.tids.get(i).copied().ok_or(SamError::UnknownReference))
+                .transpose()
+        };
+        let tid = map_tid(rec.tid())?;
+        let mtid = map_tid(rec.mtid())?;
+        rec.set_tid(tid);
+        rec.set_mtid(mtid);
+        for (tag, map) in [("RG", &self.rg), ("PG", &self.pg)] {
+            if map.is_empty() {
+                continue;
+            }
+            let new = match rec.get_tag(tag)? {
+                Some(t) => match t.get_val()? {
+                    BamAuxVal::String(s) => map.get(s.to_bytes()).cloned(),
+                    _ => None,
+                },
+                None => None,
+            };
+            if let Some(v) = new {
+                rec.update_str_tag(tag, &v)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A header line split into type (without the leading `@`) and tag:value fields.  The
+/// fields of `@CO` lines are not split
+struct HdrLine<'a> {
+    typ: &'a str,
+    fields: Vec<(&'a str, String)>,
+}
+
+impl<'a> HdrLine<'a> {
+    fn parse(s: &'a str) -> Result<Self, SamError> {
+        if s.starts_with("@CO") {
+            return Ok(Self {
+                typ: "CO",
+                fields: Vec::new(),
+            });
+        }
+        let (typ, tags) = split_line(s)?;
+        let fields = tags.into_iter().map(|(t, v)| (t, v.to_owned())).collect();
+        Ok(Self { typ, fields })
+    }
+
+    fn get(&self, tag: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn set(&mut self, tag: &str, val: String) {
+        if let Some(f) = self.fields.iter_mut().find(|(t, _)| *t == tag) {
+            f.1 = val
+        }
+    }
+
+    fn content_without_id(&self) -> String {
+        let mut s = String::new();
+        for (t, v) in self.fields.iter().filter(|(t, _)| *t != "ID") {
+            s.push_str(t);
+            s.push(':');
+            s.push_str(v);
+            s.push('\t');
+        }
+        s
+    }
+
+    fn to_line(&self) -> String {
+        let mut s = format!("@{}", self.typ);
+        for (t, v) in self.fields.iter() {
+            s.push('\t');
+            s.push_str(t);
+            s.push(':');
+            s.push_str(v);
+        }
+        s
+    }
+}
+
+/// Merged SAM header built from the headers of several input files, with the mappings required
+/// to convert records from each input to the merged header.
+///
+/// - `@SQ` lines are merged on the SN tag.  Contigs with the same name must have the same length.
+///   Contigs not present in earlier inputs are appended to the dictionary.
+/// - `@RG` and `@PG` lines with IDs that are already used by a different line from another input
+///   are renamed by adding a numeric suffix (`-1`, `-2`, ...) to the ID, and the RG and PG aux
+///   tags of records from that input are changed to match.  PP tags of `@PG` lines are updated
+///   to follow renamed IDs.  Identical lines are only included once.
+/// - `@CO` lines are carried over (omitting duplicates).
+/// - The `@HD` line is taken from the first input, with the SO field set from the sort order.
+///
+/// Malformed header lines (i.e., fields not of the form `TG:value`) give a
+/// [SamError::MalformedHeaderLine] error.
+pub struct MergedHeader {
+    hdr: SamHdr,
+    maps: Vec<InputMap>,
+    order: SortOrder,
+}
+
+impl MergedHeader {
+    pub fn new(hdrs: &[&SamHdr], order: &SortOrder) -> Result<Self, SamError> {
+        let mut hd: Option<String> = None;
+        let mut sq: Vec<String> = Vec::new();
+        let mut sq_ix: HashMap<String, (usize, Option<String>)> = HashMap::new();
+        let mut others: Vec<String> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        // Map from (line type, ID) to the line contents without the ID field
+        let mut ids: HashMap<(&str, String), String> = HashMap::new();
+        let mut maps = Vec::with_capacity(hdrs.len());
+
+        for h in hdrs {
+            let text = h
+                .text()
+                .map(|t| t.to_str())
+                .transpose()?
+                .unwrap_or_default();
+            let mut map = InputMap::default();
+            let mut lines = Vec::new();
+
+            for l in text.lines().filter(|l| !l.is_empty()) {
+                let line = HdrLine::parse(l)?;
+                match line.typ {
+                    "HD" => {
+                        if hd.is_none() {
+                            hd = Some(l.to_owned())
+                        }
+                    }
+                    "SQ" => {
+                        let name = line.get("SN").ok_or(SamError::MissingSqTag)?.to_owned();
+                        let len = line.get("LN").map(|s| s.to_owned());
+                        let ix = match sq_ix.get(&name) {
+                            Some((ix, l1)) => {
+                                if len != *l1 {
+                                    return Err(SamError::IncompatibleSeqDict(name));
+                                }
+                                *ix
+                            }
+                            None => {
+                                let ix = sq.len();
+                                sq.push(l.to_owned());
+                                sq_ix.insert(name, (ix, len));
+                                ix
+                            }
+                        };
+                        map.tids.push(ix)
+                    }
+                    _ => lines.push((l, line)),
+                }
+            }
+
+            if *order == SortOrder::Coordinate && !map.tids.is_sorted() {
+                return Err(SamError::IncompatibleSeqOrder);
+            }
+
+            // Assign IDs for RG and PG lines, renaming if the ID is used by a different line
+            for (_, line) in lines.iter() {
+                let typ = match line.typ {
+                    "RG" => "RG",
+                    "PG" => "PG",
+                    _ => continue,
+                };
+                let Some(id) = line.get("ID") else {
+                    continue;
+                };
+                let content = line.content_without_id();
+                let mut new_id = id.to_owned();
+                let mut k = 0;
+                loop {
+                    match ids.get(&(typ, new_id.clone())) {
+                        None => {
+                            ids.insert((typ, new_id.clone()), content);
+                            break;
+                        }
+                        Some(c) if *c == content => break,
+                        _ => {
+                            k += 1;
+                            new_id = format!("{id}-{k}")
+                        }
+                    }
+                }
+                if new_id != id {
+                    let m = if typ == "RG" {
+                        &mut map.rg
+                    } else {
+                        &mut map.pg
+                    };
+                    m.insert(id.as_bytes().to_vec(), new_id.into_bytes());
+                }
+            }
+
+            // Rewrite ID and PP tags and store lines
+            for (l, mut line) in lines {
+                let l = match line.typ {
+                    "RG" | "PG" => {
+                        let pg = line.typ == "PG";
+                        let m = if pg { &map.pg } else { &map.rg };
+                        if let Some(new) = line.get("ID").and_then(|s| m.get(s.as_bytes())) {
+                            line.set("ID", String::from_utf8_lossy(new).into_owned())
+                        }
+                        if pg
+                            && let Some(new) = line.get("PP").and_then(|s| map.pg.get(s.as_bytes()))
+                        {
+                            line.set("PP", String::from_utf8_lossy(new).into_owned())
+                        }
+                        line.to_line()
+                    }
+                    _ => l.to_owned(),
+                };
+                if seen.insert(l.clone()) {
+                    others.push(l)
+                }
+            }
+            maps.push(map)
+        }
+
+        let mut text = hd.unwrap_or_else(|| "@HD\tVN:1.6".to_owned());
+        for l in sq.iter().chain(others.iter()) {
+            text.push('\n');
+            text.push_str(l);
+        }
+        text.push('\n');
+        let text = CString::new(text).map_err(|_| SamError::IllegalHeaderChars)?;
+        let mut hdr = SamHdr::parse(&text)?;
+        hdr.change_hd(c"SO", Some(order.so_value()));
+        hdr.change_hd(c"GO", None);
+        Ok(Self {
+            hdr,
+            maps,
+            order: order.clone(),
+        })
+    }
+
+    #[inline]
+    pub fn header(&self) -> &SamHdr {
+        &self.hdr
+    }
+
+    #[inline]
+    pub fn into_header(self) -> SamHdr {
+        self.hdr
+    }
+
+    /// Sort order of the merged output (as given to [MergedHeader::new])
+    #[inline]
+    pub fn order(&self) -> &SortOrder {
+        &self.order
+    }
+
+    /// Convert a record from input `ix` to use the contig ids and read group and program IDs
+    /// of the merged header
+    pub fn remap(&self, ix: usize, rec: &mut BamRec) -> Result<(), SamError> {
+        self.maps
+            .get(ix)
+            .ok_or(SamError::OperationFailed)?
+            .remap(rec)
+    }
+}
+
+struct RemapReader<'a, R> {
+    rd: R,
+    ix: usize,
+    hdr: &'a MergedHeader,
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> ReadRec for RemapReader<'_, R> {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        let r = self.rd.read_rec(rec)?;
+        if r.is_some() {
+            self.hdr.remap(self.ix, rec)?
+        }
+        Ok(r)
+    }
+}
+
+/// Merge records from multiple sorted inputs into one sorted stream.
+///
+/// Each input must be sorted in the sort order of the merged header (see [MergedHeader]).
+/// Records are converted to the merged header as they are read.  For coordinate sorted input,
+/// the contigs common to all inputs must occur in the same order in each header.
+pub struct SamMerger<'a, R> {
+    inner: MergeRecs<RemapReader<'a, R>>,
+}
+
+impl<'a, R: ReadRec<Rec = BamRec, Err = SamError>> SamMerger<'a, R> {
+    /// Create a merger from readers for each input, using `hdr` (created from the headers of
+    /// the inputs, in the same order as `readers`).  The inputs are merged using the sort order
+    /// of `hdr`
+    pub fn new(readers: Vec<R>, hdr: &'a MergedHeader) -> Result<Self, SamError> {
+        if readers.len() != hdr.maps.len() {
+            return Err(SamError::MergeInputMismatch);
+        }
+        let rds = readers
+            .into_iter()
+            .enumerate()
+            .map(|(ix, rd)| RemapReader { rd, ix, hdr })
+            .collect();
+        Ok(Self {
+            inner: MergeRecs::new(rds, hdr.order.clone())?,
+        })
+    }
+
+    /// Write all merged records to `wr`
+    pub fn write_all<W>(&mut self, wr: &mut W) -> Result<(), SamError>
+    where
+        W: WriteRec<Rec = BamRec, Err = SamError>,
+    {
+        let mut rec = BamRec::new();
+        while self.inner.read_rec(&mut rec)?.is_some() {
+            wr.write_rec(&mut rec)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> ReadRec for SamMerger<'_, R> {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        self.inner.read_rec(rec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::{VecReader, VecWriter, make_hdr, parse_recs};
+
+    fn str_tag(rec: &BamRec, tag: &str) -> Result<String, SamError> {
+        match rec.get_tag(tag)?.map(|t| t.get_val()).transpose()? {
+            Some(BamAuxVal::String(s)) => Ok(s.to_str()?.to_owned()),
+            _ => Err(SamError::OperationFailed),
+        }
+    }
+
+    #[test]
+    fn merge_headers() -> Result<(), SamError> {
+        let h1 = SamHdr::parse(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n\
+              @RG\tID:rg1\tSM:s1\n@PG\tID:bwa\tPN:bwa\n@CO\tlane 1\n",
+        )?;
+        let h2 = SamHdr::parse(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr3\tLN:200\n\
+              @SQ\tSN:chr2\tLN:500\n@RG\tID:rg1\tSM:s2\n@PG\tID:bwa\tPN:bwa\n@CO\tlane 2\n",
+        )?;
+        let m = MergedHeader::new(&[&h1, &h2], &SortOrder::QueryName)?;
+        let text = m.header().text().unwrap().to_str().unwrap();
+        assert_eq!(
+            text,
+            "@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n\
+             @SQ\tSN:chr3\tLN:200\n@RG\tID:rg1\tSM:s1\n@PG\tID:bwa\tPN:bwa\n@CO\tlane 1\n\
+             @RG\tID:rg1-1\tSM:s2\n@CO\tlane 2\n"
+        );
+        assert_eq!(m.maps[1].tids, [0, 2, 1]);
+        assert_eq!(m.maps[1].rg.get(b"rg1".as_slice()).unwrap(), b"rg1-1");
+        assert!(m.maps[1].pg.is_empty());
+
+        assert!(matches!(
+            MergedHeader::new(&[&h1, &h2], &SortOrder::Coordinate),
+            Err(SamError::IncompatibleSeqOrder)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_header() {
+        assert!(matches!(
+            HdrLine::parse("@RG\tID:rg1\tSM"),
+            Err(SamError::MalformedHeaderLine(s)) if s == "@RG\tID:rg1\tSM"
+        ));
+        assert!(HdrLine::parse("@PG\tID:x\tCL:a:b").is_ok_and(|l| l.get("CL") == Some("a:b")));
+        assert!(HdrLine::parse("@CO\tnot:tag value").is_ok_and(|l| l.typ == "CO"));
+    }
+
+    #[test]
+    fn merge_recs() -> Result<(), SamError> {
+        let mut h1 = make_hdr(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n\
+              @RG\tID:rg1\tSM:s1\n@PG\tID:bwa\tPN:bwa\tVN:1",
+        )?;
+        let mut h2 = make_hdr(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n\
+              @RG\tID:rg1\tSM:s2\n@PG\tID:bwa\tPN:bwa\tVN:2",
+        )?;
+        let line = |name: &str, ctg: &str, pos: usize| {
+            format!("{name}\t0\t{ctg}\t{pos}\t60\t4M\t*\t0\t0\tACGT\tIIII\tRG:Z:rg1\tPG:Z:bwa")
+        };
+        let r1 = parse_recs(
+            &mut h1,
+            [
+                line("a1", "chr1", 100),
+                line("a2", "chr1", 300),
+                line("a3", "chr2", 50),
+            ],
+        )?;
+        let r2 = parse_recs(
+            &mut h2,
+            [
+                line("b1", "chr1", 200),
+                line("b2", "chr1", 250),
+                line("b3", "chr2", 10),
+            ],
+        )?;
+
+        let m = MergedHeader::new(&[&h1, &h2], &SortOrder::Coordinate)?;
+        assert_eq!(m.order(), &SortOrder::Coordinate);
+        let mut merger = SamMerger::new(vec![VecReader::new(r1), VecReader::new(r2)], &m)?;
+        let mut wr = VecWriter::default();
+        merger.write_all(&mut wr)?;
+
+        let names: Vec<_> =
+            wr.0.iter()
+                .map(|r| r.qname().unwrap().to_str().unwrap())
+                .collect();
+        assert_eq!(names, ["a1", "b1", "b2", "a2", "b3", "a3"]);
+        for r in wr.0.iter() {
+            let (rg, pg) = (str_tag(r, "RG")?, str_tag(r, "PG")?);
+            if r.qname().unwrap().to_bytes().starts_with(b"a") {
+                assert_eq!((rg.as_str(), pg.as_str()), ("rg1", "bwa"))
+            } else {
+                assert_eq!((rg.as_str(), pg.as_str()), ("rg1-1", "bwa-1"))
+            }
+        }
+        let text = m.header().text().unwrap().to_str().unwrap();
+        assert!(text.contains("@RG\tID:rg1-1\tSM:s2\n@PG\tID:bwa-1\tPN:bwa\tVN:2\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,508 @@
+use crate::{
+    SamError,
+    base::Base,
+    hts::{HtsPos, traits::ReadRec},
+    sam::{
+        BamRec, CigarOp, MMParse, Modification, PILEUP_DEFAULT_FLAG_FILTER,
+        record::bam1::BAM_FUNMAP,
+    },
+};
+
+const PLP_DEL: u8 = 1;
+const PLP_REFSKIP: u8 = 2;
+const PLP_HEAD: u8 = 4;
+const PLP_TAIL: u8 = 8;
+
+/// A read that is active in the pileup, with the base calls and modifications for each
+/// position in the read, and the current position of the read in the cigar
+#[derive(Default)]
+struct ModPlpRead {
+    rec: BamRec,
+    end: HtsPos,
+    bases: Vec<Base>,
+    // Modifications for query position i are mods[mod_ix[i]..mod_ix[i + 1]]
+    mod_ix: Vec<u32>,
+    mods: Vec<Modification>,
+    // Cigar element index, reference and query positions at start of cigar element
+    ci: usize,
+    rpos: HtsPos,
+    qpos: usize,
+}
+
+impl ModPlpRead {
+    fn setup(&mut self, mm: &mut MMParse) -> Result<(), SamError> {
+        let Self {
+            rec,
+            bases,
+            mod_ix,
+            mods,
+            ..
+        } = self;
+        bases.clear();
+        mods.clear();
+        mod_ix.clear();
+        mod_ix.push(0);
+        if let Some(mut it) = mm.mod_iter(rec)? {
+            while let Some(x) = it.next_pos() {
+                bases.push(x.seq_base());
+                mods.extend_from_slice(x.data());
+                mod_ix.push(mods.len() as u32);
+            }
+        }
+        // The iterator stops early (or returns nothing) if no modifications are selected
+        // from the read, so fill in the remaining bases with no modifications
+        let n = bases.len();
+        if n != rec.seq_len() {
+            bases.extend(rec.seq().skip(n));
+            mod_ix.resize(bases.len() + 1, mods.len() as u32);
+        }
+        self.end = self.rec.endpos();
+        self.ci = 0;
+        self.rpos = self.rec.pos().unwrap_or(0);
+        self.qpos = 0;
+        Ok(())
+    }
+
+    // Move cigar position to the element covering pos and generate the pileup entry for
+    // this read.  pos must be less than self.end and not less than the previous value of pos
+    fn entry(&mut self, ix: usize, pos: HtsPos) -> EntryData {
+        let elems = self.rec.cigar().expect("Missing cigar").as_elems();
+        loop {
+            let e = elems[self.ci];
+            if e.consumes_reference() {
+                let len = e.op_len() as HtsPos;
+                if self.rpos + len > pos {
+                    break;
+                }
+                self.rpos += len;
+            }
+            if e.consumes_query() {
+                self.qpos += e.op_len() as usize
+            }
+            self.ci += 1;
+        }
+        let (op, len) = elems[self.ci].op_pair();
+        let off = pos - self.rpos;
+        let mut flags = 0;
+        let mut indel = 0;
+        let qpos = match op {
+            CigarOp::Del => {
+                flags |= PLP_DEL;
+                self.qpos
+            }
+            CigarOp::RefSkip => {
+                flags |= PLP_REFSKIP;
+                self.qpos
+            }
+            _ => {
+                if off + 1 == len as HtsPos {
+                    // Check for following indel (skipping padding)
+                    if let Some(e) = elems[self.ci + 1..].iter().find(|e| e.op() != CigarOp::Pad) {
+                        match e.op_pair() {
+                            (CigarOp::Ins, l) => indel = l as i32,
+                            (CigarOp::Del, l) => indel = -(l as i32),
+                            _ => {}
+                        }
+                    }
+                }
+                self.qpos + off as usize
+            }
+        };
+        if Some(pos) == self.rec.pos() {
+            flags |= PLP_HEAD
+        }
+        if pos + 1 == self.end {
+            flags |= PLP_TAIL
+        }
+        EntryData {
+            ix: ix as u32,
+            qpos: qpos as u32,
+            indel,
+            flags,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct EntryData {
+    ix: u32,
+    qpos: u32,
+    indel: i32,
+    flags: u8,
+}
+
+/// A single read at a position in a [ModPileup]
+#[derive(Copy, Clone)]
+pub struct ModPileupEntry<'a> {
+    read: &'a ModPlpRead,
+    data: EntryData,
+}
+
+impl<'a> ModPileupEntry<'a> {
+    #[inline]
+    pub fn rec(&self) -> &'a BamRec {
+        &self.read.rec
+    }
+
+    /// Position in the read of the current base (for deletions and reference skips, the
+    /// position of the next base in the read)
+    #[inline]
+    pub fn qpos(&self) -> usize {
+        self.data.qpos as usize
+    }
+
+    /// Length of indel following the current position (positive for insertions,
+    /// negative for deletions, 0 otherwise)
+    #[inline]
+    pub fn indel(&self) -> i32 {
+        self.data.indel
+    }
+
+    #[inline]
+    pub fn is_del(&self) -> bool {
+        (self.data.flags & PLP_DEL) != 0
+    }
+
+    #[inline]
+    pub fn is_refskip(&self) -> bool {
+        (self.data.flags & PLP_REFSKIP) != 0
+    }
+
+    /// This position is the first aligned base of the read
+    #[inline]
+    pub fn is_head(&self) -> bool {
+        (self.data.flags & PLP_HEAD) != 0
+    }
+
+    /// This position is the last aligned base of the read
+    #[inline]
+    pub fn is_tail(&self) -> bool {
+        (self.data.flags & PLP_TAIL) != 0
+    }
+
+    fn has_base(&self) -> bool {
+        (self.data.flags & (PLP_DEL | PLP_REFSKIP)) == 0
+    }
+
+    /// Base call at this position (as stored in the record, so the reverse complement of the
+    /// original read base for reverse strand reads).  None for deletions and reference skips
+    pub fn base(&self) -> Option<Base> {
+        if self.has_base() {
+            self.read.bases.get(self.qpos()).copied()
+        } else {
+            None
+        }
+    }
+
+    /// Base quality at this position.  None for deletions and reference skips, or if
+    /// qualities are not present
+    pub fn qual(&self) -> Option<u8> {
+        if self.has_base() {
+            self.read
+                .rec
+                .qual_slice()
+                .get(self.qpos())
+                .copied()
+                .filter(|q| *q != 0xff)
+        } else {
+            None
+        }
+    }
+
+    /// Modifications called at this position (from the MM/ML tags).  Empty for deletions
+    /// and reference skips, or if the read has no sequence at this position
+    pub fn mods(&self) -> &'a [Modification] {
+        if self.has_base() {
+            let i = self.qpos();
+            let ix = &self.read.mod_ix;
+            match (ix.get(i), ix.get(i + 1)) {
+                (Some(a), Some(b)) => &self.read.mods[*a as usize..*b as usize],
+                _ => &[],
+            }
+        } else {
+            &[]
+        }
+    }
+}
+
+/// All reads covering a reference position in a [ModPileup]
+pub struct ModPileupColumn<'a> {
+    tid: usize,
+    pos: HtsPos,
+    reads: &'a [ModPlpRead],
+    entries: &'a [EntryData],
+}
+
+impl<'a> ModPileupColumn<'a> {
+    #[inline]
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// Reference position (zero offset)
+    #[inline]
+    pub fn pos(&self) -> HtsPos {
+        self.pos
+    }
+
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = ModPileupEntry<'a>> + use<'a> {
+        let reads = self.reads;
+        self.entries.iter().map(move |d| ModPileupEntry {
+            read: &reads[d.ix as usize],
+            data: *d,
+        })
+    }
+}
+
+/// Native pileup of records from a single coordinate sorted input.  Unlike [crate::sam::Pileup],
+/// each entry gives access to the base modifications parsed from the MM/ML tags of the read,
+/// so that base calls and modification calls can be collected in a single pass.
+///
+/// Unmapped reads and reads that do not consume any reference bases are skipped.
+pub struct ModPileup<R> {
+    rd: R,
+    mm: MMParse,
+    flag_filter: u16,
+    max_depth: usize,
+    // Next read to be added (valid if have_next is set)
+    next_rec: BamRec,
+    have_next: bool,
+    eof: bool,
+    // Position of last read from input
+    last: (usize, HtsPos),
+    active: Vec<ModPlpRead>,
+    // Storage for finished reads (to avoid reallocations)
+    pool: Vec<ModPlpRead>,
+    entries: Vec<EntryData>,
+    tid: usize,
+    pos: HtsPos,
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> ModPileup<R> {
+    pub fn new(rd: R) -> Self {
+        Self {
+            rd,
+            mm: MMParse::default(),
+            flag_filter: PILEUP_DEFAULT_FLAG_FILTER,
+            max_depth: 8000,
+            next_rec: BamRec::new(),
+            have_next: false,
+            eof: false,
+            last: (0, 0),
+            active: Vec::new(),
+            pool: Vec::new(),
+            entries: Vec::new(),
+            tid: 0,
+            pos: 0,
+        }
+    }
+
+    /// Set maximum depth per position (default 8000).  Reads starting at a position where
+    /// the depth limit has been reached are skipped
+    pub fn set_max_depth(&mut self, n: usize) -> &mut Self {
+        self.max_depth = n;
+        self
+    }
+
+    /// Records with any of the bits in `flag` set are skipped
+    /// (default [PILEUP_DEFAULT_FLAG_FILTER])
+    pub fn set_flag_filter(&mut self, flag: u16) -> &mut Self {
+        self.flag_filter = flag;
+        self
+    }
+
+    /// Access the [MMParse] used for parsing modifications (i.e., to select which
+    /// modifications are reported)
+    pub fn mm_parse_mut(&mut self) -> &mut MMParse {
+        &mut self.mm
+    }
+
+    // Read next record passing filters into self.next_rec
+    fn fill_next(&mut self) -> Result<(), SamError> {
+        while !self.have_next && !self.eof {
+            match self.rd.read_rec(&mut self.next_rec)? {
+                None => self.eof = true,
+                Some(()) => {
+                    let r = &self.next_rec;
+                    if r.flag() & (self.flag_filter | BAM_FUNMAP) != 0
+                        || r.cigar().is_none()
+                        || r.endpos() <= r.pos().unwrap_or(HtsPos::MAX)
+                    {
+                        continue;
+                    }
+                    let p = (r.tid().unwrap(), r.pos().unwrap());
+                    if p < self.last {
+                        return Err(SamError::NotCoordinateSorted);
+                    }
+                    self.last = p;
+                    self.have_next = true
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Move reads that have finished before the current position to the pool.  This is done in
+    // a single pass, keeping the order of the remaining active reads
+    fn retire_reads(&mut self) {
+        let pos = self.pos;
+        let mut j = 0;
+        for i in 0..self.active.len() {
+            if self.active[i].end > pos {
+                self.active.swap(i, j);
+                j += 1
+            }
+        }
+        self.pool.extend(self.active.drain(j..))
+    }
+
+    /// Get the next pileup column, or None at the end of input
+    pub fn next_column(&mut self) -> Result<Option<ModPileupColumn<'_>>, SamError> {
+        self.retire_reads();
+        self.fill_next()?;
+        if self.active.is_empty() {
+            if !self.have_next {
+                return Ok(None);
+            }
+            // Jump to start of next read
+            self.tid = self.next_rec.tid().unwrap();
+            self.pos = self.next_rec.pos().unwrap();
+        }
+
+        // Add new reads starting at the current position
+        while self.have_next
+            && self.next_rec.tid() == Some(self.tid)
+            && self.next_rec.pos() == Some(self.pos)
+        {
+            if self.active.len() < self.max_depth {
+                let mut r = self.pool.pop().unwrap_or_default();
+                std::mem::swap(&mut r.rec, &mut self.next_rec);
+                r.setup(&mut self.mm)?;
+                self.active.push(r)
+            }
+            self.have_next = false;
+            self.fill_next()?;
+        }
+
+        let pos = self.pos;
+        self.entries.clear();
+        for (i, r) in self.active.iter_mut().enumerate() {
+            self.entries.push(r.entry(i, pos))
+        }
+        self.pos += 1;
+
+        Ok(Some(ModPileupColumn {
+            tid: self.tid,
+            pos,
+            reads: &self.active,
+            entries: &self.entries,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hts::HtsFile,
+        sam::{SamHdr, SamReader},
+    };
+
+    #[test]
+    fn mod_pileup() -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/long_read_meth.bam", c"r")
+            .expect("Failed to read test/long_read_meth.bam");
+        let hdr = SamHdr::read(&mut h)?;
+        let rdr = SamReader::new(&mut h, &hdr);
+        let mut plp = ModPileup::new(rdr);
+
+        let mut last = None;
+        let mut n_mods = 0;
+        while let Some(col) = plp.next_column()? {
+            let p = (col.tid(), col.pos());
+            assert!(last.map(|l| l < p).unwrap_or(true));
+            last = Some(p);
+            for e in col.entries() {
+                assert_eq!(e.base().is_none(), e.is_del() || e.is_refskip());
+                if !e.mods().is_empty() {
+                    assert!(e.base().is_some());
+                    n_mods += 1;
+                }
+            }
+        }
+        assert!(n_mods > 0);
+        Ok(())
+    }
+
+    // Pileup of SAM records (with a single contig of length 20) read using htslib, so that
+    // empty ML arrays can be used.  Returns (base, number of mods) for all entries
+    fn mod_pileup_sam(
+        name: &str,
+        recs: &str,
+        select: Option<&[&str]>,
+    ) -> Result<Vec<(Option<Base>, usize)>, SamError> {
+        let path = std::env::temp_dir().join(format!(
+            "mod_pileup_{name}_{}.sam",
+            std::process::id()
+        ));
+        std::fs::write(&path, format!("@SQ\tSN:ctg\tLN:20\n{recs}\n"))?;
+        let fname = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        let mut h = HtsFile::open(&fname, c"r").expect("Failed to read SAM file");
+        let hdr = SamHdr::read(&mut h)?;
+        let rdr = SamReader::new(&mut h, &hdr);
+        let mut plp = ModPileup::new(rdr);
+        if let Some(s) = select {
+            plp.mm_parse_mut().set_selection(s)?
+        }
+        let mut v = Vec::new();
+        let res = (|| {
+            while let Some(col) = plp.next_column()? {
+                v.extend(col.entries().map(|e| (e.base(), e.mods().len())))
+            }
+            Ok::<_, SamError>(())
+        })();
+        let _ = std::fs::remove_file(&path);
+        res.map(|_| v)
+    }
+
+    #[test]
+    fn mod_pileup_no_calls() -> Result<(), SamError> {
+        // MM tag with no modification calls (and an empty ML array)
+        let v = mod_pileup_sam(
+            "no_calls",
+            "rd1\t0\tctg\t1\t60\t8M\t*\t0\t0\tACGTACGT\t*\tMM:Z:C+h?;C+m?;\tML:B:C",
+            None,
+        )?;
+        assert_eq!(v.len(), 8);
+        assert!(v.iter().all(|(b, n)| b.is_some() && *n == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn mod_pileup_no_selected() -> Result<(), SamError> {
+        // Selection excludes the only modification in the read
+        let rec = "rd1\t0\tctg\t1\t60\t8M\t*\t0\t0\tACGTACGT\t*\tMM:Z:C+m,0;\tML:B:C,200";
+        let v = mod_pileup_sam("no_selected", rec, Some(&["C+h"]))?;
+        assert_eq!(v.len(), 8);
+        assert!(v.iter().all(|(b, n)| b.is_some() && *n == 0));
+
+        let v = mod_pileup_sam("selected", rec, Some(&["C+m"]))?;
+        assert_eq!(v.iter().map(|(_, n)| n).sum::<usize>(), 1);
+        assert!(v.iter().all(|(b, _)| b.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn mod_pileup_no_seq() -> Result<(), SamError> {
+        // Mapped read without a sequence
+        let v = mod_pileup_sam("no_seq", "rd1\t0\tctg\t1\t60\t8M\t*\t0\t0\t*\t*", None)?;
+        assert_eq!(v.len(), 8);
+        assert!(v.iter().all(|(b, n)| b.is_none() && *n == 0));
+        Ok(())
+    }
+}
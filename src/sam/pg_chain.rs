@@ -0,0 +1,223 @@
+use std::{collections::HashSet, env, ffi::CString};
+
+use crate::{
+    SamError,
+    sam::{PgLine, SamHdr, SamHeader},
+};
+
+/// Details of a program run to be recorded in a @PG header line
+#[derive(Debug, Clone, Default)]
+pub struct PgRecord {
+    name: String,
+    version: Option<String>,
+    command_line: Option<String>,
+    description: Option<String>,
+}
+
+impl PgRecord {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Make record with the command line taken from the arguments of the current process
+    pub fn from_env(name: &str) -> Self {
+        let cl = env::args().collect::<Vec<_>>().join(" ");
+        Self {
+            command_line: Some(cl),
+            ..Self::new(name)
+        }
+    }
+
+    pub fn set_version(&mut self, s: &str) -> &mut Self {
+        self.version = Some(s.to_owned());
+        self
+    }
+
+    pub fn set_command_line(&mut self, s: &str) -> &mut Self {
+        self.command_line = Some(s.to_owned());
+        self
+    }
+
+    pub fn set_description(&mut self, s: &str) -> &mut Self {
+        self.description = Some(s.to_owned());
+        self
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn make_line(&self, id: String, prev_id: Option<String>) -> PgLine {
+        // Tabs and newlines are not allowed in header values
+        let clean = |s: &String| s.replace(['\t', '\n', '\r'], " ");
+        PgLine {
+            id,
+            name: Some(self.name.clone()),
+            prev_id,
+            version: self.version.as_ref().map(clean),
+            description: self.description.as_ref().map(clean),
+            command_line: self.command_line.as_ref().map(clean),
+            other: Vec::new(),
+        }
+    }
+}
+
+// Indices of PG lines (given as (ID, PP) pairs) that are not referred to by any PP tag
+fn leaf_ix(v: &[(String, Option<String>)]) -> Vec<usize> {
+    let used: HashSet<&str> = v.iter().filter_map(|(_, pp)| pp.as_deref()).collect();
+    v.iter()
+        .enumerate()
+        .filter(|(_, (id, _))| !used.contains(id.as_str()))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Generate an ID based on name (name, name.1, name.2...) not already in ids
+fn unique_id(name: &str, ids: &HashSet<String>) -> String {
+    if !ids.contains(name) {
+        return name.to_owned();
+    }
+    (1..)
+        .map(|i| format!("{name}.{i}"))
+        .find(|s| !ids.contains(s))
+        .unwrap()
+}
+
+// Make the new PG lines for rec given the existing (ID, PP) pairs.  One line is generated for
+// each leaf of the existing PG chain(s), or a single line if there are no existing PG lines
+fn new_pg_lines(v: &[(String, Option<String>)], rec: &PgRecord) -> Vec<PgLine> {
+    let mut ids: HashSet<String> = v.iter().map(|(id, _)| id.clone()).collect();
+    let mut prev: Vec<Option<String>> = leaf_ix(v)
+        .into_iter()
+        .map(|i| Some(v[i].0.clone()))
+        .collect();
+    if prev.is_empty() {
+        prev.push(None)
+    }
+    prev.into_iter()
+        .map(|pp| {
+            let id = unique_id(&rec.name, &ids);
+            ids.insert(id.clone());
+            rec.make_line(id, pp)
+        })
+        .collect()
+}
+
+impl SamHeader {
+    /// PG lines that are at the end of a PG chain (i.e., are not referred to by the PP tag
+    /// of another PG line), in header order
+    pub fn pg_leaves(&self) -> Vec<&PgLine> {
+        leaf_ix(&self.pg_id_pairs())
+            .into_iter()
+            .map(|i| &self.pg[i])
+            .collect()
+    }
+
+    /// Generate a PG ID based on `name` that is not used in the header.  If `name` is
+    /// already used then a suffix is added (name.1, name.2...)
+    pub fn unique_pg_id(&self, name: &str) -> String {
+        unique_id(name, &self.pg.iter().map(|p| p.id.clone()).collect())
+    }
+
+    /// Add PG line(s) for `rec`, linking to the end of the existing PG chain(s).
+    ///
+    /// If the header has more than one chain then a line is added to each chain, each with a
+    /// unique ID.  Returns the IDs of the added lines
+    pub fn add_pg(&mut self, rec: &PgRecord) -> Vec<String> {
+        let lines = new_pg_lines(&self.pg_id_pairs(), rec);
+        let ids = lines.iter().map(|p| p.id.clone()).collect();
+        self.pg.extend(lines);
+        ids
+    }
+
+    fn pg_id_pairs(&self) -> Vec<(String, Option<String>)> {
+        self.pg
+            .iter()
+            .map(|p| (p.id.clone(), p.prev_id.clone()))
+            .collect()
+    }
+}
+
+impl SamHdr {
+    /// Add PG line(s) for `rec`, linking to the end of the existing PG chain(s) with unique
+    /// IDs (see [SamHeader::add_pg]).  Returns the IDs of the added lines
+    pub fn add_pg_record(&mut self, rec: &PgRecord) -> Result<Vec<String>, SamError> {
+        let n = self.count_lines(c"PG").unwrap_or(0);
+        let mut v = Vec::with_capacity(n);
+        for i in 0..n {
+            let tag = |k| match self.find_tag_pos(c"PG", i, k) {
+                Some(ks) => ks.to_str().map(|s| Some(s.to_owned())),
+                None => Ok(None),
+            };
+            let id = tag(c"ID")?.ok_or(SamError::OperationFailed)?;
+            v.push((id, tag(c"PP")?))
+        }
+        let mut ids = Vec::new();
+        for p in new_pg_lines(&v, rec) {
+            let s = CString::new(p.to_string()).map_err(|_| SamError::IllegalHeaderChars)?;
+            self.add_lines(&s)?;
+            ids.push(p.id)
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HDR: &str = "@HD\tVN:1.6\n\
+        @PG\tID:bwa\tPN:bwa\n\
+        @PG\tID:samtools\tPN:samtools\tPP:bwa\n\
+        @PG\tID:picard\tPN:picard\n";
+
+    #[test]
+    fn add_pg() -> Result<(), SamError> {
+        let mut h: SamHeader = HDR.parse()?;
+        let leaves: Vec<_> = h.pg_leaves().iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(leaves, ["samtools", "picard"]);
+        assert_eq!(h.unique_pg_id("bwa"), "bwa.1");
+
+        let mut rec = PgRecord::new("mytool");
+        rec.set_version("1.0").set_command_line("mytool\t-x in.bam");
+        assert_eq!(h.add_pg(&rec), ["mytool", "mytool.1"]);
+        assert_eq!(
+            h.pg_by_id("mytool.1").and_then(|p| p.prev_id.as_deref()),
+            Some("picard")
+        );
+        assert_eq!(
+            h.pg_by_id("mytool").and_then(|p| p.command_line.as_deref()),
+            Some("mytool -x in.bam")
+        );
+        assert_eq!(h.add_pg(&rec), ["mytool.2", "mytool.3"]);
+        assert_eq!(
+            h.pg_by_id("mytool.2").and_then(|p| p.prev_id.as_deref()),
+            Some("mytool")
+        );
+
+        let mut h = SamHeader::new();
+        assert_eq!(h.add_pg(&rec), ["mytool"]);
+        assert!(h.pg[0].prev_id.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn add_pg_record() -> Result<(), SamError> {
+        let mut hdr = SamHdr::new();
+        hdr.add_lines(&CString::new(HDR).unwrap())?;
+        let rec = PgRecord::from_env("mytool");
+        assert_eq!(hdr.add_pg_record(&rec)?, ["mytool", "mytool.1"]);
+        let h = SamHeader::from_sam_hdr(&hdr)?;
+        assert_eq!(h.pg.len(), 5);
+        assert_eq!(
+            h.pg_by_id("mytool").and_then(|p| p.prev_id.as_deref()),
+            Some("samtools")
+        );
+        assert!(h.pg_by_id("mytool").unwrap().command_line.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,494 @@
+use std::{ffi::c_void, ptr::NonNull, slice};
+
+use c2rust_bitfields::BitfieldStruct;
+use libc::c_int;
+
+use crate::{
+    SamError,
+    hts::{HtsPos, traits::ReadRec},
+    sam::{
+        BamRec,
+        record::bam1::{BAM_FDUP, BAM_FQCFAIL, BAM_FSECONDARY, BAM_FUNMAP, bam1_t},
+    },
+};
+
+/// Default flag filter for pileups (as used by samtools mpileup)
+pub const PILEUP_DEFAULT_FLAG_FILTER: u16 = BAM_FUNMAP | BAM_FSECONDARY | BAM_FQCFAIL | BAM_FDUP;
+
+#[repr(C)]
+struct BamPlpRaw {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+struct BamMplpRaw {
+    _unused: [u8; 0],
+}
+
+type BamPlpAutoFunc = unsafe extern "C" fn(data: *mut c_void, b: *mut bam1_t) -> c_int;
+
+#[repr(C)]
+#[derive(BitfieldStruct)]
+struct BamPileup1 {
+    b: *mut bam1_t,
+    qpos: i32,
+    indel: c_int,
+    level: c_int,
+    #[bitfield(name = "del_flag", ty = "u32", bits = "0..=0")]
+    #[bitfield(name = "head_flag", ty = "u32", bits = "1..=1")]
+    #[bitfield(name = "tail_flag", ty = "u32", bits = "2..=2")]
+    #[bitfield(name = "refskip_flag", ty = "u32", bits = "3..=3")]
+    #[bitfield(name = "aux", ty = "u32", bits = "5..=31")]
+    bfield: [u8; 4],
+    cd: u64,
+    cigar_ind: c_int,
+}
+
+#[link(name = "hts")]
+unsafe extern "C" {
+    fn bam_plp_init(func: Option<BamPlpAutoFunc>, data: *mut c_void) -> *mut BamPlpRaw;
+    fn bam_plp_destroy(iter: *mut BamPlpRaw);
+    fn bam_plp_set_maxcnt(iter: *mut BamPlpRaw, maxcnt: c_int);
+    fn bam_plp_init_overlaps(iter: *mut BamPlpRaw);
+    fn bam_plp64_auto(
+        iter: *mut BamPlpRaw,
+        tid: *mut c_int,
+        pos: *mut HtsPos,
+        n_plp: *mut c_int,
+    ) -> *const BamPileup1;
+    fn bam_mplp_init(
+        n: c_int,
+        func: Option<BamPlpAutoFunc>,
+        data: *mut *mut c_void,
+    ) -> *mut BamMplpRaw;
+    fn bam_mplp_destroy(iter: *mut BamMplpRaw);
+    fn bam_mplp_set_maxcnt(iter: *mut BamMplpRaw, maxcnt: c_int);
+    fn bam_mplp_init_overlaps(iter: *mut BamMplpRaw) -> c_int;
+    fn bam_mplp64_auto(
+        iter: *mut BamMplpRaw,
+        tid: *mut c_int,
+        pos: *mut HtsPos,
+        n_plp: *mut c_int,
+        plp: *mut *const BamPileup1,
+    ) -> c_int;
+}
+
+/// A single read at a pileup position
+#[repr(transparent)]
+pub struct PileupEntry(BamPileup1);
+
+impl PileupEntry {
+    #[inline]
+    pub fn rec(&self) -> &BamRec {
+        unsafe { BamRec::from_raw_ptr(self.0.b) }
+    }
+
+    /// Position in the read of the current base (for deletions, the position of the
+    /// next base in the read)
+    #[inline]
+    pub fn qpos(&self) -> usize {
+        self.0.qpos as usize
+    }
+
+    /// Length of indel following the current position (positive for insertions,
+    /// negative for deletions, 0 otherwise)
+    #[inline]
+    pub fn indel(&self) -> i32 {
+        self.0.indel
+    }
+
+    /// Display level of the read (used for text alignment views)
+    #[inline]
+    pub fn level(&self) -> i32 {
+        self.0.level
+    }
+
+    /// The read has a deletion at this position
+    #[inline]
+    pub fn is_del(&self) -> bool {
+        self.0.del_flag() != 0
+    }
+
+    /// This position is the first base of the read
+    #[inline]
+    pub fn is_head(&self) -> bool {
+        self.0.head_flag() != 0
+    }
+
+    /// This position is the last base of the read
+    #[inline]
+    pub fn is_tail(&self) -> bool {
+        self.0.tail_flag() != 0
+    }
+
+    /// The read has a reference skip (cigar N) at this position
+    #[inline]
+    pub fn is_refskip(&self) -> bool {
+        self.0.refskip_flag() != 0
+    }
+
+    /// Index of the current element in the read cigar
+    #[inline]
+    pub fn cigar_ind(&self) -> usize {
+        self.0.cigar_ind as usize
+    }
+}
+
+/// All reads covering a reference position
+pub struct PileupColumn<'a> {
+    tid: usize,
+    pos: HtsPos,
+    entries: &'a [PileupEntry],
+}
+
+impl<'a> PileupColumn<'a> {
+    #[inline]
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// Reference position (zero offset)
+    #[inline]
+    pub fn pos(&self) -> HtsPos {
+        self.pos
+    }
+
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn entries(&self) -> &'a [PileupEntry] {
+        self.entries
+    }
+}
+
+/// Pileup columns from multiple inputs at a reference position
+pub struct MPileupColumn<'a> {
+    tid: usize,
+    pos: HtsPos,
+    n_plp: &'a [c_int],
+    plp: &'a [*const BamPileup1],
+}
+
+impl<'a> MPileupColumn<'a> {
+    #[inline]
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// Reference position (zero offset)
+    #[inline]
+    pub fn pos(&self) -> HtsPos {
+        self.pos
+    }
+
+    /// Number of inputs
+    #[inline]
+    pub fn n_inputs(&self) -> usize {
+        self.n_plp.len()
+    }
+
+    /// Total depth across all inputs
+    pub fn depth(&self) -> usize {
+        self.n_plp.iter().map(|n| (*n).max(0) as usize).sum()
+    }
+
+    /// Reads from input `ix`
+    pub fn entries(&self, ix: usize) -> &'a [PileupEntry] {
+        unsafe { make_entries(self.plp[ix], self.n_plp[ix]) }
+    }
+}
+
+unsafe fn make_entries<'a>(p: *const BamPileup1, n: c_int) -> &'a [PileupEntry] {
+    if p.is_null() || n <= 0 {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(p as *const PileupEntry, n as usize) }
+    }
+}
+
+struct PlpReader<R> {
+    rd: R,
+    rec: BamRec,
+    flag_filter: u16,
+    err: Option<SamError>,
+}
+
+impl<R> PlpReader<R> {
+    fn new_ptr(rd: R) -> NonNull<Self> {
+        let b = Box::new(Self {
+            rd,
+            rec: BamRec::new(),
+            flag_filter: PILEUP_DEFAULT_FLAG_FILTER,
+            err: None,
+        });
+        unsafe { NonNull::new_unchecked(Box::into_raw(b)) }
+    }
+}
+
+// Callback used by htslib to get the next record for the pileup.  Records matching
+// the flag filter are skipped.
+unsafe extern "C" fn plp_read<R: ReadRec<Rec = BamRec, Err = SamError>>(
+    data: *mut c_void,
+    b: *mut bam1_t,
+) -> c_int {
+    let ctx = unsafe { &mut *(data as *mut PlpReader<R>) };
+    loop {
+        match ctx.rd.read_rec(&mut ctx.rec) {
+            Ok(Some(())) => {
+                if ctx.rec.flag() & ctx.flag_filter == 0 {
+                    // b is owned by htslib so we copy into it rather than replacing it
+                    let dst = unsafe { &mut *(b as *mut BamRec) };
+                    ctx.rec.copy(dst);
+                    return 0;
+                }
+            }
+            Ok(None) => return -1,
+            Err(e) => {
+                ctx.err = Some(e);
+                return -2;
+            }
+        }
+    }
+}
+
+/// Pileup of records from a single coordinate sorted input using the htslib pileup engine
+pub struct Pileup<R> {
+    iter: NonNull<BamPlpRaw>,
+    ctx: NonNull<PlpReader<R>>,
+}
+
+impl<R> Drop for Pileup<R> {
+    fn drop(&mut self) {
+        unsafe {
+            bam_plp_destroy(self.iter.as_ptr());
+            drop(Box::from_raw(self.ctx.as_ptr()))
+        }
+    }
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> Pileup<R> {
+    pub fn new(rd: R) -> Result<Self, SamError> {
+        let ctx = PlpReader::new_ptr(rd);
+        match NonNull::new(unsafe { bam_plp_init(Some(plp_read::<R>), ctx.as_ptr().cast()) }) {
+            Some(iter) => Ok(Self { iter, ctx }),
+            None => {
+                drop(unsafe { Box::from_raw(ctx.as_ptr()) });
+                Err(SamError::OutOfMemory)
+            }
+        }
+    }
+
+    /// Set maximum depth per position (default 8000)
+    pub fn set_max_depth(&mut self, n: usize) -> &mut Self {
+        unsafe { bam_plp_set_maxcnt(self.iter.as_ptr(), n.min(c_int::MAX as usize) as c_int) }
+        self
+    }
+
+    /// Detect overlapping read pairs, setting the quality of one of the overlapping bases to 0
+    /// so bases are not counted twice.  This should be called before the first column is
+    /// requested
+    pub fn enable_overlap_detection(&mut self) -> &mut Self {
+        unsafe { bam_plp_init_overlaps(self.iter.as_ptr()) }
+        self
+    }
+
+    /// Records with any of the bits in `flag` set are skipped
+    /// (default [PILEUP_DEFAULT_FLAG_FILTER])
+    pub fn set_flag_filter(&mut self, flag: u16) -> &mut Self {
+        unsafe { (*self.ctx.as_ptr()).flag_filter = flag }
+        self
+    }
+
+    /// Get the next pileup column, or None at the end of input
+    pub fn next_column(&mut self) -> Result<Option<PileupColumn<'_>>, SamError> {
+        let (mut tid, mut pos, mut n) = (0, 0, 0);
+        let p = unsafe { bam_plp64_auto(self.iter.as_ptr(), &mut tid, &mut pos, &mut n) };
+        if let Some(e) = unsafe { (*self.ctx.as_ptr()).err.take() } {
+            Err(e)
+        } else if p.is_null() {
+            if n < 0 {
+                Err(SamError::PileupFailed)
+            } else {
+                Ok(None)
+            }
+        } else {
+            Ok(Some(PileupColumn {
+                tid: tid as usize,
+                pos,
+                entries: unsafe { make_entries(p, n) },
+            }))
+        }
+    }
+}
+
+/// Pileup across multiple coordinate sorted inputs (sharing the same sequence dictionary)
+/// using the htslib multi-pileup engine.  Columns are returned for every position covered in
+/// at least one input.
+pub struct MPileup<R> {
+    iter: NonNull<BamMplpRaw>,
+    ctx: Vec<NonNull<PlpReader<R>>>,
+    n_plp: Vec<c_int>,
+    plp: Vec<*const BamPileup1>,
+}
+
+impl<R> Drop for MPileup<R> {
+    fn drop(&mut self) {
+        unsafe {
+            bam_mplp_destroy(self.iter.as_ptr());
+            for c in self.ctx.drain(..) {
+                drop(Box::from_raw(c.as_ptr()))
+            }
+        }
+    }
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> MPileup<R> {
+    pub fn new(rds: Vec<R>) -> Result<Self, SamError> {
+        let n = rds.len();
+        let ctx: Vec<_> = rds.into_iter().map(PlpReader::new_ptr).collect();
+        let mut data: Vec<*mut c_void> = ctx.iter().map(|c| c.as_ptr().cast()).collect();
+        let p = unsafe { bam_mplp_init(n as c_int, Some(plp_read::<R>), data.as_mut_ptr()) };
+        match NonNull::new(p) {
+            Some(iter) => Ok(Self {
+                iter,
+                ctx,
+                n_plp: vec![0; n],
+                plp: vec![std::ptr::null(); n],
+            }),
+            None => {
+                for c in ctx {
+                    drop(unsafe { Box::from_raw(c.as_ptr()) })
+                }
+                Err(SamError::OutOfMemory)
+            }
+        }
+    }
+
+    /// Set maximum depth per position for each input (default 8000)
+    pub fn set_max_depth(&mut self, n: usize) -> &mut Self {
+        unsafe { bam_mplp_set_maxcnt(self.iter.as_ptr(), n.min(c_int::MAX as usize) as c_int) }
+        self
+    }
+
+    /// Detect overlapping read pairs (see [Pileup::enable_overlap_detection])
+    pub fn enable_overlap_detection(&mut self) -> Result<&mut Self, SamError> {
+        if unsafe { bam_mplp_init_overlaps(self.iter.as_ptr()) } < 0 {
+            Err(SamError::OutOfMemory)
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Records with any of the bits in `flag` set are skipped
+    /// (default [PILEUP_DEFAULT_FLAG_FILTER])
+    pub fn set_flag_filter(&mut self, flag: u16) -> &mut Self {
+        for c in self.ctx.iter() {
+            unsafe { (*c.as_ptr()).flag_filter = flag }
+        }
+        self
+    }
+
+    /// Get the next pileup column, or None at the end of input
+    pub fn next_column(&mut self) -> Result<Option<MPileupColumn<'_>>, SamError> {
+        let (mut tid, mut pos) = (0, 0);
+        let r = unsafe {
+            bam_mplp64_auto(
+                self.iter.as_ptr(),
+                &mut tid,
+                &mut pos,
+                self.n_plp.as_mut_ptr(),
+                self.plp.as_mut_ptr(),
+            )
+        };
+        for c in self.ctx.iter() {
+            if let Some(e) = unsafe { (*c.as_ptr()).err.take() } {
+                return Err(e);
+            }
+        }
+        match r {
+            ..0 => Err(SamError::PileupFailed),
+            0 => Ok(None),
+            _ => Ok(Some(MPileupColumn {
+                tid: tid as usize,
+                pos,
+                n_plp: &self.n_plp,
+                plp: &self.plp,
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hts::HtsFile,
+        sam::{SamHdr, SamReader},
+    };
+
+    #[test]
+    fn pileup() -> Result<(), SamError> {
+        let mut h =
+            HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to read test/realn01.sam");
+        let hdr = SamHdr::read(&mut h)?;
+        let rdr = SamReader::new(&mut h, &hdr);
+        let mut plp = Pileup::new(rdr)?;
+        plp.set_max_depth(100);
+
+        let col = plp.next_column()?.expect("No pileup columns");
+        assert_eq!(col.tid(), 0);
+        assert_eq!(col.pos(), 531);
+        assert!(col.depth() > 0);
+        assert!(col.entries().iter().all(|e| e.is_head() && !e.is_del()));
+
+        let mut n = 1;
+        let mut dels = 0;
+        while let Some(col) = plp.next_column()? {
+            dels += col.entries().iter().filter(|e| e.is_del()).count();
+            n += 1;
+        }
+        assert!(n > 100);
+        assert!(dels > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn mpileup() -> Result<(), SamError> {
+        // Single input pileup: (tid, pos, depth) for each column
+        let mut h =
+            HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to read test/realn01.sam");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut plp = Pileup::new(SamReader::new(&mut h, &hdr))?;
+        let mut cols = Vec::new();
+        while let Some(col) = plp.next_column()? {
+            cols.push((col.tid(), col.pos(), col.depth()))
+        }
+        drop(plp);
+
+        // Multi input pileup using the same file twice
+        let mut h1 =
+            HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to read test/realn01.sam");
+        let mut h2 =
+            HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to read test/realn01.sam");
+        let hdr1 = SamHdr::read(&mut h1)?;
+        let hdr2 = SamHdr::read(&mut h2)?;
+        let mut mplp = MPileup::new(vec![
+            SamReader::new(&mut h1, &hdr1),
+            SamReader::new(&mut h2, &hdr2),
+        ])?;
+        let mut mcols = Vec::new();
+        while let Some(col) = mplp.next_column()? {
+            assert_eq!(col.n_inputs(), 2);
+            let (d1, d2) = (col.entries(0).len(), col.entries(1).len());
+            assert_eq!(d1, d2);
+            assert_eq!(col.depth(), d1 + d2);
+            mcols.push((col.tid(), col.pos(), d1))
+        }
+        assert_eq!(mcols, cols);
+        assert_eq!(mcols[0].1, 531);
+        Ok(())
+    }
+}
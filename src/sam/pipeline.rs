@@ -0,0 +1,310 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, SyncSender, sync_channel},
+    },
+    thread,
+};
+
+use crate::{
+    SamError,
+    hts::traits::{ReadRec, WriteRec},
+    sam::BamRec,
+};
+
+/// Record counts from a [Pipeline] run
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub n_read: u64,
+    pub n_written: u64,
+}
+
+// A batch of records passed between the stages of the pipeline.  The records are kept
+// between uses, so only the first n entries are valid
+#[derive(Default)]
+struct Batch {
+    recs: Vec<BamRec>,
+    keep: Vec<bool>,
+    n: usize,
+}
+
+impl Batch {
+    fn fill<R>(&mut self, rd: &mut R, size: usize) -> Result<(), SamError>
+    where
+        R: ReadRec<Rec = BamRec, Err = SamError>,
+    {
+        self.n = 0;
+        while self.n < size {
+            if self.n == self.recs.len() {
+                self.recs.push(BamRec::new())
+            }
+            if rd.read_rec(&mut self.recs[self.n])?.is_none() {
+                break;
+            }
+            self.n += 1
+        }
+        Ok(())
+    }
+}
+
+// Messages sent to the writer.  Errors from any stage are sent here so that the writer
+// (which runs on the calling thread) sees them and can shut down the pipeline
+type Msg = Result<(u64, Batch), SamError>;
+
+/// Process records in parallel, keeping the input order.
+///
+/// A reader thread fills batches of records from the input, which are then handed to a pool
+/// of worker threads that call a user supplied function on each record.  The processed
+/// batches are written out in input order from the calling thread.  The number of batches
+/// in existence is fixed, so memory use is bounded however fast or slow the individual
+/// stages are.  If any stage fails, the pipeline is stopped and the first error is returned.
+pub struct Pipeline {
+    batch_size: usize,
+    n_workers: usize,
+    n_batches: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        let n_workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self {
+            batch_size: 1024,
+            n_workers,
+            n_batches: 2 * n_workers + 2,
+        }
+    }
+
+    /// Number of records in each batch (default 1024)
+    pub fn set_batch_size(&mut self, n: usize) -> &mut Self {
+        self.batch_size = n.max(1);
+        self
+    }
+
+    /// Set number of worker threads (default is the available parallelism).  This also
+    /// resets the number of batches to twice the number of workers plus 2
+    pub fn set_n_workers(&mut self, n: usize) -> &mut Self {
+        self.n_workers = n.max(1);
+        self.n_batches = 2 * self.n_workers + 2;
+        self
+    }
+
+    /// Maximum number of batches in flight at any time.  This, together with the batch
+    /// size, limits the number of records held in memory
+    pub fn set_max_batches(&mut self, n: usize) -> &mut Self {
+        self.n_batches = n.max(1);
+        self
+    }
+
+    /// Read all records from `rd`, call `f` on each record and write the records for which
+    /// `f` returns true to `wr`, in the same order as they were read
+    pub fn run<R, W, F>(&self, rd: &mut R, wr: &mut W, f: F) -> Result<PipelineStats, SamError>
+    where
+        R: ReadRec<Rec = BamRec, Err = SamError> + Send,
+        W: WriteRec<Rec = BamRec, Err = SamError>,
+        F: Fn(&mut BamRec) -> Result<bool, SamError> + Sync,
+    {
+        self.run_with_state(rd, wr, || (), |_, rec| f(rec))
+    }
+
+    /// As [Pipeline::run], but each worker thread creates its own state by calling `init`,
+    /// which is then passed to `f`.  This allows workers to keep buffers (or parsers etc.)
+    /// between records
+    pub fn run_with_state<R, W, S, I, F>(
+        &self,
+        rd: &mut R,
+        wr: &mut W,
+        init: I,
+        f: F,
+    ) -> Result<PipelineStats, SamError>
+    where
+        R: ReadRec<Rec = BamRec, Err = SamError> + Send,
+        W: WriteRec<Rec = BamRec, Err = SamError>,
+        I: Fn() -> S + Sync,
+        F: Fn(&mut S, &mut BamRec) -> Result<bool, SamError> + Sync,
+    {
+        let nb = self.n_batches;
+        let abort = AtomicBool::new(false);
+
+        // As there are only nb batches, sends to these channels can never block
+        let (free_tx, free_rx) = sync_channel::<Batch>(nb);
+        let (work_tx, work_rx) = sync_channel::<(u64, Batch)>(nb);
+        let (out_tx, out_rx) = sync_channel::<Msg>(nb + self.n_workers + 1);
+        let work_rx = Mutex::new(work_rx);
+
+        for _ in 0..nb {
+            free_tx.send(Batch::default()).expect("Channel closed")
+        }
+
+        thread::scope(|sc| {
+            // Reader
+            let tx = out_tx.clone();
+            let abort = &abort;
+            let size = self.batch_size;
+            sc.spawn(move || {
+                if let Err(e) = read_stage(rd, size, free_rx, work_tx, abort) {
+                    let _ = tx.send(Err(e));
+                }
+            });
+
+            // Workers
+            for _ in 0..self.n_workers {
+                let tx = out_tx.clone();
+                let (work_rx, init, f) = (&work_rx, &init, &f);
+                sc.spawn(move || {
+                    let mut state = init();
+                    while let Some((ix, mut b)) = next_batch(work_rx, abort) {
+                        let r = b.recs[..b.n]
+                            .iter_mut()
+                            .zip(b.keep.iter_mut())
+                            .try_for_each(|(rec, k)| f(&mut state, rec).map(|x| *k = x));
+                        let msg = r.map(|_| (ix, b));
+                        let failed = msg.is_err();
+                        if tx.send(msg).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(out_tx);
+
+            // Writer
+            let res = write_stage(wr, out_rx, free_tx);
+            if res.is_err() {
+                abort.store(true, Ordering::Relaxed)
+            }
+            res
+        })
+    }
+}
+
+fn read_stage<R>(
+    rd: &mut R,
+    size: usize,
+    free_rx: Receiver<Batch>,
+    work_tx: SyncSender<(u64, Batch)>,
+    abort: &AtomicBool,
+) -> Result<(), SamError>
+where
+    R: ReadRec<Rec = BamRec, Err = SamError>,
+{
+    let mut ix = 0;
+    // recv() fails if the writer has exited
+    while let Ok(mut b) = free_rx.recv() {
+        if abort.load(Ordering::Relaxed) {
+            break;
+        }
+        b.fill(rd, size)?;
+        if b.n == 0 {
+            break;
+        }
+        b.keep.resize(b.n, true);
+        let full = b.n == size;
+        if work_tx.send((ix, b)).is_err() || !full {
+            break;
+        }
+        ix += 1
+    }
+    Ok(())
+}
+
+fn next_batch(rx: &Mutex<Receiver<(u64, Batch)>>, abort: &AtomicBool) -> Option<(u64, Batch)> {
+    if abort.load(Ordering::Relaxed) {
+        None
+    } else {
+        rx.lock().unwrap().recv().ok()
+    }
+}
+
+fn write_stage<W>(
+    wr: &mut W,
+    out_rx: Receiver<Msg>,
+    free_tx: SyncSender<Batch>,
+) -> Result<PipelineStats, SamError>
+where
+    W: WriteRec<Rec = BamRec, Err = SamError>,
+{
+    let mut stats = PipelineStats::default();
+    let mut pending = BTreeMap::new();
+    let mut next_ix = 0;
+
+    // Finishes when all senders (reader and workers) have gone
+    for msg in out_rx.iter() {
+        let (ix, b) = msg?;
+        pending.insert(ix, b);
+        while let Some(mut b) = pending.remove(&next_ix) {
+            stats.n_read += b.n as u64;
+            for (rec, k) in b.recs[..b.n].iter_mut().zip(b.keep.iter()) {
+                if *k {
+                    wr.write_rec(rec)?;
+                    stats.n_written += 1
+                }
+            }
+            next_ix += 1;
+            // The reader may already have finished, in which case the batch is not needed
+            let _ = free_tx.send(b);
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::{VecReader, VecWriter};
+
+    fn make_recs(n: usize) -> Result<VecReader, SamError> {
+        VecReader::from_lines(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000",
+            (0..n).map(|i| {
+                format!(
+                    "rd{i}\t0\tchr1\t{}\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+                    i + 1
+                )
+            }),
+        )
+    }
+
+    #[test]
+    fn ordered() -> Result<(), SamError> {
+        let mut rd = make_recs(1000)?;
+        let mut wr = VecWriter::default();
+        let mut pl = Pipeline::new();
+        pl.set_n_workers(4).set_batch_size(7);
+        let stats = pl.run(&mut rd, &mut wr, |rec| {
+            Ok(rec.pos().map(|x| x % 3 != 0).unwrap_or(false))
+        })?;
+        assert_eq!(stats.n_read, 1000);
+        assert_eq!(stats.n_written, 666);
+        assert_eq!(wr.0.len(), 666);
+        assert!(wr.0.windows(2).all(|w| w[0].pos() < w[1].pos()));
+        Ok(())
+    }
+
+    #[test]
+    fn error() -> Result<(), SamError> {
+        let mut rd = make_recs(500)?;
+        let mut wr = VecWriter::default();
+        let mut pl = Pipeline::new();
+        pl.set_n_workers(3).set_batch_size(10);
+        let res = pl.run(&mut rd, &mut wr, |rec| {
+            if rec.pos() == Some(250) {
+                Err(SamError::OperationFailed)
+            } else {
+                Ok(true)
+            }
+        });
+        assert!(matches!(res, Err(SamError::OperationFailed)));
+        assert!(wr.0.len() <= 250);
+        Ok(())
+    }
+}
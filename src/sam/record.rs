@@ -9,6 +9,7 @@ use bam1::*;
 /// All non library rust code should work with BamRec rather than
 /// with bam1_t (which is private)
 #[derive(Clone, Default, Debug)]
+#[repr(transparent)]
 pub struct BamRec {
     inner: bam1_t,
 }
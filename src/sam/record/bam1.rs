@@ -18,7 +18,7 @@ const BAM_USER_OWNS_STRUCT: u32 = 1;
 #[allow(unused)]
 const BAM_USER_OWNS_DATA: u32 = 2;
 
-use crate::{SamError, hts::HtsPos};
+use crate::{SamError, bgzf::BgzfRaw, hts::HtsPos};
 
 pub const BAM_FPAIRED: u16 = 1;
 pub const BAM_FPROPER_PAIR: u16 = 2;
@@ -41,6 +41,8 @@ unsafe extern "C" {
     fn bam_copy1<'a>(bdest: *mut bam1_t, bsrc: *const bam1_t) -> *mut bam1_t;
     fn bam_endpos(pt_: *const bam1_t) -> HtsPos;
     fn bam_set_qname(pt_: *mut bam1_t, qname: *const c_char) -> c_int;
+    fn bam_read1(fp: *mut BgzfRaw, b: *mut bam1_t) -> c_int;
+    fn bam_write1(fp: *mut BgzfRaw, b: *const bam1_t) -> c_int;
 }
 
 #[repr(C)]
@@ -156,6 +158,22 @@ impl bam1_t {
             _ => Err(SamError::SetQnameFailed),
         }
     }
+
+    fn read_bgzf(&mut self, fp: &mut BgzfRaw) -> Result<Option<()>, SamError> {
+        match unsafe { bam_read1(fp, self) } {
+            0.. => Ok(Some(())),
+            -1 => Ok(None),
+            e => Err(SamError::SamReadError(e)),
+        }
+    }
+
+    fn write_bgzf(&self, fp: &mut BgzfRaw) -> Result<(), SamError> {
+        if unsafe { bam_write1(fp, self) } < 0 {
+            Err(SamError::BamWriteError)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[inline]
@@ -22,6 +22,9 @@ unsafe extern "C" {
         len: c_int,
         data: *const i8,
     ) -> c_int;
+    fn bam_aux_update_int(b: *mut bam1_t, tag: *const c_char, val: i64) -> c_int;
+    fn bam_aux_update_str(b: *mut bam1_t, tag: *const c_char, len: c_int, data: *const c_char)
+    -> c_int;
 }
 
 /// Represnts a block of tag data that is to be deleted.
@@ -107,6 +110,39 @@ impl BamRec {
         }
     }
 
+    /// Set the integer tag `tag_id` to `val`, replacing any existing tag with the same id.
+    /// The tag is stored using the smallest integer type that can hold `val`
+    pub fn update_int_tag(&mut self, tag_id: &str, val: i64) -> Result<(), AuxError> {
+        let id = check_tag_id(tag_id)?;
+        if unsafe { bam_aux_update_int(self.as_mut_ptr(), id.as_ptr() as *const c_char, val) } == 0 {
+            Ok(())
+        } else {
+            Err(AuxError::AddingAuxFailed)
+        }
+    }
+
+    /// Set the string (Z) tag `tag_id` to `val`, replacing any existing tag with the same id.
+    pub fn update_str_tag(&mut self, tag_id: &str, val: &[u8]) -> Result<(), AuxError> {
+        let id = check_tag_id(tag_id)?;
+        if val.iter().any(|c| !(b' '..=b'~').contains(c)) {
+            return Err(AuxError::IllegalCharacters);
+        }
+        let len = c_int::try_from(val.len()).map_err(|_| AuxError::IntegerOutOfRange)?;
+        if unsafe {
+            bam_aux_update_str(
+                self.as_mut_ptr(),
+                id.as_ptr() as *const c_char,
+                len,
+                val.as_ptr() as *const c_char,
+            )
+        } == 0
+        {
+            Ok(())
+        } else {
+            Err(AuxError::AddingAuxFailed)
+        }
+    }
+
     #[inline]
     pub fn aux_tags<'a>(&'a self) -> BamAuxIter<'a> {
         BamAuxIter::new(self.get_aux_slice())
@@ -375,6 +411,15 @@ impl BamRec {
     }
 }
 
+fn check_tag_id(tag_id: &str) -> Result<&[u8], AuxError> {
+    let id = tag_id.as_bytes();
+    if id.len() == 2 && id[0].is_ascii_alphabetic() && id[1].is_ascii_alphanumeric() {
+        Ok(id)
+    } else {
+        Err(AuxError::BadTagId)
+    }
+}
+
 pub fn parse_aux_tag<W: Write + Seek>(
     wrt: &mut W,
     s: &[u8],
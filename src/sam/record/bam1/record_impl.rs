@@ -1,7 +1,7 @@
 use std::ffi::CStr;
 
 use crate::{
-    hts::HtsPos, sam::{bam1::{bam1_t, BAM_FREVERSE}, BamRec, Cigar, CigarElem, QualIter, SeqIter, SeqQualIter}, SamError
+    bgzf::BgzfRaw, hts::HtsPos, sam::{bam1::{bam1_t, BAM_FREVERSE}, BamRec, Cigar, CigarElem, QualIter, SeqIter, SeqQualIter}, SamError
 };
 
 use libc::c_int;
@@ -25,6 +25,24 @@ impl BamRec {
         self.inner.copy(&mut dst.inner)
     }
 
+    /// Read a record in BAM format from a BGZF stream, returning None on EOF
+    #[inline]
+    pub fn read_bgzf(&mut self, fp: &mut BgzfRaw) -> Result<Option<()>, SamError> {
+        self.inner.read_bgzf(fp)
+    }
+
+    /// Write the record in BAM format to a BGZF stream
+    #[inline]
+    pub fn write_bgzf(&self, fp: &mut BgzfRaw) -> Result<(), SamError> {
+        self.inner.write_bgzf(fp)
+    }
+
+    /// Approximate memory used by the record, including the data segment
+    #[inline]
+    pub fn mem_size(&self) -> usize {
+        size_of::<Self>() + self.inner.m_data as usize
+    }
+
     pub fn qname(&self) -> Option<&CStr> {
         if self.inner.data.is_null() {
             None
@@ -101,6 +119,13 @@ impl BamRec {
         }
     }
 
+    /// Position field of the record without checking the unmapped flag (-1 if not set).
+    /// Unmapped reads can be placed at the position of their mapped mate
+    #[inline]
+    pub fn raw_pos(&self) -> HtsPos {
+        self.inner.core.pos
+    }
+
     pub fn mpos(&self) -> Option<HtsPos> {
         let x = self.inner.core.mpos;
         if x >= 0 && (self.inner.core.flag & BAM_FMUNMAP) == 0 {
@@ -114,6 +139,54 @@ impl BamRec {
         self.inner.core.isze
     }
 
+    #[inline]
+    pub fn set_flag(&mut self, flag: u16) {
+        self.inner.core.flag = flag
+    }
+
+    #[inline]
+    pub fn set_mapq(&mut self, mapq: u8) {
+        self.inner.core.qual = mapq
+    }
+
+    #[inline]
+    pub fn set_tid(&mut self, tid: Option<usize>) {
+        self.inner.core.tid = tid_to_c_int(tid)
+    }
+
+    #[inline]
+    pub fn set_mtid(&mut self, mtid: Option<usize>) {
+        self.inner.core.mtid = tid_to_c_int(mtid)
+    }
+
+    /// Set the (0 offset) position of the record. The bin field is recalculated
+    /// as it is stored in BAM output and must be consistent with the position
+    pub fn set_pos(&mut self, pos: Option<HtsPos>) {
+        self.inner.core.pos = pos.unwrap_or(-1);
+        self.update_bin()
+    }
+
+    #[inline]
+    pub fn set_mpos(&mut self, mpos: Option<HtsPos>) {
+        self.inner.core.mpos = mpos.unwrap_or(-1)
+    }
+
+    #[inline]
+    pub fn set_template_len(&mut self, isize: HtsPos) {
+        self.inner.core.isze = isize
+    }
+
+    /// Recalculate the bin field from the current position and alignment end
+    pub fn update_bin(&mut self) {
+        let beg = self.inner.core.pos.max(0);
+        let end = if self.is_mapped() && self.inner.core.n_cigar > 0 {
+            self.endpos().max(beg + 1)
+        } else {
+            beg + 1
+        };
+        self.inner.core.bin = reg2bin(beg, end)
+    }
+
     pub(super) fn make_data_slice(&self, off: usize, sz: usize) -> &[u8] {
         assert!(off + sz <= self.inner.l_data as usize, "Bam data corrupt");
         unsafe { super::make_data_slice(self.inner.data as *const u8, off, sz) }
@@ -156,6 +229,14 @@ impl BamRec {
     pub(crate) fn as_mut_ptr(&mut self) -> *mut bam1_t {
         &mut self.inner as *mut bam1_t
     }
+
+    /// # Safety
+    ///
+    /// `p` must be non-null and point to a valid bam1_t that lives at least as long as 'a
+    #[inline]
+    pub(crate) unsafe fn from_raw_ptr<'a>(p: *const bam1_t) -> &'a Self {
+        unsafe { &*(p as *const Self) }
+    }
 }
 
 unsafe impl Send for BamRec {}
@@ -165,3 +246,29 @@ unsafe impl Sync for BamRec {}
 fn check_tid(i: c_int) -> Option<usize> {
     if i >= 0 { Some(i as usize) } else { None }
 }
+
+#[inline]
+fn tid_to_c_int(i: Option<usize>) -> c_int {
+    match i {
+        Some(x) => c_int::try_from(x).expect("Target id too large"),
+        None => -1,
+    }
+}
+
+/// Rust version of hts_reg2bin() from htslib with the BAM parameters (min_shift = 14, n_lvls = 5)
+/// `end` is exclusive
+fn reg2bin(beg: HtsPos, end: HtsPos) -> u16 {
+    let end = end - 1;
+    let mut l = 5;
+    let mut s = 14;
+    let mut t = ((1 << 15) - 1) / 7;
+    while l > 0 {
+        if beg >> s == end >> s {
+            return (t + (beg >> s)) as u16;
+        }
+        l -= 1;
+        s += 3;
+        t -= 1 << (l * 3);
+    }
+    0
+}
@@ -76,6 +76,15 @@ impl SamReader<'_, '_, '_> {
        Ok(HtsRegionsIter::make_regions_iter(reg_iter, f, self))
     }
     
+    /// Make an iterator over the records overlapping `reg` for use with
+    /// [ReadRecIter::read_rec_iter].  The index is loaded if necessary
+    pub fn query(&mut self, reg: &HtsRegion) -> Result<HtsItr, SamError> {
+        self.load_idx()?;
+        let idx = self.idx.as_ref().unwrap();
+        HtsItr::make(unsafe { sam_itr_queryi(idx.deref(), reg.tid(), reg.start(), reg.end()) })
+            .ok_or(SamError::OperationFailed)
+    }
+
     pub fn load_idx(&mut self) -> Result<(), SamError> {
         if self.idx.is_none() {
             let hts_raw = self.hts_file.deref_mut();
@@ -1,8 +1,9 @@
 use std::{
+    ffi::{CStr, CString},
     ops::DerefMut,
 };
 
-use libc::c_int;
+use libc::{c_char, c_int};
 
 use crate::{
     SamError,
@@ -10,7 +11,7 @@ use crate::{
         HtsFile, HtsFileRaw,
         traits::{HdrType, IdMap, WriteRec, SeqId},
     },
-    sam::{SamHdr, SamHdrRaw},
+    sam::{SamHdr, SamHdrRaw, SamIdxFmt},
 };
 
 use super::{BamRec, bam1::bam1_t};
@@ -18,11 +19,19 @@ use super::{BamRec, bam1::bam1_t};
 #[link(name = "hts")]
 unsafe extern "C" {
     fn sam_write1(fp_: *mut HtsFileRaw, hd_: *mut SamHdrRaw, b_: *mut bam1_t) -> c_int;
+    fn sam_idx_init(
+        fp_: *mut HtsFileRaw,
+        hd_: *mut SamHdrRaw,
+        min_shift: c_int,
+        fnidx: *const c_char,
+    ) -> c_int;
+    fn sam_idx_save(fp_: *mut HtsFileRaw) -> c_int;
 }
 
 pub struct SamWriter<'a: 'b, 'b, 'c> {
     hts_file: &'b mut HtsFile<'a>,
     hdr: &'c SamHdr,
+    indexing: bool,
 }
 
 impl<'a, 'b, 'c> SamWriter<'a, 'b, 'c> {
@@ -30,6 +39,69 @@ impl<'a, 'b, 'c> SamWriter<'a, 'b, 'c> {
         Self {
             hts_file,
             hdr,
+            indexing: false,
+        }
+    }
+
+    /// Create a writer that builds an index on the fly as records are written.  The header
+    /// must already have been written to `hts_file`, and the records must be written in
+    /// coordinate order.  [SamWriter::save_index] must be called after the last record has been
+    /// written to write out the index.
+    ///
+    /// `idx_name` gives the name of the index file; if None the index name is derived from the
+    /// output file name by adding the default suffix (i.e., `.bai`, `.csi` or `.crai`).
+    pub fn new_with_index(
+        hts_file: &'b mut HtsFile<'a>,
+        hdr: &'c SamHdr,
+        fmt: SamIdxFmt,
+        idx_name: Option<&CStr>,
+    ) -> Result<Self, SamError> {
+        fmt.check()?;
+        let idx_name = match idx_name {
+            Some(s) => s.to_owned(),
+            None => {
+                let mut s = unsafe { CStr::from_ptr(hts_file.file_name_ptr()) }
+                    .to_bytes()
+                    .to_vec();
+                let suffix = if hts_file.is_cram() {
+                    ".crai"
+                } else {
+                    fmt.suffix()
+                };
+                s.extend_from_slice(suffix.as_bytes());
+                CString::new(s).map_err(|_| SamError::IndexInitFailed)?
+            }
+        };
+        let mut g = hdr.write_guard();
+        if unsafe {
+            sam_idx_init(
+                hts_file.deref_mut(),
+                g.as_ptr_mut(),
+                fmt.min_shift(),
+                idx_name.as_ptr(),
+            )
+        } != 0
+        {
+            return Err(SamError::IndexInitFailed);
+        }
+        drop(g);
+        Ok(Self {
+            hts_file,
+            hdr,
+            indexing: true,
+        })
+    }
+
+    /// Finish and write the index being built while writing.  This flushes the output file, so
+    /// no more records should be written after this call
+    pub fn save_index(&mut self) -> Result<(), SamError> {
+        if !self.indexing {
+            Err(SamError::IndexNotInitialized)
+        } else if unsafe { sam_idx_save(self.hts_file.deref_mut()) } != 0 {
+            Err(SamError::IndexSaveFailed)
+        } else {
+            self.indexing = false;
+            Ok(())
         }
     }
 }
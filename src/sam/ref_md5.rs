@@ -0,0 +1,157 @@
+use std::{ffi::CString, fmt};
+
+use crate::{
+    FaidxError, SamError,
+    faidx::Faidx,
+    sam::{SamHdr, SamHeader},
+};
+
+/// Difference between an @SQ line and the reference found by [SamHeader::verify_ref]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefMismatch {
+    /// Contig not present in the reference
+    Missing(String),
+    Length {
+        name: String,
+        hdr_len: usize,
+        ref_len: usize,
+    },
+    Md5 {
+        name: String,
+        hdr_md5: String,
+        ref_md5: String,
+    },
+}
+
+impl fmt::Display for RefMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(s) => write!(f, "contig {s} not found in reference"),
+            Self::Length {
+                name,
+                hdr_len,
+                ref_len,
+            } => write!(
+                f,
+                "length of contig {name} differs (header: {hdr_len}, reference: {ref_len})"
+            ),
+            Self::Md5 {
+                name,
+                hdr_md5,
+                ref_md5,
+            } => write!(
+                f,
+                "M5 of contig {name} differs (header: {hdr_md5}, reference: {ref_md5})"
+            ),
+        }
+    }
+}
+
+fn c_name(s: &str) -> Result<CString, SamError> {
+    CString::new(s).map_err(|_| SamError::IllegalHeaderChars)
+}
+
+impl SamHeader {
+    /// Set the M5 tags of the @SQ lines from the sequences in `fai`.  Existing M5 tags are
+    /// only replaced if `overwrite` is true.  An error is returned if a contig is not present in
+    /// `fai` or its length differs from the LN tag.  Returns the number of M5 tags set
+    pub fn fill_md5(&mut self, fai: &mut Faidx, overwrite: bool) -> Result<usize, SamError> {
+        let mut n = 0;
+        for sq in self.sq.iter_mut().filter(|s| overwrite || s.md5.is_none()) {
+            let name = c_name(&sq.name)?;
+            match fai.get_seq_len(&name) {
+                None => return Err(FaidxError::UnknownSequence.into()),
+                Some(l) if l != sq.len => {
+                    return Err(SamError::IncompatibleSeqDict(sq.name.clone()));
+                }
+                _ => {}
+            }
+            sq.md5 = Some(fai.seq_md5(&name)?);
+            n += 1
+        }
+        Ok(n)
+    }
+
+    /// Check the @SQ lines against the sequences in `fai`, returning a list of differences.
+    /// Lengths are checked for all contigs, and checksums for contigs with an M5 tag
+    pub fn verify_ref(&self, fai: &mut Faidx) -> Result<Vec<RefMismatch>, SamError> {
+        let mut v = Vec::new();
+        for sq in self.sq.iter() {
+            let name = c_name(&sq.name)?;
+            match fai.get_seq_len(&name) {
+                None => v.push(RefMismatch::Missing(sq.name.clone())),
+                Some(l) if l != sq.len => v.push(RefMismatch::Length {
+                    name: sq.name.clone(),
+                    hdr_len: sq.len,
+                    ref_len: l,
+                }),
+                _ => {
+                    if let Some(m) = sq.md5.as_deref() {
+                        let r = fai.seq_md5(&name)?;
+                        if !m.eq_ignore_ascii_case(&r) {
+                            v.push(RefMismatch::Md5 {
+                                name: sq.name.clone(),
+                                hdr_md5: m.to_owned(),
+                                ref_md5: r,
+                            })
+                        }
+                    }
+                }
+            }
+        }
+        Ok(v)
+    }
+}
+
+impl SamHdr {
+    /// Set the M5 tags of the @SQ lines from the sequences in `fai` (see
+    /// [SamHeader::fill_md5])
+    pub fn fill_md5(&mut self, fai: &mut Faidx, overwrite: bool) -> Result<usize, SamError> {
+        let mut h = SamHeader::from_sam_hdr(self)?;
+        let n = h.fill_md5(fai, overwrite)?;
+        if n > 0 {
+            *self = h.to_sam_hdr()?
+        }
+        Ok(n)
+    }
+
+    /// Check the @SQ lines against the sequences in `fai` (see [SamHeader::verify_ref])
+    pub fn verify_ref(&self, fai: &mut Faidx) -> Result<Vec<RefMismatch>, SamError> {
+        SamHeader::from_sam_hdr(self)?.verify_ref(fai)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hts::HtsFile, sam::SqLine};
+
+    #[test]
+    fn md5() -> Result<(), SamError> {
+        let mut fai = Faidx::load("test/realn01.fa")?;
+        let mut f = HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to open file");
+        let mut hdr = SamHdr::read(&mut f)?;
+        assert_eq!(hdr.fill_md5(&mut fai, false)?, 1);
+        let h = SamHeader::from_sam_hdr(&hdr)?;
+        assert_eq!(
+            h.sq[0].md5.as_deref(),
+            Some("b4ba1b4bb98b7ad13b8e4d57512bf3a2")
+        );
+        assert!(hdr.verify_ref(&mut fai)?.is_empty());
+        assert_eq!(hdr.fill_md5(&mut fai, false)?, 0);
+
+        let mut h = SamHeader::new();
+        h.sq.push(SqLine {
+            md5: Some("00000000000000000000000000000000".to_owned()),
+            ..SqLine::new("000000F", 686)
+        });
+        h.sq.push(SqLine::new("000000F_x", 10));
+        let v = h.verify_ref(&mut fai)?;
+        assert_eq!(v.len(), 2);
+        assert!(
+            matches!(&v[0], RefMismatch::Md5 { ref_md5, .. } if ref_md5 == "b4ba1b4bb98b7ad13b8e4d57512bf3a2")
+        );
+        assert_eq!(v[1], RefMismatch::Missing("000000F_x".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs,
+    path::Path,
+};
+
+use crate::{
+    SamError,
+    hts::traits::{IdMap, ReadRec, SeqId},
+    sam::{BamAuxVal, BamRec, SamHdr, SamHeader},
+};
+
+/// Mapping between contig names (i.e., `1` -> `chr1`).  Names not in the map are unchanged
+#[derive(Debug, Clone, Default)]
+pub struct ContigMap {
+    map: HashMap<String, String>,
+}
+
+impl ContigMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, from: &str, to: &str) -> &mut Self {
+        self.map.insert(from.to_owned(), to.to_owned());
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.map.get(name).map(|s| s.as_str())
+    }
+
+    /// Return the new name for `name` (or `name` if it is not in the map)
+    pub fn translate<'a>(&'a self, name: &'a str) -> &'a str {
+        self.get(name).unwrap_or(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Read mapping from a file with two whitespace separated columns (old name, new name).
+    /// Empty lines and lines starting with `#` are ignored
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, SamError> {
+        let s = fs::read_to_string(path)?;
+        let mut m = Self::new();
+        for (i, l) in s.lines().enumerate() {
+            let l = l.trim();
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+            let mut it = l.split_whitespace();
+            match (it.next(), it.next(), it.next()) {
+                (Some(a), Some(b), None) => {
+                    m.insert(a, b);
+                }
+                _ => return Err(SamError::InvalidContigMap(i + 1)),
+            }
+        }
+        Ok(m)
+    }
+
+    /// Mapping adding a `chr` prefix to all contigs in `h` that do not already have one.  `MT`
+    /// is mapped to `chrM`
+    pub fn add_chr<T: IdMap>(h: &T) -> Self {
+        let mut m = Self::new();
+        for s in h.seq_iter().map(|s| s.to_string_lossy()) {
+            if s == "MT" {
+                m.insert(&s, "chrM");
+            } else if !s.starts_with("chr") {
+                m.insert(&s, &format!("chr{s}"));
+            }
+        }
+        m
+    }
+
+    /// Mapping removing the `chr` prefix from all contigs in `h`.  `chrM` is mapped to `MT`
+    pub fn strip_chr<T: IdMap>(h: &T) -> Self {
+        let mut m = Self::new();
+        for s in h.seq_iter().map(|s| s.to_string_lossy()) {
+            if s == "chrM" {
+                m.insert(&s, "MT");
+            } else if let Some(t) = s.strip_prefix("chr")
+                && !t.is_empty()
+            {
+                m.insert(&s, t);
+            }
+        }
+        m
+    }
+
+    /// Make a copy of `hdr` with the contigs (and alternative contig names) renamed, along
+    /// with the table to convert tids between the two headers.  An error is returned if the
+    /// renaming results in duplicate contig names
+    pub fn reheader(&self, hdr: &SamHdr) -> Result<(SamHdr, TidRemap), SamError> {
+        let mut h = SamHeader::from_sam_hdr(hdr)?;
+        for sq in h.sq.iter_mut() {
+            sq.name = self.translate(&sq.name).to_owned();
+            for a in sq.alt_names.iter_mut() {
+                *a = self.translate(a).to_owned();
+            }
+        }
+        let mut seen = HashSet::with_capacity(h.sq.len());
+        for sq in h.sq.iter() {
+            if !seen.insert(sq.name.as_str()) {
+                return Err(SamError::DuplicateContig(sq.name.clone()));
+            }
+        }
+        let new_hdr = h.to_sam_hdr()?;
+        let remap = TidRemap::new(hdr, &new_hdr, self);
+        Ok((new_hdr, remap))
+    }
+}
+
+/// Table converting tids from one header to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TidRemap {
+    v: Vec<Option<usize>>,
+}
+
+impl TidRemap {
+    /// Make table mapping contigs in `src` to contigs in `dst`, after translating the names
+    /// from `src` using `map`.  Contigs not found in `dst` are mapped to None
+    pub fn new<S: IdMap, D: SeqId>(src: &S, dst: &D, map: &ContigMap) -> Self {
+        let v = src
+            .seq_iter()
+            .map(|s| {
+                let name = s.to_string_lossy();
+                let t = map.translate(&name);
+                if t == name {
+                    dst.seq_id(s)
+                } else {
+                    CString::new(t).ok().and_then(|c| dst.seq_id(&c))
+                }
+            })
+            .collect();
+        Self { v }
+    }
+
+    /// New tid for `tid` (None if `tid` is not present in the destination header)
+    #[inline]
+    pub fn get(&self, tid: usize) -> Option<usize> {
+        self.v.get(tid).copied().flatten()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.v.is_empty()
+    }
+
+    /// True if every tid maps to itself
+    pub fn is_identity(&self) -> bool {
+        self.v.iter().enumerate().all(|(i, t)| *t == Some(i))
+    }
+}
+
+/// Adapt records to a new header by rewriting the tid and mtid fields and the contig names in
+/// SA tags
+#[derive(Debug, Clone)]
+pub struct RecRemapper {
+    remap: TidRemap,
+    names: ContigMap,
+}
+
+impl RecRemapper {
+    pub fn new(remap: TidRemap, names: ContigMap) -> Self {
+        Self { remap, names }
+    }
+
+    /// Make remapper from a [ContigMap] and the original header (see [ContigMap::reheader]),
+    /// returning the new header and the remapper
+    pub fn from_reheader(map: &ContigMap, hdr: &SamHdr) -> Result<(SamHdr, Self), SamError> {
+        let (h, remap) = map.reheader(hdr)?;
+        Ok((h, Self::new(remap, map.clone())))
+    }
+
+    #[inline]
+    pub fn tid_remap(&self) -> &TidRemap {
+        &self.remap
+    }
+
+    fn new_tid(&self, tid: Option<usize>) -> Result<Option<usize>, SamError> {
+        match tid {
+            Some(t) => self
+                .remap
+                .get(t)
+                .map(Some)
+                .ok_or(SamError::UnknownReference),
+            None => Ok(None),
+        }
+    }
+
+    /// Rewrite `rec` for the new header.  An error is returned if the record refers to a
+    /// contig that is not present in the new header
+    pub fn remap(&self, rec: &mut BamRec) -> Result<(), SamError> {
+        let tid = self.new_tid(rec.tid())?;
+        let mtid = self.new_tid(rec.mtid())?;
+        rec.set_tid(tid);
+        rec.set_mtid(mtid);
+
+        let sa = match rec.get_tag("SA")? {
+            Some(tag) => match tag.get_val()? {
+                BamAuxVal::String(s) => self.remap_sa(s.to_bytes()),
+                _ => None,
+            },
+            None => None,
+        };
+        if let Some(s) = sa {
+            rec.update_str_tag("SA", s.as_bytes())?
+        }
+        Ok(())
+    }
+
+    // SA tags have the format (rname,pos,strand,CIGAR,mapQ,NM;)+.  Returns the new tag value
+    // if any names have changed
+    fn remap_sa(&self, sa: &[u8]) -> Option<String> {
+        let sa = String::from_utf8_lossy(sa);
+        let mut changed = false;
+        let mut out = String::with_capacity(sa.len() + 16);
+        for e in sa.split_inclusive(';') {
+            match e.split_once(',') {
+                Some((name, rest)) => {
+                    let t = self.names.translate(name);
+                    changed |= t != name;
+                    out.push_str(t);
+                    out.push(',');
+                    out.push_str(rest);
+                }
+                None => out.push_str(e),
+            }
+        }
+        changed.then_some(out)
+    }
+}
+
+/// [ReadRec] adaptor applying a [RecRemapper] to each record read
+pub struct RemapReader<R> {
+    rd: R,
+    remapper: RecRemapper,
+}
+
+impl<R> RemapReader<R> {
+    pub fn new(rd: R, remapper: RecRemapper) -> Self {
+        Self { rd, remapper }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rd
+    }
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> ReadRec for RemapReader<R> {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut BamRec) -> Result<Option<()>, SamError> {
+        let r = self.rd.read_rec(rec)?;
+        if r.is_some() {
+            self.remapper.remap(rec)?
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::{make_hdr, parse_rec};
+
+    #[test]
+    fn reheader() -> Result<(), SamError> {
+        let mut hdr = make_hdr(
+            c"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:1\tLN:1000\n@SQ\tSN:2\tLN:2000\n@SQ\tSN:MT\tLN:16569",
+        )?;
+        let map = ContigMap::add_chr(&hdr);
+        assert_eq!(map.translate("MT"), "chrM");
+        assert_eq!(map.translate("X"), "X");
+
+        let (h, mut rm) = RecRemapper::from_reheader(&map, &hdr)?;
+        assert_eq!(h.seq_name(0), Some(c"chr1"));
+        assert_eq!(h.seq_name(2), Some(c"chrM"));
+        assert_eq!(h.seq_len(1), Some(2000));
+        assert!(rm.tid_remap().is_identity());
+
+        let mut rec = parse_rec(
+            &mut hdr,
+            b"rd1\t2113\t1\t101\t60\t10M\t2\t501\t0\tACGTACGTAC\tIIIIIIIIII\tSA:Z:2,501,+,5S5M,60,0;MT,11,-,5M5S,30,1;",
+        )?;
+        rm.remap(&mut rec)?;
+        let sa = rec.get_tag("SA")?.expect("Missing SA tag");
+        assert!(
+            matches!(sa.get_val()?, BamAuxVal::String(s) if s == c"chr2,501,+,5S5M,60,0;chrM,11,-,5M5S,30,1;")
+        );
+
+        // Remap to a header with a different contig order and a contig missing
+        let h2 = make_hdr(c"@SQ\tSN:chr2\tLN:2000\n@SQ\tSN:chr1\tLN:1000")?;
+        let remap = TidRemap::new(&hdr, &h2, &map);
+        assert_eq!(remap.get(0), Some(1));
+        assert_eq!(remap.get(1), Some(0));
+        assert_eq!(remap.get(2), None);
+        rm = RecRemapper::new(remap, map);
+        let mut rec1 = BamRec::new();
+        rec.copy(&mut rec1);
+        rm.remap(&mut rec)?;
+        assert_eq!((rec.tid(), rec.mtid()), (Some(1), Some(0)));
+        rec1.set_mtid(Some(2));
+        assert!(matches!(
+            rm.remap(&mut rec1),
+            Err(SamError::UnknownReference)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn strip_chr() -> Result<(), SamError> {
+        let h = make_hdr(c"@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chrM\tLN:16569\n@SQ\tSN:1\tLN:10")?;
+        let map = ContigMap::strip_chr(&h);
+        assert_eq!(map.translate("chrM"), "MT");
+        assert!(matches!(
+            map.reheader(&h),
+            Err(SamError::DuplicateContig(s)) if s == "1"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn map_file() -> Result<(), SamError> {
+        let path = std::env::temp_dir().join(format!("contig_map_test_{}.txt", std::process::id()));
+        fs::write(&path, "# old new\n1\tchr1\n\n  \n  MT   chrM  \n#X chrX\n")?;
+        let map = ContigMap::from_file(&path)?;
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("1"), Some("chr1"));
+        assert_eq!(map.get("MT"), Some("chrM"));
+        assert_eq!(map.get("#X"), None);
+
+        fs::write(&path, "1 chr1\n# comment\n2 chr2 extra\n")?;
+        let res = ContigMap::from_file(&path);
+        let _ = fs::remove_file(&path);
+        assert!(matches!(res, Err(SamError::InvalidContigMap(3))));
+        Ok(())
+    }
+}
@@ -3,7 +3,7 @@ use std::{num::ParseIntError, str::Utf8Error};
 use thiserror::Error;
 use libc::c_int;
 
-use crate::{AuxError, CigarError, CramError, FaidxError, KStringError, ParseINumError};
+use crate::{AuxError, BaseModsError, BgzfError, CigarError, CramError, FaidxError, KStringError, ParseINumError};
 
 #[derive(Error, Debug)]
 pub enum SamError {
@@ -47,6 +47,8 @@ pub enum SamError {
     ParseIntError(#[from] ParseIntError),
     #[error("Cigar Error: {0}")]
     CigarError(#[from] CigarError),
+    #[error("Bgzf Error: {0}")]
+    BgzfError(#[from] BgzfError),
     #[error("KString Error: {0}")]
     KStringError(#[from] KStringError),
     #[error("Error setting query name for Bam Record")]
@@ -109,4 +111,58 @@ pub enum SamError {
     BaqRealignFailed,
     #[error("BAQ realignment failed - unknown error")]
     BaqRealignUnknownError,
+    #[error("Query names of mate records do not match")]
+    MateQnameMismatch,
+    #[error("Records do not form a primary read pair")]
+    NotReadPair,
+    #[error("Input is not coordinate sorted")]
+    NotCoordinateSorted,
+    #[error("Mate cigar (MC tag) missing from paired record - run fixmate first")]
+    MissingMateCigar,
+    #[error("Error writing BAM record")]
+    BamWriteError,
+    #[error("Error creating temporary file: {0}")]
+    TempFileError(String),
+    #[error("SQ header line missing SN tag")]
+    MissingSqTag,
+    #[error("Sequence dictionaries incompatible - length of contig {0} differs")]
+    IncompatibleSeqDict(String),
+    #[error("Contigs occur in different orders in the sequence dictionaries")]
+    IncompatibleSeqOrder,
+    #[error("Number of inputs does not match the merged header")]
+    MergeInputMismatch,
+    #[error("Illegal min_shift value for CSI index: {0}")]
+    IllegalMinShift(u8),
+    #[error("Failed to build index")]
+    IndexBuildFailed,
+    #[error("Failed to open file for indexing")]
+    IndexOpenFailed,
+    #[error("File format is not indexable")]
+    NotIndexable,
+    #[error("Failed to save index")]
+    IndexSaveFailed,
+    #[error("Failed to initialize index")]
+    IndexInitFailed,
+    #[error("Index not initialized for writer")]
+    IndexNotInitialized,
+    #[error("Error in pileup")]
+    PileupFailed,
+    #[error("Base modification error: {0}")]
+    BaseModsError(#[from] BaseModsError),
+    #[error("Input is not grouped by query name - {0} occurs in more than one group")]
+    InputNotGrouped(String),
+    #[error("Multiple primary alignments for the same read in template {0}")]
+    DuplicatePrimary(String),
+    #[error("Mate cache full ({0} records)")]
+    MateCacheFull(usize),
+    #[error("Malformed header line: {0}")]
+    MalformedHeaderLine(String),
+    #[error("Missing {0} tag in header line: {1}")]
+    MissingHeaderTag(String, String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid contig map file (line {0})")]
+    InvalidContigMap(usize),
+    #[error("Duplicate contig name {0}")]
+    DuplicateContig(String),
 }
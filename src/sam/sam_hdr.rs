@@ -12,6 +12,7 @@ use std::{
 
 use super::sam_error::SamError;
 use crate::{
+    cram::CramFdRaw,
     cstr_len, from_c,
     hts::{
         htsfile::{HtsFile, HtsFileRaw},
@@ -476,6 +477,13 @@ impl SamHdr {
         self.read_guard().write(hts_file)
     }
 
+    /// Writes the header to the CRAM file `fd`
+    #[inline]
+    pub fn write_cram(&self, fd: &mut CramFdRaw) -> Result<(), SamError> {
+        fd.write_header(&self.read_guard())?;
+        Ok(())
+    }
+
     /// Returns the number of references in sam header
     #[inline]
     pub fn nref(&self) -> usize {
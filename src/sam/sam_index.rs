@@ -0,0 +1,183 @@
+use std::{ffi::CStr, ptr};
+
+use libc::{c_char, c_int};
+
+use crate::{SamError, gen_utils::CStrWrap};
+
+#[link(name = "hts")]
+unsafe extern "C" {
+    fn sam_index_build3(
+        fn_: *const c_char,
+        fnidx: *const c_char,
+        min_shift: c_int,
+        nthreads: c_int,
+    ) -> c_int;
+}
+
+/// Index format for SAM/BAM files.  CRAM files are always indexed using CRAI indices, so this
+/// has no effect for CRAM
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SamIdxFmt {
+    #[default]
+    Bai,
+    /// CSI index with bins of size 2^min_shift at the lowest level
+    Csi(u8),
+}
+
+impl SamIdxFmt {
+    /// Default value of min_shift for CSI indices
+    pub const CSI_DEFAULT_MIN_SHIFT: u8 = 14;
+
+    #[inline]
+    pub fn csi() -> Self {
+        Self::Csi(Self::CSI_DEFAULT_MIN_SHIFT)
+    }
+
+    /// Value of min_shift to pass to htslib (0 indicates a BAI index)
+    pub(crate) fn min_shift(&self) -> c_int {
+        match self {
+            Self::Bai => 0,
+            Self::Csi(s) => *s as c_int,
+        }
+    }
+
+    /// Default suffix for index files (for BAM or SAM files)
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::Bai => ".bai",
+            Self::Csi(_) => ".csi",
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<(), SamError> {
+        match self {
+            Self::Csi(s) if *s == 0 || *s > 30 => Err(SamError::IllegalMinShift(*s)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Build an index for an existing coordinate sorted BAM, CRAM or bgzipped SAM file.
+///
+/// `idx_name` gives the name of the index file; if None the index name is derived from
+/// `fname` by adding the default suffix (i.e., `.bai`, `.csi` or `.crai`).
+///
+/// `n_threads` is the number of additional threads used for decompression
+pub fn sam_index_build<'a, T: Into<CStrWrap<'a>>>(
+    fname: T,
+    idx_name: Option<&CStr>,
+    fmt: SamIdxFmt,
+    n_threads: usize,
+) -> Result<(), SamError> {
+    fmt.check()?;
+    let fname = fname.into();
+    match unsafe {
+        sam_index_build3(
+            fname.as_ptr(),
+            idx_name.map(|s| s.as_ptr()).unwrap_or(ptr::null()),
+            fmt.min_shift(),
+            n_threads.min(c_int::MAX as usize) as c_int,
+        )
+    } {
+        0 => Ok(()),
+        -2 => Err(SamError::IndexOpenFailed),
+        -3 => Err(SamError::NotIndexable),
+        -4 => Err(SamError::IndexSaveFailed),
+        _ => Err(SamError::IndexBuildFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, path::PathBuf};
+
+    use super::*;
+    use crate::{
+        hts::{
+            HtsFile,
+            traits::{ReadRec, WriteRec},
+        },
+        region::Reg,
+        sam::{BamRec, SamHdr, SamReader, SamWriter},
+    };
+
+    fn tmp_name(s: &str) -> (PathBuf, CString) {
+        let path = std::env::temp_dir().join(format!("sam_index_{s}_{}.bam", std::process::id()));
+        let name = CString::new(path.to_str().unwrap()).unwrap();
+        (path, name)
+    }
+
+    // Copy test/index.sam to a BAM file, optionally building an index on the fly
+    fn write_bam(name: &CStr, fmt: Option<SamIdxFmt>) -> Result<(), SamError> {
+        let mut h = HtsFile::open(c"test/index.sam", c"r").expect("Failed to read test/index.sam");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rd = SamReader::new(&mut h, &hdr);
+        let mut f = HtsFile::open(name, c"wb").expect("Failed to open output file");
+        hdr.write(&mut f)?;
+        let mut wr = match fmt {
+            Some(fmt) => SamWriter::new_with_index(&mut f, &hdr, fmt, None)?,
+            None => SamWriter::new(&mut f, &hdr),
+        };
+        let mut rec = BamRec::new();
+        while rd.read_rec(&mut rec)?.is_some() {
+            wr.write_rec(&mut rec)?;
+        }
+        if fmt.is_some() {
+            wr.save_index()?;
+        }
+        Ok(())
+    }
+
+    // Number of records overlapping `reg` using the index of `name`
+    fn count_region(name: &CStr, reg: &[u8]) -> Result<usize, SamError> {
+        let mut h = HtsFile::open(name, c"r").expect("Failed to open BAM file");
+        let hdr = SamHdr::read(&mut h)?;
+        let reg = Reg::from_u8_slice(reg).expect("Bad region");
+        let mut it = SamReader::new(&mut h, &hdr).region_iter(&reg)?;
+        let mut rec = BamRec::new();
+        let mut n = 0;
+        while it.read_rec(&mut rec)?.is_some() {
+            n += 1
+        }
+        Ok(n)
+    }
+
+    #[test]
+    fn index_build() -> Result<(), SamError> {
+        let (path, name) = tmp_name("build");
+        write_bam(&name, None)?;
+        for fmt in [SamIdxFmt::Bai, SamIdxFmt::csi()] {
+            let idx = PathBuf::from(format!("{}{}", path.display(), fmt.suffix()));
+            sam_index_build(&name, None, fmt, 0)?;
+            assert!(idx.exists());
+            assert_eq!(count_region(&name, b"CHROMOSOME_II")?, 28);
+            assert_eq!(count_region(&name, b"CHROMOSOME_III")?, 0);
+            std::fs::remove_file(&idx)?;
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn index_min_shift() {
+        for fmt in [SamIdxFmt::Csi(0), SamIdxFmt::Csi(31)] {
+            assert!(matches!(
+                sam_index_build(c"test/index.sam", None, fmt, 0),
+                Err(SamError::IllegalMinShift(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn index_on_the_fly() -> Result<(), SamError> {
+        let (path, name) = tmp_name("otf");
+        write_bam(&name, Some(SamIdxFmt::Bai))?;
+        let idx = PathBuf::from(format!("{}.bai", path.display()));
+        assert!(idx.exists());
+        assert_eq!(count_region(&name, b"CHROMOSOME_I")?, 61);
+        assert_eq!(count_region(&name, b"CHROMOSOME_V")?, 42);
+        std::fs::remove_file(&idx)?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,653 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use crate::{
+    SamError,
+    base::Base,
+    faidx::{Faidx, Sequence},
+    hts::traits::{IdMap, ReadRec},
+    sam::{
+        BamRec, CigarOp,
+        record::bam1::{
+            BAM_FDUP, BAM_FMUNMAP, BAM_FPAIRED, BAM_FPROPER_PAIR, BAM_FQCFAIL, BAM_FREAD1,
+            BAM_FREAD2, BAM_FSECONDARY, BAM_FSUPPLEMENTARY, BAM_FUNMAP,
+        },
+    },
+};
+
+/// Simple histogram of counts indexed by a non-negative integer value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram(Vec<u64>);
+
+impl Histogram {
+    #[inline]
+    pub fn add(&mut self, x: usize) {
+        self.add_n(x, 1)
+    }
+
+    pub fn add_n(&mut self, x: usize, n: u64) {
+        if x >= self.0.len() {
+            self.0.resize(x + 1, 0)
+        }
+        self.0[x] += n
+    }
+
+    /// Counts for each value (the last element is the highest value seen)
+    #[inline]
+    pub fn counts(&self) -> &[u64] {
+        &self.0
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    pub fn mean(&self) -> f64 {
+        let (s, n) = self.0.iter().enumerate().fold((0.0, 0), |(s, n), (i, c)| {
+            (s + (i as f64) * (*c as f64), n + c)
+        });
+        if n > 0 { s / n as f64 } else { 0.0 }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (i, c) in other.0.iter().enumerate().filter(|(_, c)| **c > 0) {
+            self.add_n(i, *c)
+        }
+    }
+
+    // Non-zero entries as (value, count)
+    fn non_zero(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(i, c)| (i, *c))
+    }
+}
+
+/// Flag based counts as reported by samtools flagstat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlagStats {
+    pub total: u64,
+    pub primary: u64,
+    pub secondary: u64,
+    pub supplementary: u64,
+    pub duplicates: u64,
+    pub primary_duplicates: u64,
+    pub mapped: u64,
+    pub primary_mapped: u64,
+    pub paired: u64,
+    pub read1: u64,
+    pub read2: u64,
+    pub properly_paired: u64,
+    pub both_mapped: u64,
+    pub singletons: u64,
+    pub mate_diff_chr: u64,
+    pub mate_diff_chr_mapq5: u64,
+}
+
+impl FlagStats {
+    pub fn add(&mut self, rec: &BamRec) {
+        let flag = rec.flag();
+        let mapped = flag & BAM_FUNMAP == 0;
+        self.total += 1;
+        if flag & BAM_FSECONDARY != 0 {
+            self.secondary += 1
+        } else if flag & BAM_FSUPPLEMENTARY != 0 {
+            self.supplementary += 1
+        } else {
+            self.primary += 1;
+            if mapped {
+                self.primary_mapped += 1
+            }
+            if flag & BAM_FDUP != 0 {
+                self.primary_duplicates += 1
+            }
+            if flag & BAM_FPAIRED != 0 {
+                self.paired += 1;
+                if flag & BAM_FREAD1 != 0 {
+                    self.read1 += 1
+                }
+                if flag & BAM_FREAD2 != 0 {
+                    self.read2 += 1
+                }
+                if mapped {
+                    if flag & BAM_FPROPER_PAIR != 0 {
+                        self.properly_paired += 1
+                    }
+                    if flag & BAM_FMUNMAP == 0 {
+                        self.both_mapped += 1;
+                        if rec.tid() != rec.mtid() {
+                            self.mate_diff_chr += 1;
+                            if rec.mapq() >= 5 {
+                                self.mate_diff_chr_mapq5 += 1
+                            }
+                        }
+                    } else {
+                        self.singletons += 1
+                    }
+                }
+            }
+        }
+        if mapped {
+            self.mapped += 1
+        }
+        if flag & BAM_FDUP != 0 {
+            self.duplicates += 1
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.total += other.total;
+        self.primary += other.primary;
+        self.secondary += other.secondary;
+        self.supplementary += other.supplementary;
+        self.duplicates += other.duplicates;
+        self.primary_duplicates += other.primary_duplicates;
+        self.mapped += other.mapped;
+        self.primary_mapped += other.primary_mapped;
+        self.paired += other.paired;
+        self.read1 += other.read1;
+        self.read2 += other.read2;
+        self.properly_paired += other.properly_paired;
+        self.both_mapped += other.both_mapped;
+        self.singletons += other.singletons;
+        self.mate_diff_chr += other.mate_diff_chr;
+        self.mate_diff_chr_mapq5 += other.mate_diff_chr_mapq5;
+    }
+}
+
+/// Flagstat output for QC passed and QC failed reads
+pub struct FlagStatReport<'a>(&'a [FlagStats; 2]);
+
+impl fmt::Display for FlagStatReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [p, q] = self.0;
+        let pct = |a: u64, b: u64| {
+            if b > 0 {
+                format!("{:.2}%", 100.0 * a as f64 / b as f64)
+            } else {
+                "N/A".to_string()
+            }
+        };
+        writeln!(
+            f,
+            "{} + {} in total (QC-passed reads + QC-failed reads)",
+            p.total, q.total
+        )?;
+        writeln!(f, "{} + {} primary", p.primary, q.primary)?;
+        writeln!(f, "{} + {} secondary", p.secondary, q.secondary)?;
+        writeln!(f, "{} + {} supplementary", p.supplementary, q.supplementary)?;
+        writeln!(f, "{} + {} duplicates", p.duplicates, q.duplicates)?;
+        writeln!(
+            f,
+            "{} + {} primary duplicates",
+            p.primary_duplicates, q.primary_duplicates
+        )?;
+        writeln!(
+            f,
+            "{} + {} mapped ({} : {})",
+            p.mapped,
+            q.mapped,
+            pct(p.mapped, p.total),
+            pct(q.mapped, q.total)
+        )?;
+        writeln!(
+            f,
+            "{} + {} primary mapped ({} : {})",
+            p.primary_mapped,
+            q.primary_mapped,
+            pct(p.primary_mapped, p.primary),
+            pct(q.primary_mapped, q.primary)
+        )?;
+        writeln!(f, "{} + {} paired in sequencing", p.paired, q.paired)?;
+        writeln!(f, "{} + {} read1", p.read1, q.read1)?;
+        writeln!(f, "{} + {} read2", p.read2, q.read2)?;
+        writeln!(
+            f,
+            "{} + {} properly paired ({} : {})",
+            p.properly_paired,
+            q.properly_paired,
+            pct(p.properly_paired, p.paired),
+            pct(q.properly_paired, q.paired)
+        )?;
+        writeln!(
+            f,
+            "{} + {} with itself and mate mapped",
+            p.both_mapped, q.both_mapped
+        )?;
+        writeln!(
+            f,
+            "{} + {} singletons ({} : {})",
+            p.singletons,
+            q.singletons,
+            pct(p.singletons, p.paired),
+            pct(q.singletons, q.paired)
+        )?;
+        writeln!(
+            f,
+            "{} + {} with mate mapped to a different chr",
+            p.mate_diff_chr, q.mate_diff_chr
+        )?;
+        writeln!(
+            f,
+            "{} + {} with mate mapped to a different chr (mapQ>=5)",
+            p.mate_diff_chr_mapq5, q.mate_diff_chr_mapq5
+        )
+    }
+}
+
+/// Alignment statistics in the style of samtools stats.
+///
+/// Flag counts are collected for all records; the remaining statistics are collected from
+/// primary, QC passed records.  Partial results (i.e., from different threads) can be
+/// combined using [SamStats::merge].
+#[derive(Debug, Clone)]
+pub struct SamStats {
+    max_insert_size: usize,
+    flag_stats: [FlagStats; 2],
+    n_seqs: u64,
+    total_bases: u64,
+    mapped_bases: u64,
+    mismatches: u64,
+    read_len: Histogram,
+    mapq: Histogram,
+    insert_size: Histogram,
+    gc: Histogram,
+    ins_size: Histogram,
+    del_size: Histogram,
+    // Quality distribution per cycle for first and last fragments
+    qual_by_cycle: [Vec<Histogram>; 2],
+    // (bases, mismatches) per cycle (only collected if a reference is available)
+    mismatch_by_cycle: Vec<(u64, u64)>,
+}
+
+impl Default for SamStats {
+    fn default() -> Self {
+        Self {
+            max_insert_size: 8000,
+            flag_stats: Default::default(),
+            n_seqs: 0,
+            total_bases: 0,
+            mapped_bases: 0,
+            mismatches: 0,
+            read_len: Histogram::default(),
+            mapq: Histogram::default(),
+            insert_size: Histogram::default(),
+            gc: Histogram::default(),
+            ins_size: Histogram::default(),
+            del_size: Histogram::default(),
+            qual_by_cycle: Default::default(),
+            mismatch_by_cycle: Vec::new(),
+        }
+    }
+}
+
+impl SamStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert sizes greater than `x` are counted as `x` (default 8000)
+    pub fn set_max_insert_size(&mut self, x: usize) -> &mut Self {
+        self.max_insert_size = x;
+        self
+    }
+
+    /// Flag counts for QC passed (index 0) and QC failed (index 1) reads
+    #[inline]
+    pub fn flag_stats(&self) -> &[FlagStats; 2] {
+        &self.flag_stats
+    }
+
+    /// Flag counts formatted as for samtools flagstat
+    pub fn flagstat_report(&self) -> FlagStatReport<'_> {
+        FlagStatReport(&self.flag_stats)
+    }
+
+    #[inline]
+    pub fn read_len(&self) -> &Histogram {
+        &self.read_len
+    }
+
+    #[inline]
+    pub fn mapq(&self) -> &Histogram {
+        &self.mapq
+    }
+
+    #[inline]
+    pub fn insert_size(&self) -> &Histogram {
+        &self.insert_size
+    }
+
+    /// Distribution of GC content (percent) per read
+    #[inline]
+    pub fn gc(&self) -> &Histogram {
+        &self.gc
+    }
+
+    #[inline]
+    pub fn insertion_size(&self) -> &Histogram {
+        &self.ins_size
+    }
+
+    #[inline]
+    pub fn deletion_size(&self) -> &Histogram {
+        &self.del_size
+    }
+
+    /// Base quality distribution for each cycle for first (`read2` false) or last
+    /// (`read2` true) fragments
+    #[inline]
+    pub fn qual_by_cycle(&self, read2: bool) -> &[Histogram] {
+        &self.qual_by_cycle[read2 as usize]
+    }
+
+    /// Number of (bases, mismatches) compared per cycle.  Only collected when a reference
+    /// is available
+    #[inline]
+    pub fn mismatch_by_cycle(&self) -> &[(u64, u64)] {
+        &self.mismatch_by_cycle
+    }
+
+    /// Mismatch rate (mismatches / mapped bases compared to the reference)
+    pub fn error_rate(&self) -> f64 {
+        let n: u64 = self.mismatch_by_cycle.iter().map(|(n, _)| n).sum();
+        if n > 0 {
+            self.mismatches as f64 / n as f64
+        } else {
+            0.0
+        }
+    }
+
+    #[inline]
+    pub fn add(&mut self, rec: &BamRec) {
+        self.add_with_ref(rec, None)
+    }
+
+    /// Add record to statistics.  `ref_seq` if present should be the complete sequence
+    /// of the contig that `rec` is mapped to, and is used to collect mismatch statistics
+    pub fn add_with_ref(&mut self, rec: &BamRec, ref_seq: Option<&[u8]>) {
+        let flag = rec.flag();
+        let qc_fail = flag & BAM_FQCFAIL != 0;
+        self.flag_stats[qc_fail as usize].add(rec);
+        if qc_fail || flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) != 0 {
+            return;
+        }
+
+        let len = rec.seq_len();
+        let mapped = flag & BAM_FUNMAP == 0;
+        let reversed = rec.is_reversed();
+        self.n_seqs += 1;
+        self.total_bases += len as u64;
+        self.read_len.add(len);
+
+        // GC content
+        let (gc, n) = rec
+            .seq()
+            .fold((0usize, 0usize), |(gc, n), b| match b.single_base() {
+                Some(1 | 2) => (gc + 1, n + 1),
+                Some(_) => (gc, n + 1),
+                None => (gc, n),
+            });
+        if let Some(x) = (100 * gc + (n >> 1)).checked_div(n) {
+            self.gc.add(x)
+        }
+
+        // Quality by cycle
+        let qual = rec.qual_slice();
+        if qual.first().map(|q| *q != 0xff).unwrap_or(false) {
+            let v = &mut self.qual_by_cycle[(flag & BAM_FREAD2 != 0) as usize];
+            if v.len() < len {
+                v.resize(len, Histogram::default())
+            }
+            for (i, q) in qual.iter().enumerate() {
+                let cycle = if reversed { len - 1 - i } else { i };
+                v[cycle].add(*q as usize)
+            }
+        }
+
+        if !mapped {
+            return;
+        }
+        self.mapq.add(rec.mapq() as usize);
+
+        // Insert size (counted once per pair)
+        if flag & (BAM_FPAIRED | BAM_FMUNMAP) == BAM_FPAIRED
+            && rec.tid() == rec.mtid()
+            && rec.template_len() > 0
+        {
+            self.insert_size
+                .add((rec.template_len() as usize).min(self.max_insert_size))
+        }
+
+        let Some(cigar) = rec.cigar() else { return };
+        let seq: Vec<Base> = if ref_seq.is_some() {
+            rec.seq().collect()
+        } else {
+            Vec::new()
+        };
+        if ref_seq.is_some() && self.mismatch_by_cycle.len() < len {
+            self.mismatch_by_cycle.resize(len, (0, 0))
+        }
+        let mut rpos = rec.pos().unwrap_or(0) as usize;
+        let mut qpos = 0;
+        for e in cigar.as_elems() {
+            let (op, l) = e.op_pair();
+            let l = l as usize;
+            match op {
+                CigarOp::Match | CigarOp::Equal | CigarOp::Diff => {
+                    self.mapped_bases += l as u64;
+                    if let Some(rs) = ref_seq {
+                        for i in 0..l {
+                            let (Some(rb), Some(sb)) = (rs.get(rpos + i), seq.get(qpos + i)) else {
+                                continue;
+                            };
+                            if let (Some(a), Some(b)) =
+                                (Base::from_u8(*rb).single_base(), sb.single_base())
+                            {
+                                let cycle = if reversed {
+                                    len - 1 - (qpos + i)
+                                } else {
+                                    qpos + i
+                                };
+                                let m = &mut self.mismatch_by_cycle[cycle];
+                                m.0 += 1;
+                                if a != b {
+                                    m.1 += 1;
+                                    self.mismatches += 1
+                                }
+                            }
+                        }
+                    }
+                }
+                CigarOp::Ins => self.ins_size.add(l),
+                CigarOp::Del => self.del_size.add(l),
+                _ => {}
+            }
+            if e.consumes_reference() {
+                rpos += l
+            }
+            if e.consumes_query() {
+                qpos += l
+            }
+        }
+    }
+
+    /// Collect statistics for all records from `rd`.  If `fai` is supplied, mismatch
+    /// statistics are collected by comparison with the reference
+    pub fn collect<R>(&mut self, rd: &mut R, mut fai: Option<&mut Faidx>) -> Result<(), SamError>
+    where
+        R: ReadRec<Rec = BamRec, Err = SamError> + IdMap,
+    {
+        let mut rec = BamRec::new();
+        let mut curr: Option<(usize, Sequence)> = None;
+        while rd.read_rec(&mut rec)?.is_some() {
+            let ref_seq = match (fai.as_deref_mut(), rec.tid()) {
+                (Some(f), Some(tid)) if rec.flag() & BAM_FUNMAP == 0 => {
+                    if curr.as_ref().map(|(t, _)| *t != tid).unwrap_or(true) {
+                        let name = rd.seq_name(tid).ok_or(SamError::UnknownReference)?;
+                        curr = Some((tid, f.fetch_seq(name, 1, None)?))
+                    }
+                    curr.as_ref().map(|(_, s)| s.seq())
+                }
+                _ => None,
+            };
+            self.add_with_ref(&rec, ref_seq)
+        }
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (a, b) in self.flag_stats.iter_mut().zip(other.flag_stats.iter()) {
+            a.merge(b)
+        }
+        self.n_seqs += other.n_seqs;
+        self.total_bases += other.total_bases;
+        self.mapped_bases += other.mapped_bases;
+        self.mismatches += other.mismatches;
+        self.read_len.merge(&other.read_len);
+        self.mapq.merge(&other.mapq);
+        self.insert_size.merge(&other.insert_size);
+        self.gc.merge(&other.gc);
+        self.ins_size.merge(&other.ins_size);
+        self.del_size.merge(&other.del_size);
+        for (a, b) in self
+            .qual_by_cycle
+            .iter_mut()
+            .zip(other.qual_by_cycle.iter())
+        {
+            if a.len() < b.len() {
+                a.resize(b.len(), Histogram::default())
+            }
+            for (h, h1) in a.iter_mut().zip(b.iter()) {
+                h.merge(h1)
+            }
+        }
+        if self.mismatch_by_cycle.len() < other.mismatch_by_cycle.len() {
+            self.mismatch_by_cycle
+                .resize(other.mismatch_by_cycle.len(), (0, 0))
+        }
+        for (a, b) in self
+            .mismatch_by_cycle
+            .iter_mut()
+            .zip(other.mismatch_by_cycle.iter())
+        {
+            a.0 += b.0;
+            a.1 += b.1;
+        }
+    }
+
+    /// Write report in a format similar to that of samtools stats
+    pub fn write_report<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let [p, q] = &self.flag_stats;
+        writeln!(w, "# Summary numbers")?;
+        writeln!(w, "SN\traw total sequences:\t{}", self.n_seqs)?;
+        writeln!(w, "SN\treads QC failed:\t{}", q.primary)?;
+        writeln!(w, "SN\treads mapped:\t{}", p.primary_mapped)?;
+        writeln!(w, "SN\treads unmapped:\t{}", p.primary - p.primary_mapped)?;
+        writeln!(w, "SN\treads properly paired:\t{}", p.properly_paired)?;
+        writeln!(w, "SN\treads duplicated:\t{}", p.primary_duplicates)?;
+        writeln!(w, "SN\tnon-primary alignments:\t{}", p.secondary)?;
+        writeln!(w, "SN\tsupplementary alignments:\t{}", p.supplementary)?;
+        writeln!(w, "SN\ttotal length:\t{}", self.total_bases)?;
+        writeln!(w, "SN\tbases mapped (cigar):\t{}", self.mapped_bases)?;
+        writeln!(w, "SN\tmismatches:\t{}", self.mismatches)?;
+        writeln!(w, "SN\terror rate:\t{:e}", self.error_rate())?;
+        writeln!(w, "SN\taverage length:\t{:.1}", self.read_len.mean())?;
+        writeln!(w, "SN\taverage mapping quality:\t{:.1}", self.mapq.mean())?;
+        writeln!(
+            w,
+            "SN\tinsert size average:\t{:.1}",
+            self.insert_size.mean()
+        )?;
+
+        for (tag, desc, v) in [
+            ("FFQ", "first", &self.qual_by_cycle[0]),
+            ("LFQ", "last", &self.qual_by_cycle[1]),
+        ] {
+            writeln!(w, "# Quality distribution per cycle for {desc} fragments")?;
+            for (i, h) in v.iter().enumerate() {
+                write!(w, "{tag}\t{}", i + 1)?;
+                for c in h.counts() {
+                    write!(w, "\t{c}")?
+                }
+                writeln!(w)?
+            }
+        }
+        for (tag, desc, h) in [
+            ("GCF", "GC content (percent)", &self.gc),
+            ("IS", "Insert sizes", &self.insert_size),
+            ("RL", "Read lengths", &self.read_len),
+            ("MAPQ", "Mapping qualities", &self.mapq),
+        ] {
+            writeln!(w, "# {desc}")?;
+            for (i, c) in h.non_zero() {
+                writeln!(w, "{tag}\t{i}\t{c}")?
+            }
+        }
+        writeln!(
+            w,
+            "# Indel size distribution: length, insertions, deletions"
+        )?;
+        let n = self
+            .ins_size
+            .counts()
+            .len()
+            .max(self.del_size.counts().len());
+        for i in 1..n {
+            let a = self.ins_size.counts().get(i).copied().unwrap_or(0);
+            let b = self.del_size.counts().get(i).copied().unwrap_or(0);
+            if a + b > 0 {
+                writeln!(w, "ID\t{i}\t{a}\t{b}")?
+            }
+        }
+        if !self.mismatch_by_cycle.is_empty() {
+            writeln!(w, "# Mismatches per cycle: cycle, bases, mismatches")?;
+            for (i, (n, m)) in self.mismatch_by_cycle.iter().enumerate() {
+                writeln!(w, "MPC\t{}\t{n}\t{m}", i + 1)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hts::HtsFile,
+        sam::{SamHdr, SamReader},
+    };
+
+    #[test]
+    fn stats() -> Result<(), SamError> {
+        let mut h =
+            HtsFile::open(c"test/realn01.sam", c"r").expect("Failed to read test/realn01.sam");
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rdr = SamReader::new(&mut h, &hdr);
+        let mut fai = Faidx::load("test/realn01.fa")?;
+
+        let mut st = SamStats::new();
+        st.collect(&mut rdr, Some(&mut fai))?;
+        assert_eq!(st.flag_stats()[0].total, 4);
+        assert!(!st.mismatch_by_cycle().is_empty());
+
+        let mut st1 = st.clone();
+        st1.merge(&st);
+        assert_eq!(st1.flag_stats()[0].total, 8);
+        assert_eq!(st1.read_len().total(), 2 * st.read_len().total());
+
+        let mut w = Vec::new();
+        st1.write_report(&mut w).unwrap();
+        assert!(w.starts_with(b"# Summary numbers\nSN\traw total sequences:\t8\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use std::{ffi::CStr, fs, path::Path, str::FromStr};
+
+use crate::{
+    SamError,
+    faidx::Faidx,
+    hts::traits::{HdrType, HtsHdrType, IdMap, SeqId},
+    sam::{HdLine, SamHdr, SamHeader, SqLine},
+};
+
+/// Sequence dictionary as used by Picard/GATK (`.dict` files).  This is a SAM header
+/// containing only @HD and @SQ lines.  [SeqDict] implements [IdMap] and [SeqId], so it can be
+/// used to generate regions without opening a SAM/BAM/CRAM file
+#[derive(Default, Clone)]
+pub struct SeqDict {
+    hdr: SamHdr,
+}
+
+impl SeqDict {
+    /// Read a `.dict` file
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, SamError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Make dictionary from the @HD and @SQ lines of `hdr`
+    pub fn from_sam_hdr(hdr: &SamHdr) -> Result<Self, SamError> {
+        let h = SamHeader::from_sam_hdr(hdr)?;
+        Self::from_header(&h)
+    }
+
+    fn from_header(h: &SamHeader) -> Result<Self, SamError> {
+        let h = SamHeader {
+            hd: h.hd.clone(),
+            sq: h.sq.clone(),
+            ..Default::default()
+        };
+        Ok(Self {
+            hdr: h.to_sam_hdr()?,
+        })
+    }
+
+    /// Generate dictionary from the sequences in `fai`, with M5 tags calculated from the
+    /// sequences.  If `uri` is given then it is used for the UR tag of each @SQ line
+    pub fn from_faidx(fai: &mut Faidx, uri: Option<&str>) -> Result<Self, SamError> {
+        let mut h = SamHeader {
+            hd: Some(HdLine::new("1.6")),
+            ..Default::default()
+        };
+        for i in 0..fai.num_seqs() {
+            let name = fai
+                .seq_name(i)
+                .ok_or(SamError::UnknownReference)?
+                .to_str()?;
+            let len = fai.seq_len(i).ok_or(SamError::UnknownReference)?;
+            h.sq.push(SqLine {
+                uri: uri.map(|s| s.to_owned()),
+                ..SqLine::new(name, len)
+            });
+        }
+        h.fill_md5(fai, true)?;
+        Self::from_header(&h)
+    }
+
+    /// Generate dictionary for the (indexed) FASTA file `path` (see [SeqDict::from_faidx]).
+    /// The UR tags are set to the absolute path of the FASTA file as a `file:` URI, as done by
+    /// Picard CreateSequenceDictionary
+    pub fn from_fasta<P: AsRef<Path>>(path: P) -> Result<Self, SamError> {
+        let mut fai = Faidx::load(path.as_ref())?;
+        let uri = format!("file:{}", fs::canonicalize(path)?.display());
+        Self::from_faidx(&mut fai, Some(&uri))
+    }
+
+    /// Write dictionary to a `.dict` file
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), SamError> {
+        let txt = self.hdr.text().map(|s| s.to_bytes()).unwrap_or_default();
+        fs::write(path, txt)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn sam_hdr(&self) -> &SamHdr {
+        &self.hdr
+    }
+
+    #[inline]
+    pub fn into_sam_hdr(self) -> SamHdr {
+        self.hdr
+    }
+}
+
+impl FromStr for SeqDict {
+    type Err = SamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_header(&s.parse()?)
+    }
+}
+
+impl HdrType for SeqDict {
+    fn hdr_type(&self) -> HtsHdrType {
+        HtsHdrType::Sam
+    }
+}
+
+impl SeqId for SeqDict {
+    #[inline]
+    fn seq_id(&self, s: &CStr) -> Option<usize> {
+        self.hdr.seq_id(s)
+    }
+}
+
+impl IdMap for SeqDict {
+    #[inline]
+    fn seq_name(&self, i: usize) -> Option<&CStr> {
+        self.hdr.seq_name(i)
+    }
+
+    #[inline]
+    fn seq_len(&self, i: usize) -> Option<usize> {
+        self.hdr.seq_len(i)
+    }
+
+    #[inline]
+    fn num_seqs(&self) -> usize {
+        self.hdr.num_seqs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::Reg;
+
+    #[test]
+    fn dict() -> Result<(), SamError> {
+        let d = SeqDict::from_fasta("test/realn01.fa")?;
+        assert_eq!(d.num_seqs(), 1);
+        assert_eq!(d.seq_name(0), Some(c"000000F"));
+        assert_eq!(d.seq_len(0), Some(686));
+        let h = SamHeader::from_sam_hdr(d.sam_hdr())?;
+        assert_eq!(
+            h.sq[0].md5.as_deref(),
+            Some("b4ba1b4bb98b7ad13b8e4d57512bf3a2")
+        );
+        assert!(
+            h.sq[0]
+                .uri
+                .as_deref()
+                .is_some_and(|s| s.starts_with("file:/"))
+        );
+
+        let d: SeqDict =
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\tM5:0123\n@SQ\tSN:chr2\tLN:500\n@PG\tID:x"
+                .parse()?;
+        assert_eq!(d.num_seqs(), 2);
+        assert_eq!(d.seq_id(c"chr2"), Some(1));
+        let s = d.sam_hdr().text().expect("Missing header text");
+        assert!(!s.to_bytes().windows(3).any(|w| w == b"@PG"));
+
+        let r = Reg::from_u8_slice(b"chr2:101-200").expect("Bad region");
+        let hr = r.make_htslib_region(&d).expect("Unknown contig");
+        assert_eq!(hr.tid(), 1);
+        let r = Reg::from_u8_slice(b"chr3").expect("Bad region");
+        assert!(r.make_htslib_region(&d).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn write_read() -> Result<(), SamError> {
+        let d = SeqDict::from_fasta("test/xx.fa")?;
+        let path = std::env::temp_dir().join(format!("seq_dict_test_{}.dict", std::process::id()));
+        d.write(&path)?;
+        let res = SeqDict::read(&path);
+        let _ = fs::remove_file(&path);
+        let d1 = res?;
+
+        let h = SamHeader::from_sam_hdr(d.sam_hdr())?;
+        let h1 = SamHeader::from_sam_hdr(d1.sam_hdr())?;
+        assert!(h1.hd.is_some());
+        assert_eq!(h1.sq.len(), 5);
+        for (a, b) in h.sq.iter().zip(h1.sq.iter()) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.len, b.len);
+            assert_eq!(a.md5, b.md5);
+            assert_eq!(a.uri, b.uri);
+            assert!(b.md5.is_some() && b.uri.is_some());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    ffi::{CStr, CString},
+    panic,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use libc::c_int;
+
+use crate::{
+    HtsError, SamError,
+    gen_utils::CStrWrap,
+    hts::{
+        HtsFile, HtsPos, HtsRegion, HtsThreadPool,
+        hts_itr::HtsItr,
+        traits::{HdrType, HtsHdrType, IdMap, ReadRec, ReadRecIter, SeqId},
+    },
+    region::RegionList,
+    sam::{BamRec, SamHdr, SamReader},
+};
+
+/// A section of a contig to be processed as a unit of work by a [ShardRunner]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Shard {
+    tid: usize,
+    start: HtsPos,
+    end: HtsPos,
+}
+
+impl Shard {
+    /// Make shard covering [start, end) on contig `tid`
+    pub fn new(tid: usize, start: HtsPos, end: HtsPos) -> Self {
+        assert!(start >= 0 && start < end, "Invalid shard coordinates");
+        Self { tid, start, end }
+    }
+
+    #[inline]
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// Start of shard (zero offset)
+    #[inline]
+    pub fn start(&self) -> HtsPos {
+        self.start
+    }
+
+    /// End of shard (half open)
+    #[inline]
+    pub fn end(&self) -> HtsPos {
+        self.end
+    }
+
+    /// True if the alignment start of `rec` lies within the shard.
+    ///
+    /// Records that overlap the boundary between shards are returned when reading each
+    /// shard they overlap, so this can be used to ensure that each record is processed once
+    pub fn owns(&self, rec: &BamRec) -> bool {
+        rec.tid() == Some(self.tid)
+            && rec
+                .pos()
+                .map(|x| x >= self.start && x < self.end)
+                .unwrap_or(false)
+    }
+
+    fn hts_region(&self) -> HtsRegion {
+        HtsRegion::new(self.tid as c_int, self.start, self.end)
+    }
+}
+
+// Split [start, end) into shards of (at most) size `size` on contig `tid`
+fn split_region(tid: usize, start: HtsPos, end: HtsPos, size: usize, v: &mut Vec<Shard>) {
+    let size = if size == 0 {
+        end - start
+    } else {
+        size as HtsPos
+    };
+    let mut x = start;
+    while x < end {
+        let y = (x + size).min(end);
+        v.push(Shard::new(tid, x, y));
+        x = y
+    }
+}
+
+/// Split all contigs in `h` into shards of size `shard_size` (if zero, each contig is a
+/// single shard).  Shards are returned in genomic order
+pub fn make_shards<T: IdMap>(h: &T, shard_size: usize) -> Vec<Shard> {
+    let mut v = Vec::new();
+    for tid in 0..h.num_seqs() {
+        let len = h.seq_len(tid).unwrap_or(0) as HtsPos;
+        split_region(tid, 0, len, shard_size, &mut v)
+    }
+    v
+}
+
+/// Split the regions in `rl` (which must be normalized) into shards of size `shard_size`
+/// (if zero, each region is a single shard).  Shards are returned in genomic order (i.e.,
+/// following the order of contigs in `h`).  Unmapped regions are ignored
+pub fn make_region_shards<T: IdMap + SeqId>(
+    h: &T,
+    rl: &RegionList,
+    shard_size: usize,
+) -> Result<Vec<Shard>, HtsError> {
+    if rl.is_all_regions() {
+        return Ok(make_shards(h, shard_size));
+    }
+    let mut v = Vec::new();
+    for reg in rl.regions().filter(|r| r.has_contigs()) {
+        let r = reg.make_htslib_region(h)?;
+        split_region(r.tid() as usize, r.start(), r.end(), shard_size, &mut v)
+    }
+    v.sort_unstable();
+    Ok(v)
+}
+
+/// Reader for the records overlapping a [Shard]
+pub struct ShardReader<'r, 'a: 'b, 'b, 'c> {
+    rdr: &'r mut SamReader<'a, 'b, 'c>,
+    itr: HtsItr,
+}
+
+impl ReadRec for ShardReader<'_, '_, '_, '_> {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut BamRec) -> Result<Option<()>, SamError> {
+        self.rdr.read_rec_iter(&mut self.itr, rec)
+    }
+}
+
+impl HdrType for ShardReader<'_, '_, '_, '_> {
+    fn hdr_type(&self) -> HtsHdrType {
+        self.rdr.hdr_type()
+    }
+}
+
+impl SeqId for ShardReader<'_, '_, '_, '_> {
+    fn seq_id(&self, s: &CStr) -> Option<usize> {
+        self.rdr.seq_id(s)
+    }
+}
+
+impl IdMap for ShardReader<'_, '_, '_, '_> {
+    fn seq_len(&self, i: usize) -> Option<usize> {
+        self.rdr.seq_len(i)
+    }
+
+    fn seq_name(&self, i: usize) -> Option<&CStr> {
+        self.rdr.seq_name(i)
+    }
+
+    fn num_seqs(&self) -> usize {
+        self.rdr.num_seqs()
+    }
+}
+
+/// Process an indexed SAM/BAM/CRAM file in parallel by shards.
+///
+/// Each worker thread opens its own file handle and reader, and takes shards from a shared
+/// list until all shards have been processed.  Decompression can be shared between workers
+/// by setting an [HtsThreadPool].  The results of each shard are returned in the same order
+/// as the input shards.
+pub struct ShardRunner<'p> {
+    fname: CString,
+    n_workers: usize,
+    tpool: Option<&'p HtsThreadPool>,
+    reference: Option<CString>,
+}
+
+impl<'p> ShardRunner<'p> {
+    pub fn new<'a, S: Into<CStrWrap<'a>>>(fname: S) -> Self {
+        let n_workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self {
+            fname: fname.into().as_c_str().to_owned(),
+            n_workers,
+            tpool: None,
+            reference: None,
+        }
+    }
+
+    /// Set number of worker threads (default is the available parallelism)
+    pub fn set_n_workers(&mut self, n: usize) -> &mut Self {
+        self.n_workers = n.max(1);
+        self
+    }
+
+    /// Thread pool shared between all workers for decompression
+    pub fn set_thread_pool(&mut self, tp: &'p HtsThreadPool) -> &mut Self {
+        self.tpool = Some(tp);
+        self
+    }
+
+    /// Set reference fasta index (needed for CRAM files where the reference can not be
+    /// found otherwise)
+    pub fn set_reference(&mut self, fai_fname: &CStr) -> &mut Self {
+        self.reference = Some(fai_fname.to_owned());
+        self
+    }
+
+    /// Call `f` for each shard in `shards`, returning the results in the order of `shards`.
+    /// If any call fails, the remaining shards are not processed and the first error
+    /// is returned
+    pub fn run<T, F>(&self, shards: &[Shard], f: F) -> Result<Vec<T>, HtsError>
+    where
+        T: Send,
+        F: Fn(&Shard, &mut ShardReader) -> Result<T, HtsError> + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let abort = AtomicBool::new(false);
+        let results: Mutex<Vec<Option<T>>> = Mutex::new(shards.iter().map(|_| None).collect());
+
+        let worker = || -> Result<(), HtsError> {
+            let mut h = HtsFile::open(self.fname.as_c_str(), c"r")?;
+            if let Some(tp) = self.tpool {
+                h.set_thread_pool(tp)?
+            }
+            if let Some(r) = self.reference.as_deref() {
+                h.set_fai_filename(r)?
+            }
+            let hdr = SamHdr::read(&mut h)?;
+            let mut rdr = SamReader::new(&mut h, &hdr);
+            while !abort.load(Ordering::Relaxed) {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(s) = shards.get(i) else { break };
+                let itr = rdr.query(&s.hts_region())?;
+                let mut srdr = ShardReader { rdr: &mut rdr, itr };
+                let r = f(s, &mut srdr)?;
+                results.lock().unwrap()[i] = Some(r);
+            }
+            Ok(())
+        };
+
+        let n = self.n_workers.min(shards.len());
+        thread::scope(|sc| {
+            let handles: Vec<_> = (0..n)
+                .map(|_| {
+                    sc.spawn(|| {
+                        let r = worker();
+                        if r.is_err() {
+                            abort.store(true, Ordering::Relaxed)
+                        }
+                        r
+                    })
+                })
+                .collect();
+            let mut res = Ok(());
+            for h in handles {
+                match h.join() {
+                    Ok(Err(e)) if res.is_ok() => res = Err(e),
+                    Err(e) => panic::resume_unwind(e),
+                    _ => {}
+                }
+            }
+            res
+        })?;
+
+        Ok(results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.expect("Missing shard result"))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards() -> Result<(), HtsError> {
+        let mut h = HtsFile::open(c"test/test_input_1_a.cram", c"r")?;
+        let hdr = SamHdr::read(&mut h)?;
+        let mut rdr = SamReader::new(&mut h, &hdr);
+        let mut rec = BamRec::new();
+        let mut n_mapped = 0;
+        while rdr.read_rec(&mut rec)?.is_some() {
+            if rec.tid().is_some() && rec.pos().is_some() {
+                n_mapped += 1
+            }
+        }
+
+        let shards = make_shards(&hdr, 10);
+        assert!(shards.len() > hdr.num_seqs());
+        assert!(shards.windows(2).all(|w| w[0] < w[1]));
+
+        let mut runner = ShardRunner::new(c"test/test_input_1_a.cram");
+        runner.set_n_workers(3);
+        let counts = runner.run(&shards, |s, rd| {
+            let mut rec = BamRec::new();
+            let mut n = 0;
+            while rd.read_rec(&mut rec)?.is_some() {
+                if s.owns(&rec) {
+                    n += 1
+                }
+            }
+            Ok(n)
+        })?;
+        assert_eq!(counts.len(), shards.len());
+        assert_eq!(counts.iter().sum::<usize>(), n_mapped);
+        Ok(())
+    }
+}
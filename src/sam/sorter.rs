@@ -0,0 +1,528 @@
+use std::{
+    cmp::Ordering,
+    ffi::{CStr, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use libc::{c_char, c_int};
+
+use crate::{
+    SamError,
+    bgzf::Bgzf,
+    hts::{
+        HtsPos,
+        traits::{ReadRec, WriteRec},
+    },
+    sam::{
+        BamAuxVal, BamRec, SamHdr,
+        record::bam1::{BAM_FREAD1, BAM_FREAD2},
+    },
+};
+
+/// Default memory limit for records held in memory before spilling to disk (768MB)
+pub const DEFAULT_SORT_MEM_LIMIT: usize = 768 << 20;
+
+/// Default maximum number of temporary files kept open before they are merged
+pub const DEFAULT_SORT_MAX_RUNS: usize = 64;
+
+/// Sort order for [Sorter]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Contig (in header order, with unplaced reads last), position and strand
+    Coordinate,
+    /// Query name using natural ordering (so that numeric fields are compared numerically),
+    /// with read 1 before read 2
+    QueryName,
+    /// Value of the aux tag, with records lacking the tag first, then by coordinate
+    TagCoordinate(String),
+    /// Value of the aux tag, with records lacking the tag first, then by query name
+    TagQueryName(String),
+}
+
+impl SortOrder {
+    /// Compare two records according to the sort order
+    pub fn compare(&self, a: &BamRec, b: &BamRec) -> Ordering {
+        match self {
+            Self::Coordinate => cmp_coord(a, b),
+            Self::QueryName => cmp_name(a, b),
+            Self::TagCoordinate(t) => cmp_tag(a, b, t).then_with(|| cmp_coord(a, b)),
+            Self::TagQueryName(t) => cmp_tag(a, b, t).then_with(|| cmp_name(a, b)),
+        }
+    }
+
+    /// Value for the SO field of the @HD header line
+    pub fn so_value(&self) -> &'static CStr {
+        match self {
+            Self::Coordinate => c"coordinate",
+            Self::QueryName => c"queryname",
+            _ => c"unknown",
+        }
+    }
+
+    fn tag(&self) -> Option<&str> {
+        match self {
+            Self::TagCoordinate(t) | Self::TagQueryName(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+/// Sort BAM records with a limit on memory use.
+///
+/// Records are added with [Sorter::push].  When the memory used by the stored records exceeds
+/// the limit, they are sorted and written as a run to a temporary BGZF file.  The temporary
+/// files are unlinked on creation, so they are removed automatically when the sorter (or the
+/// [SortedRecs] returned by [Sorter::finish]) is dropped.  On output the runs are merged.
+/// Each run keeps a file open, so when the number of runs reaches a limit (see
+/// [Sorter::set_max_runs]) the existing runs are merged into a single run.
+/// The sort is stable, so records that compare equal are returned in input order.
+pub struct Sorter {
+    order: SortOrder,
+    mem_limit: usize,
+    max_runs: usize,
+    tmp_dir: PathBuf,
+    recs: Vec<BamRec>,
+    mem_used: usize,
+    runs: Vec<Bgzf>,
+}
+
+impl Sorter {
+    pub fn new(order: SortOrder) -> Result<Self, SamError> {
+        if order.tag().is_some_and(|t| t.len() != 2) {
+            return Err(SamError::IllegalTagLength);
+        }
+        Ok(Self {
+            order,
+            mem_limit: DEFAULT_SORT_MEM_LIMIT,
+            max_runs: DEFAULT_SORT_MAX_RUNS,
+            tmp_dir: std::env::temp_dir(),
+            recs: Vec::new(),
+            mem_used: 0,
+            runs: Vec::new(),
+        })
+    }
+
+    /// Set the approximate memory limit (in bytes) for records held in memory
+    pub fn set_mem_limit(&mut self, x: usize) -> &mut Self {
+        self.mem_limit = x;
+        self
+    }
+
+    /// Set the maximum number of sorted runs (each using an open temporary file) kept before
+    /// they are merged into a single run (default [DEFAULT_SORT_MAX_RUNS], minimum 2)
+    pub fn set_max_runs(&mut self, n: usize) -> &mut Self {
+        self.max_runs = n.max(2);
+        self
+    }
+
+    /// Set the directory used for temporary files (the default is the system temporary
+    /// directory)
+    pub fn set_tmp_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.tmp_dir = dir.as_ref().to_owned();
+        self
+    }
+
+    #[inline]
+    pub fn order(&self) -> &SortOrder {
+        &self.order
+    }
+
+    /// Update the SO field of the @HD header line to reflect the sort order, removing any GO
+    /// field
+    pub fn update_header(&self, hdr: &mut SamHdr) {
+        hdr.change_hd(c"SO", Some(self.order.so_value()));
+        hdr.change_hd(c"GO", None);
+    }
+
+    pub fn push(&mut self, rec: BamRec) -> Result<(), SamError> {
+        self.mem_used += rec.mem_size();
+        self.recs.push(rec);
+        if self.mem_used >= self.mem_limit {
+            self.spill()?
+        }
+        Ok(())
+    }
+
+    /// Finish input, returning the sorted records
+    pub fn finish(mut self) -> Result<SortedRecs, SamError> {
+        let inner = if self.runs.is_empty() {
+            self.sort_recs();
+            SortedInner::Memory(std::mem::take(&mut self.recs).into_iter())
+        } else {
+            if !self.recs.is_empty() {
+                self.spill()?
+            }
+            SortedInner::Merge(MergeRecs::new(
+                self.runs.drain(..).map(BgzfRun).collect(),
+                self.order.clone(),
+            )?)
+        };
+        Ok(SortedRecs { inner })
+    }
+
+    /// Sort all records from `rd`, writing them to `wr`
+    pub fn sort<R, W>(mut self, rd: &mut R, wr: &mut W) -> Result<(), SamError>
+    where
+        R: ReadRec<Rec = BamRec, Err = SamError>,
+        W: WriteRec<Rec = BamRec, Err = SamError>,
+    {
+        let mut rec = BamRec::new();
+        while rd.read_rec(&mut rec)?.is_some() {
+            self.push(std::mem::take(&mut rec))?
+        }
+        let mut sorted = self.finish()?;
+        while sorted.read_rec(&mut rec)?.is_some() {
+            wr.write_rec(&mut rec)?;
+        }
+        Ok(())
+    }
+
+    fn sort_recs(&mut self) {
+        let order = &self.order;
+        self.recs.sort_by(|a, b| order.compare(a, b))
+    }
+
+    fn spill(&mut self) -> Result<(), SamError> {
+        self.sort_recs();
+        let recs = &self.recs;
+        let fp = write_run(&self.tmp_dir, |fp| {
+            recs.iter().try_for_each(|r| r.write_bgzf(fp))
+        })?;
+        self.runs.push(fp);
+        self.recs.clear();
+        self.mem_used = 0;
+        if self.runs.len() >= self.max_runs {
+            self.merge_runs()?
+        }
+        Ok(())
+    }
+
+    // Merge the existing runs into a single run to limit the number of open files.  As the
+    // merged run replaces all earlier runs, the sort remains stable
+    fn merge_runs(&mut self) -> Result<(), SamError> {
+        let mut m = MergeRecs::new(
+            self.runs.drain(..).map(BgzfRun).collect(),
+            self.order.clone(),
+        )?;
+        let mut rec = BamRec::new();
+        let fp = write_run(&self.tmp_dir, |fp| {
+            while m.read_rec(&mut rec)?.is_some() {
+                rec.write_bgzf(fp)?
+            }
+            Ok(())
+        })?;
+        self.runs.push(fp);
+        Ok(())
+    }
+}
+
+/// Write a run to a new temporary file in `dir` using `f`, returning the file opened for
+/// reading from the start
+fn write_run<F>(dir: &Path, f: F) -> Result<Bgzf, SamError>
+where
+    F: FnOnce(&mut Bgzf) -> Result<(), SamError>,
+{
+    let fd = make_tmp_file(dir)?;
+    let wfd = unsafe { libc::dup(fd) };
+    if wfd < 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(SamError::TempFileError(e.to_string()));
+    }
+    let mut fp = match Bgzf::dopen(wfd, c"w1") {
+        Ok(fp) => fp,
+        Err(e) => {
+            unsafe {
+                libc::close(wfd);
+                libc::close(fd);
+            }
+            return Err(e.into());
+        }
+    };
+    let res = f(&mut fp).and_then(|_| fp.flush().map_err(SamError::from));
+    // Closing the file writes the EOF block
+    drop(fp);
+    if let Err(e) = res {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    if unsafe { libc::lseek(fd, 0, libc::SEEK_SET) } < 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(SamError::TempFileError(e.to_string()));
+    }
+    Ok(Bgzf::dopen(fd, c"r").inspect_err(|_| unsafe {
+        libc::close(fd);
+    })?)
+}
+
+/// Create a temporary file in `dir`, which is immediately unlinked.  Returns the
+/// file descriptor
+fn make_tmp_file(dir: &Path) -> Result<c_int, SamError> {
+    let mut tmpl = dir.as_os_str().as_bytes().to_vec();
+    tmpl.extend_from_slice(b"/m_htslib_sort.XXXXXX");
+    let tmpl =
+        CString::new(tmpl).map_err(|_| SamError::TempFileError("Illegal path".to_string()))?;
+    let mut tmpl = tmpl.into_bytes_with_nul();
+    let fd = unsafe { libc::mkstemp(tmpl.as_mut_ptr() as *mut c_char) };
+    if fd < 0 {
+        Err(SamError::TempFileError(
+            io::Error::last_os_error().to_string(),
+        ))
+    } else {
+        unsafe { libc::unlink(tmpl.as_ptr() as *const c_char) };
+        Ok(fd)
+    }
+}
+
+enum SortedInner {
+    Memory(std::vec::IntoIter<BamRec>),
+    Merge(MergeRecs<BgzfRun>),
+}
+
+/// Sorted records returned from [Sorter::finish]
+pub struct SortedRecs {
+    inner: SortedInner,
+}
+
+impl ReadRec for SortedRecs {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        match &mut self.inner {
+            SortedInner::Memory(it) => Ok(it.next().map(|r| *rec = r)),
+            SortedInner::Merge(m) => m.read_rec(rec),
+        }
+    }
+}
+
+/// A sorted run stored in a temporary BGZF file
+struct BgzfRun(Bgzf);
+
+impl ReadRec for BgzfRun {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut Self::Rec) -> Result<Option<()>, Self::Err> {
+        rec.read_bgzf(&mut self.0)
+    }
+}
+
+struct Source<S> {
+    rd: S,
+    rec: BamRec,
+}
+
+/// K-way merge of sorted record streams using a binary heap of stream indices.  Ties are broken
+/// on the stream index to keep the merge stable
+pub(super) struct MergeRecs<S> {
+    order: SortOrder,
+    srcs: Vec<Source<S>>,
+    heap: Vec<usize>,
+}
+
+impl<S: ReadRec<Rec = BamRec, Err = SamError>> MergeRecs<S> {
+    pub(super) fn new(rds: Vec<S>, order: SortOrder) -> Result<Self, SamError> {
+        let mut srcs = Vec::with_capacity(rds.len());
+        let mut heap = Vec::with_capacity(rds.len());
+        for mut rd in rds {
+            let mut rec = BamRec::new();
+            if rd.read_rec(&mut rec)?.is_some() {
+                heap.push(srcs.len());
+            }
+            srcs.push(Source { rd, rec })
+        }
+        let mut m = Self { order, srcs, heap };
+        for i in (0..m.heap.len() / 2).rev() {
+            m.sift_down(i)
+        }
+        Ok(m)
+    }
+
+    fn less(&self, i: usize, j: usize) -> bool {
+        match self.order.compare(&self.srcs[i].rec, &self.srcs[j].rec) {
+            Ordering::Less => true,
+            Ordering::Greater => false,
+            Ordering::Equal => i < j,
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        let n = self.heap.len();
+        loop {
+            let l = 2 * i + 1;
+            if l >= n {
+                break;
+            }
+            let c = if l + 1 < n && self.less(self.heap[l + 1], self.heap[l]) {
+                l + 1
+            } else {
+                l
+            };
+            if self.less(self.heap[c], self.heap[i]) {
+                self.heap.swap(c, i);
+                i = c
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub(super) fn read_rec(&mut self, rec: &mut BamRec) -> Result<Option<()>, SamError> {
+        let Some(&top) = self.heap.first() else {
+            return Ok(None);
+        };
+        let src = &mut self.srcs[top];
+        std::mem::swap(rec, &mut src.rec);
+        if src.rd.read_rec(&mut src.rec)?.is_none() {
+            self.heap.swap_remove(0);
+        }
+        self.sift_down(0);
+        Ok(Some(()))
+    }
+}
+
+#[inline]
+fn coord_key(r: &BamRec) -> (usize, HtsPos, bool) {
+    (r.tid().unwrap_or(usize::MAX), r.raw_pos(), r.is_reversed())
+}
+
+fn cmp_coord(a: &BamRec, b: &BamRec) -> Ordering {
+    coord_key(a).cmp(&coord_key(b))
+}
+
+fn cmp_name(a: &BamRec, b: &BamRec) -> Ordering {
+    let qa = a.qname().map(|s| s.to_bytes()).unwrap_or_default();
+    let qb = b.qname().map(|s| s.to_bytes()).unwrap_or_default();
+    natural_cmp(qa, qb).then_with(|| {
+        let m = BAM_FREAD1 | BAM_FREAD2;
+        (a.flag() & m).cmp(&(b.flag() & m))
+    })
+}
+
+#[derive(Debug, PartialEq, PartialOrd)]
+enum TagKey<'a> {
+    Missing,
+    Num(f64),
+    Str(&'a [u8]),
+}
+
+fn tag_key<'a>(r: &'a BamRec, tag: &str) -> TagKey<'a> {
+    let Ok(Some(t)) = r.get_tag(tag) else {
+        return TagKey::Missing;
+    };
+    match t.get_val() {
+        Ok(BamAuxVal::Char(_)) => TagKey::Str(&t.data()[3..4]),
+        Ok(BamAuxVal::String(s)) => TagKey::Str(s.to_bytes()),
+        Ok(BamAuxVal::Int(x)) => TagKey::Num(x as f64),
+        Ok(BamAuxVal::Float32(x)) => TagKey::Num(x as f64),
+        Ok(BamAuxVal::Float64(x)) => TagKey::Num(x),
+        _ => TagKey::Missing,
+    }
+}
+
+fn cmp_tag(a: &BamRec, b: &BamRec, tag: &str) -> Ordering {
+    tag_key(a, tag)
+        .partial_cmp(&tag_key(b, tag))
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Compare strings so that runs of digits are compared numerically (as samtools)
+pub fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            while i < a.len() && a[i] == b'0' {
+                i += 1
+            }
+            while j < b.len() && b[j] == b'0' {
+                j += 1
+            }
+            let di = a[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let dj = b[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let o = di.cmp(&dj).then_with(|| a[i..i + di].cmp(&b[j..j + dj]));
+            if o != Ordering::Equal {
+                return o;
+            }
+            i += di;
+            j += dj;
+        } else if a[i] != b[j] {
+            return a[i].cmp(&b[j]);
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::{make_hdr, parse_rec};
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(natural_cmp(b"read2", b"read10"), Ordering::Less);
+        assert_eq!(natural_cmp(b"read10:5", b"read10:12"), Ordering::Less);
+        assert_eq!(natural_cmp(b"read010", b"read10"), Ordering::Equal);
+        assert_eq!(natural_cmp(b"readA", b"read1"), Ordering::Greater);
+        assert_eq!(natural_cmp(b"read1", b"read1a"), Ordering::Less);
+    }
+
+    #[test]
+    fn sort_with_spill() -> Result<(), SamError> {
+        let mut hdr = make_hdr(c"@HD\tVN:1.6\tSO:unsorted\tGO:query\n@SQ\tSN:chr1\tLN:100000")?;
+
+        let mut sorter = Sorter::new(SortOrder::Coordinate)?;
+        // Force a spill every few records
+        sorter.set_mem_limit(1);
+        for (i, pos) in [500, 20, 300, 20, 1, 5000, 42].iter().enumerate() {
+            let s = format!("r{i}\t0\tchr1\t{pos}\t60\t4M\t*\t0\t0\tACGT\tIIII");
+            let b = parse_rec(&mut hdr, s.as_bytes())?;
+            sorter.push(b)?;
+        }
+        sorter.update_header(&mut hdr);
+        let mut sorted = sorter.finish()?;
+        let mut b = BamRec::new();
+        let mut v = Vec::new();
+        while sorted.read_rec(&mut b)?.is_some() {
+            v.push((b.pos().unwrap(), b.qname().unwrap().to_owned()))
+        }
+        let names: Vec<_> = v.iter().map(|(_, s)| s.to_str().unwrap()).collect();
+        assert_eq!(names, ["r4", "r1", "r3", "r6", "r2", "r0", "r5"]);
+
+        let text = hdr.text().unwrap().to_str().unwrap();
+        assert!(text.starts_with("@HD\tVN:1.6\tSO:coordinate\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_runs() -> Result<(), SamError> {
+        let mut hdr = make_hdr(c"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100000")?;
+        let mut sorter = Sorter::new(SortOrder::Coordinate)?;
+        sorter.set_mem_limit(1).set_max_runs(4);
+        for i in 0..50 {
+            let s = format!(
+                "r{i}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII",
+                1000 - (i % 10) * 7
+            );
+            sorter.push(parse_rec(&mut hdr, s.as_bytes())?)?;
+            assert!(sorter.runs.len() < 4);
+        }
+        let mut sorted = sorter.finish()?;
+        let mut b = BamRec::new();
+        let mut v = Vec::new();
+        while sorted.read_rec(&mut b)?.is_some() {
+            let name = b.qname().unwrap().to_str().unwrap();
+            v.push((b.pos().unwrap(), name[1..].parse::<usize>().unwrap()))
+        }
+        assert_eq!(v.len(), 50);
+        // Sorted on position, with records at the same position in input order
+        assert!(v.is_sorted());
+        Ok(())
+    }
+}
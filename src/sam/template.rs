@@ -0,0 +1,273 @@
+use std::{collections::HashSet, ffi::CStr};
+
+use crate::{
+    SamError,
+    hts::traits::ReadRec,
+    sam::{
+        BamRec,
+        record::bam1::{BAM_FPAIRED, BAM_FREAD2, BAM_FSECONDARY, BAM_FSUPPLEMENTARY},
+    },
+};
+
+/// All records for a template (i.e., sharing a query name).
+///
+/// The primary alignments for read 1 and read 2 are split out from the secondary and
+/// supplementary alignments.  For unpaired reads the primary alignment is stored as read 1.
+/// Record storage is kept between uses so that reading a series of templates does not
+/// require repeated allocation.
+#[derive(Default, Debug)]
+pub struct Template {
+    recs: Vec<BamRec>,
+    n: usize,
+    read1: Option<usize>,
+    read2: Option<usize>,
+    secondary: Vec<usize>,
+    supplementary: Vec<usize>,
+}
+
+impl Template {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.n = 0;
+        self.read1 = None;
+        self.read2 = None;
+        self.secondary.clear();
+        self.supplementary.clear();
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    pub fn qname(&self) -> Option<&CStr> {
+        self.recs().first().and_then(|r| r.qname())
+    }
+
+    /// All records in the template in input order
+    #[inline]
+    pub fn recs(&self) -> &[BamRec] {
+        &self.recs[..self.n]
+    }
+
+    #[inline]
+    pub fn recs_mut(&mut self) -> &mut [BamRec] {
+        &mut self.recs[..self.n]
+    }
+
+    /// Primary alignment for read 1 (or for the read if unpaired)
+    pub fn read1(&self) -> Option<&BamRec> {
+        self.read1.map(|i| &self.recs[i])
+    }
+
+    /// Primary alignment for read 2
+    pub fn read2(&self) -> Option<&BamRec> {
+        self.read2.map(|i| &self.recs[i])
+    }
+
+    /// Mutable references to the primary alignments for read 1 and read 2
+    pub fn primary_mut(&mut self) -> (Option<&mut BamRec>, Option<&mut BamRec>) {
+        let mut r1 = None;
+        let mut r2 = None;
+        for (i, r) in self.recs[..self.n].iter_mut().enumerate() {
+            if Some(i) == self.read1 {
+                r1 = Some(r)
+            } else if Some(i) == self.read2 {
+                r2 = Some(r)
+            }
+        }
+        (r1, r2)
+    }
+
+    /// True if primary alignments for both reads are present
+    pub fn is_complete_pair(&self) -> bool {
+        self.read1.is_some() && self.read2.is_some()
+    }
+
+    pub fn secondary(&self) -> impl Iterator<Item = &BamRec> {
+        self.secondary.iter().map(|i| &self.recs[*i])
+    }
+
+    pub fn supplementary(&self) -> impl Iterator<Item = &BamRec> {
+        self.supplementary.iter().map(|i| &self.recs[*i])
+    }
+
+    // Move rec into the template, leaving an unused record in its place
+    fn push_swap(&mut self, rec: &mut BamRec) {
+        if self.n == self.recs.len() {
+            self.recs.push(BamRec::new())
+        }
+        std::mem::swap(&mut self.recs[self.n], rec);
+        self.n += 1
+    }
+
+    fn qname_string(&self) -> String {
+        self.qname()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    fn classify(&mut self) -> Result<(), SamError> {
+        for i in 0..self.n {
+            let flag = self.recs[i].flag();
+            if flag & BAM_FSECONDARY != 0 {
+                self.secondary.push(i)
+            } else if flag & BAM_FSUPPLEMENTARY != 0 {
+                self.supplementary.push(i)
+            } else {
+                let slot = if flag & (BAM_FPAIRED | BAM_FREAD2) == (BAM_FPAIRED | BAM_FREAD2) {
+                    &mut self.read2
+                } else {
+                    &mut self.read1
+                };
+                if slot.replace(i).is_some() {
+                    return Err(SamError::DuplicatePrimary(self.qname_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Groups consecutive records with the same query name from a name collated input (i.e.,
+/// the output of samtools collate or sort -n) into [Template]s.
+///
+/// By default the reader checks that the input is grouped by remembering the query names
+/// of all previous templates, and returns an error if a query name appears again.  For very
+/// large inputs where memory use is a concern, this check can be disabled with
+/// [TemplateReader::set_check_grouping].
+pub struct TemplateReader<R> {
+    rd: R,
+    next: BamRec,
+    have_next: bool,
+    check_grouping: bool,
+    seen: HashSet<Box<[u8]>>,
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> TemplateReader<R> {
+    pub fn new(rd: R) -> Self {
+        Self {
+            rd,
+            next: BamRec::new(),
+            have_next: false,
+            check_grouping: true,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn set_check_grouping(&mut self, x: bool) -> &mut Self {
+        self.check_grouping = x;
+        if !x {
+            self.seen = HashSet::new()
+        }
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rd
+    }
+
+    fn fill_next(&mut self) -> Result<(), SamError> {
+        if !self.have_next {
+            self.have_next = self.rd.read_rec(&mut self.next)?.is_some()
+        }
+        Ok(())
+    }
+}
+
+impl<R: ReadRec<Rec = BamRec, Err = SamError>> ReadRec for TemplateReader<R> {
+    type Rec = Template;
+    type Err = SamError;
+
+    fn read_rec(&mut self, t: &mut Template) -> Result<Option<()>, SamError> {
+        t.clear();
+        self.fill_next()?;
+        if !self.have_next {
+            return Ok(None);
+        }
+        loop {
+            t.push_swap(&mut self.next);
+            self.have_next = false;
+            self.fill_next()?;
+            if !self.have_next || self.next.qname() != t.qname() {
+                break;
+            }
+        }
+        if self.check_grouping {
+            let qn = t.qname().map(|s| s.to_bytes()).unwrap_or(&[]);
+            if !self.seen.insert(qn.into()) {
+                return Err(SamError::InputNotGrouped(t.qname_string()));
+            }
+        }
+        t.classify()?;
+        Ok(Some(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::test_util::VecReader;
+
+    fn make_recs(lines: &[&[u8]]) -> Result<VecReader, SamError> {
+        VecReader::from_lines(c"@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:100000", lines)
+    }
+
+    #[test]
+    fn templates() -> Result<(), SamError> {
+        let rd = make_recs(&[
+            b"rd1\t99\tchr1\t101\t60\t10M\tchr1\t191\t100\tACGTACGTAC\tIIIIIIIIII",
+            b"rd1\t2147\tchr1\t501\t60\t5H5M\tchr1\t191\t0\tACGTA\tIIIII",
+            b"rd1\t147\tchr1\t191\t60\t10M\tchr1\t101\t-100\tACGTACGTAC\tIIIIIIIIII",
+            b"rd2\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+            b"rd3\t73\tchr1\t101\t60\t10M\t=\t101\t0\tACGTACGTAC\tIIIIIIIIII",
+        ])?;
+        let mut tr = TemplateReader::new(rd);
+        let mut t = Template::new();
+
+        tr.read_rec(&mut t)?.expect("Missing template");
+        assert_eq!(t.qname(), Some(c"rd1"));
+        assert_eq!(t.len(), 3);
+        assert!(t.is_complete_pair());
+        assert_eq!(t.read2().and_then(|r| r.pos()), Some(190));
+        assert_eq!(t.supplementary().count(), 1);
+        assert_eq!(t.secondary().count(), 0);
+
+        tr.read_rec(&mut t)?.expect("Missing template");
+        assert_eq!(t.qname(), Some(c"rd2"));
+        assert!(t.read1().is_some() && t.read2().is_none());
+
+        tr.read_rec(&mut t)?.expect("Missing template");
+        assert_eq!(t.qname(), Some(c"rd3"));
+        assert!(!t.is_complete_pair());
+
+        assert!(tr.read_rec(&mut t)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn not_grouped() -> Result<(), SamError> {
+        let rd = make_recs(&[
+            b"rd1\t67\tchr1\t101\t60\t10M\tchr1\t191\t100\tACGTACGTAC\tIIIIIIIIII",
+            b"rd2\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+            b"rd1\t131\tchr1\t191\t60\t10M\tchr1\t101\t-100\tACGTACGTAC\tIIIIIIIIII",
+        ])?;
+        let mut tr = TemplateReader::new(rd);
+        let mut t = Template::new();
+        tr.read_rec(&mut t)?;
+        tr.read_rec(&mut t)?;
+        assert!(matches!(
+            tr.read_rec(&mut t),
+            Err(SamError::InputNotGrouped(s)) if s == "rd1"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+// Fixtures shared by the unit tests of the sam modules
+
+use std::ffi::CStr;
+
+use crate::{
+    SamError,
+    hts::traits::{ReadRec, WriteRec},
+    sam::{BamRec, SamHdr, SamParser},
+};
+
+/// Make header from the (newline separated) header lines in `s`
+pub(crate) fn make_hdr(s: &CStr) -> Result<SamHdr, SamError> {
+    let mut hdr = SamHdr::new();
+    hdr.add_lines(s)?;
+    Ok(hdr)
+}
+
+/// Parse a single SAM text line into a record using `hdr`
+pub(crate) fn parse_rec(hdr: &mut SamHdr, line: &[u8]) -> Result<BamRec, SamError> {
+    let mut rec = BamRec::new();
+    SamParser::new().parse(&mut rec, hdr, line)?;
+    Ok(rec)
+}
+
+/// Parse SAM text lines into records using `hdr`
+pub(crate) fn parse_recs<I, T>(hdr: &mut SamHdr, lines: I) -> Result<Vec<BamRec>, SamError>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut p = SamParser::new();
+    let mut v = Vec::new();
+    for l in lines {
+        let mut rec = BamRec::new();
+        p.parse(&mut rec, hdr, l.as_ref())?;
+        v.push(rec)
+    }
+    Ok(v)
+}
+
+/// Record source returning records from a vector
+pub(crate) struct VecReader(std::vec::IntoIter<BamRec>);
+
+impl VecReader {
+    pub(crate) fn new(v: Vec<BamRec>) -> Self {
+        Self(v.into_iter())
+    }
+
+    /// Reader for the records parsed from `lines` using a header made from `hdr_lines`
+    pub(crate) fn from_lines<I, T>(hdr_lines: &CStr, lines: I) -> Result<Self, SamError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let mut hdr = make_hdr(hdr_lines)?;
+        Ok(Self::new(parse_recs(&mut hdr, lines)?))
+    }
+}
+
+impl ReadRec for VecReader {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn read_rec(&mut self, rec: &mut BamRec) -> Result<Option<()>, SamError> {
+        Ok(self.0.next().map(|r| *rec = r))
+    }
+}
+
+/// Record sink collecting copies of the written records
+#[derive(Default)]
+pub(crate) struct VecWriter(pub(crate) Vec<BamRec>);
+
+impl WriteRec for VecWriter {
+    type Rec = BamRec;
+    type Err = SamError;
+
+    fn write_rec(&mut self, rec: &mut BamRec) -> Result<Option<()>, SamError> {
+        let mut r = BamRec::new();
+        rec.copy(&mut r);
+        self.0.push(r);
+        Ok(Some(()))
+    }
+}